@@ -0,0 +1,323 @@
+use crate::configuration::{ArchiveStrategy, Configuration};
+use crate::error::Error;
+use crate::error::Error::{MissingFullArchive, MissingParentArchive};
+use crate::notification::format_size;
+use crate::retention::ArchiveFile;
+use crate::{LOG_TARGET, TIMESTAMP_FORMAT};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use time::PrimitiveDateTime;
+
+const CATALOG_EXTENSION: &str = "jsonl";
+
+/// One run of the archive process recorded in the catalog
+#[derive(Serialize, Deserialize)]
+pub struct CatalogRun {
+    /// Timestamp shared by every archive of the run, formatted with [`TIMESTAMP_FORMAT`]
+    pub run_id: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub strategy: String,
+    pub compression: String,
+    pub compression_level: u32,
+    pub encrypted: bool,
+    pub archives: Vec<CatalogArchive>,
+}
+
+/// An archive, or repository snapshot, written by a run
+#[derive(Serialize, Deserialize)]
+pub struct CatalogArchive {
+    pub path: PathBuf,
+    /// Volume in the archive, or `None` when the archive contains every volume
+    pub volume: Option<String>,
+    pub size: u64,
+    /// Number of files, directories and links in the archive
+    pub file_count: u64,
+    /// Hex encoded SHA-256 digest of the archive file
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub incremental: bool,
+    /// Name of the full archive an incremental archive builds on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Name of the archive an incremental archive builds on directly, which is missing from
+    /// archives cataloged before it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// IDs of the containers sharing the volume that were stopped or paused while it was archived
+    pub container_ids: Vec<String>,
+}
+
+impl CatalogArchive {
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Catalog of the runs made with the configured prefix, stored as one JSON object per line in the
+/// backup directory
+pub struct Catalog {
+    path: PathBuf,
+    pub runs: Vec<CatalogRun>,
+}
+
+impl Catalog {
+    fn path(config: &Configuration) -> PathBuf {
+        config.backup_dir.join(format!(
+            ".{}_catalog.{}",
+            config.archive_prefix, CATALOG_EXTENSION
+        ))
+    }
+
+    /// Load the catalog, which is empty when no run has been recorded yet. Lines that cannot be
+    /// read are skipped so one damaged entry does not hide the others.
+    pub fn load(config: &Configuration) -> Result<Self, Error> {
+        let path = Self::path(config);
+        let mut runs = Vec::new();
+        if path.is_file() {
+            for (number, line) in BufReader::new(File::open(path.as_path())?)
+                .lines()
+                .enumerate()
+            {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str(line.as_str()) {
+                    Ok(run) => runs.push(run),
+                    Err(error) => {
+                        warn!(target: LOG_TARGET, "Ignoring line {} of catalog {}: {}", number + 1, path.to_string_lossy(), error)
+                    }
+                }
+            }
+        }
+        debug!(target: LOG_TARGET, "Loaded {} runs from catalog {}", runs.len(), path.to_string_lossy());
+        Ok(Self { path, runs })
+    }
+
+    /// Append a run to the catalog
+    pub fn record(config: &Configuration, run: &CatalogRun) -> Result<(), Error> {
+        let path = Self::path(config);
+        let mut line = serde_json::to_string(run)?;
+        line.push('\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.as_path())?;
+        file.write_all(line.as_bytes())?;
+        file.sync_all()?;
+        std::fs::set_permissions(path.as_path(), config.archive_permission())?;
+        debug!(target: LOG_TARGET, "Recorded run {} in catalog {}", run.run_id, path.to_string_lossy());
+        Ok(())
+    }
+
+    /// Rewrite the catalog under a temporary name and rename it so a partial catalog is never left
+    /// behind
+    pub fn save(&self, config: &Configuration) -> Result<(), Error> {
+        let mut data = Vec::new();
+        for run in self.runs.iter() {
+            serde_json::to_writer(&mut data, run)?;
+            data.push(b'\n');
+        }
+        let mut temporary = self.path.as_os_str().to_os_string();
+        temporary.push(".tmp");
+        let mut file = File::create(temporary.as_os_str())?;
+        file.write_all(data.as_slice())?;
+        file.sync_all()?;
+        std::fs::set_permissions(temporary.as_os_str(), config.archive_permission())?;
+        std::fs::rename(temporary, self.path.as_path())?;
+        Ok(())
+    }
+
+    /// Every archive in the catalog along with the run that wrote it. Repository snapshots are
+    /// managed by the repository subcommands and are not included.
+    pub fn archives(&self) -> impl Iterator<Item = (&CatalogRun, &CatalogArchive)> {
+        let repository = ArchiveStrategy::Repository.to_string();
+        self.runs
+            .iter()
+            .filter(move |r| r.strategy.ne(&repository))
+            .flat_map(|r| r.archives.iter().map(move |a| (r, a)))
+    }
+
+    /// Find an archive by its file name
+    pub fn find(&self, name: &str) -> Option<(&CatalogRun, &CatalogArchive)> {
+        self.archives().find(|(_, a)| a.name().eq(name))
+    }
+
+    /// Remove an archive by its file name, along with its run once the run has no archives left
+    pub fn remove(&mut self, name: &str) {
+        for run in self.runs.iter_mut() {
+            run.archives.retain(|a| a.name().ne(name));
+        }
+        self.runs.retain(|r| !r.archives.is_empty());
+    }
+
+    /// Find the archives needed to restore an incremental archive by following the archive each
+    /// one builds on, from the full archive through to the archive itself, oldest first. Fail when
+    /// an archive of the chain is not in the catalog rather than restore without it.
+    pub fn incremental_chain(&self, name: &str) -> Result<Vec<String>, Error> {
+        let (_, mut archive) = self
+            .find(name)
+            .ok_or_else(|| MissingFullArchive(name.to_string()))?;
+        let mut chain = vec![archive.name()];
+        while archive.incremental {
+            let parent = archive.parent.as_deref().ok_or_else(|| {
+                MissingParentArchive(archive.name(), "the archive it builds on".into())
+            })?;
+            // A chain longer than the catalog can only come from a cycle
+            if chain.len() > self.runs.iter().map(|r| r.archives.len()).sum() {
+                return Err(MissingFullArchive(name.to_string()));
+            }
+            archive = self
+                .find(parent)
+                .map(|(_, a)| a)
+                .ok_or_else(|| MissingParentArchive(archive.name(), parent.to_string()))?;
+            chain.push(archive.name());
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+}
+
+impl ArchiveFile {
+    /// Describe an archive recorded in the catalog, located in the backup directory
+    pub fn from_catalog(
+        config: &Configuration,
+        run: &CatalogRun,
+        archive: &CatalogArchive,
+    ) -> Option<Self> {
+        let Ok(timestamp) = PrimitiveDateTime::parse(run.run_id.as_str(), TIMESTAMP_FORMAT) else {
+            warn!(target: LOG_TARGET, "Ignoring catalog run {} because its ID is not a timestamp", run.run_id);
+            return None;
+        };
+        Some(Self {
+            path: config.backup_dir.join(archive.name()),
+            volume: archive.volume.clone(),
+            timestamp,
+            incremental: archive.incremental,
+        })
+    }
+}
+
+/// Log every run in the catalog along with its archives
+pub fn list_catalog(config: &Configuration) -> Result<(), Error> {
+    let catalog = Catalog::load(config)?;
+    for run in catalog.runs.iter() {
+        info!(target: LOG_TARGET, "{}: {} run with {} level {} finished at {}", run.run_id, run.strategy, run.compression, run.compression_level, run.finished_at);
+        for archive in run.archives.iter() {
+            let location = match local_path(config, run, archive).is_file() {
+                true => "",
+                false => " (not in backup directory)",
+            };
+            info!(target: LOG_TARGET, "  {} for {} with {} entries ({}), SHA-256 {}, {} containers stopped or paused{}", archive.name(), archive.volume.as_deref().unwrap_or("all volumes"), archive.file_count, format_size(archive.size), archive.checksum.as_deref().unwrap_or("unknown"), archive.container_ids.len(), location);
+        }
+    }
+    info!(target: LOG_TARGET, "Found {} runs in catalog {}", catalog.runs.len(), catalog.path.to_string_lossy());
+    Ok(())
+}
+
+/// Path of an archive on this host. Archives are looked up in the backup directory, which may be
+/// mounted somewhere else than when they were written, while snapshots stay where they were written.
+fn local_path(config: &Configuration, run: &CatalogRun, archive: &CatalogArchive) -> PathBuf {
+    match run.strategy.eq(&ArchiveStrategy::Repository.to_string()) {
+        true => archive.path.clone(),
+        false => config.backup_dir.join(archive.name()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(run_id: &str, name: &str, parent: Option<&str>) -> CatalogRun {
+        CatalogRun {
+            run_id: run_id.to_string(),
+            started_at: String::new(),
+            finished_at: String::new(),
+            strategy: ArchiveStrategy::Incremental.to_string(),
+            compression: "gzip".to_string(),
+            compression_level: 6,
+            encrypted: false,
+            archives: vec![CatalogArchive {
+                path: PathBuf::from(name),
+                volume: Some("db".to_string()),
+                size: 0,
+                file_count: 0,
+                checksum: None,
+                incremental: parent.is_some(),
+                base: parent.map(|_| "full.tar.gz".to_string()),
+                parent: parent.map(str::to_string),
+                container_ids: Vec::new(),
+            }],
+        }
+    }
+
+    fn catalog(runs: Vec<CatalogRun>) -> Catalog {
+        Catalog {
+            path: PathBuf::new(),
+            runs,
+        }
+    }
+
+    #[test]
+    fn follows_each_archive_back_to_the_full_archive() {
+        let catalog = catalog(vec![
+            run("2024-01-01_00-00-00", "full.tar.gz", None),
+            run("2024-01-02_00-00-00", "a.incr.tar.gz", Some("full.tar.gz")),
+            run(
+                "2024-01-03_00-00-00",
+                "b.incr.tar.gz",
+                Some("a.incr.tar.gz"),
+            ),
+        ]);
+        assert_eq!(
+            catalog.incremental_chain("b.incr.tar.gz").unwrap(),
+            ["full.tar.gz", "a.incr.tar.gz", "b.incr.tar.gz"]
+        );
+        assert_eq!(
+            catalog.incremental_chain("full.tar.gz").unwrap(),
+            ["full.tar.gz"]
+        );
+    }
+
+    #[test]
+    fn fails_when_an_archive_of_the_chain_is_not_cataloged() {
+        // The archive of the second run was written but never cataloged
+        let catalog = catalog(vec![
+            run("2024-01-01_00-00-00", "full.tar.gz", None),
+            run(
+                "2024-01-03_00-00-00",
+                "b.incr.tar.gz",
+                Some("a.incr.tar.gz"),
+            ),
+        ]);
+        assert!(matches!(
+            catalog.incremental_chain("b.incr.tar.gz"),
+            Err(MissingParentArchive(archive, parent))
+                if archive.eq("b.incr.tar.gz") && parent.eq("a.incr.tar.gz")
+        ));
+    }
+
+    #[test]
+    fn fails_on_a_cycle() {
+        let catalog = catalog(vec![
+            run(
+                "2024-01-02_00-00-00",
+                "a.incr.tar.gz",
+                Some("b.incr.tar.gz"),
+            ),
+            run(
+                "2024-01-03_00-00-00",
+                "b.incr.tar.gz",
+                Some("a.incr.tar.gz"),
+            ),
+        ]);
+        assert!(catalog.incremental_chain("b.incr.tar.gz").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::error::Error;
+use sha2::{Digest, Sha256};
+use std::fs::{File, Permissions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const SIDECAR_EXTENSION: &str = "sha256";
+
+/// Writer that computes the SHA-256 digest of everything written through it
+pub struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Return the inner writer and the hex encoded digest of the bytes written
+    pub fn finalize(self) -> (W, String) {
+        (self.inner, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Path of the checksum sidecar for an archive: `{archive}.sha256`
+pub fn sidecar_path<P: AsRef<Path>>(archive: P) -> PathBuf {
+    let mut path = archive.as_ref().as_os_str().to_os_string();
+    path.push(".");
+    path.push(SIDECAR_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Write the checksum sidecar for an archive in the format used by `sha256sum`
+pub fn write_sidecar<P: AsRef<Path>>(
+    archive: P,
+    digest: &str,
+    permissions: Permissions,
+) -> Result<(), Error> {
+    let path = sidecar_path(archive.as_ref());
+    let file_name = archive
+        .as_ref()
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut file = File::create(path.as_path())?;
+    writeln!(file, "{}  {}", digest, file_name)?;
+    std::fs::set_permissions(path, permissions)?;
+    Ok(())
+}
+
+/// Read the digest recorded in the checksum sidecar of an archive if the sidecar exists
+pub fn read_sidecar<P: AsRef<Path>>(archive: P) -> Result<Option<String>, Error> {
+    let path = sidecar_path(archive);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(path)?;
+    Ok(contents
+        .split_whitespace()
+        .next()
+        .map(|d| d.to_ascii_lowercase()))
+}
+
+/// Compute the hex encoded SHA-256 digest of a file
+pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String, Error> {
+    let mut writer = ChecksumWriter::new(std::io::sink());
+    std::io::copy(&mut File::open(path)?, &mut writer)?;
+    Ok(writer.finalize().1)
+}
@@ -0,0 +1,267 @@
+use crate::configuration::Settings;
+use crate::error::Error;
+use crate::repository::RepositoryCommand;
+use crate::restore::RestoreOptions;
+use crate::verify::VerifyOptions;
+use crate::{
+    ARCHIVE_WORKERS_ENV, BACKUP_DIR_ENV, COMPRESSION_ENV, COMPRESSION_LEVEL_ENV,
+    COMPRESSION_THREADS_ENV, DATA_DIR_ENV, EXCLUDE_ENV, PREFIX_ENV, QUIESCE_MODE_ENV,
+    RETENTION_DRY_RUN_ENV, RETENTION_KEEP_DAILY_ENV, RETENTION_KEEP_LAST_ENV,
+    RETENTION_KEEP_MONTHLY_ENV, RETENTION_KEEP_WEEKLY_ENV, RETENTION_KEEP_YEARLY_ENV,
+    RETENTION_MAX_AGE_ENV, SALVAGE_CONTAINER_MANAGEMENT_ENV, SCHEDULE_ENV, STRATEGY_ENV,
+};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+/// Archive Docker volumes on a schedule or on demand.
+///
+/// Settings are read from the command line, then environment variables, then the configuration
+/// file. Without a subcommand Salvage archives once when SALVAGE_RUN_ONCE is true and otherwise
+/// runs on the schedule.
+#[derive(Parser)]
+#[command(name = "salvage", version)]
+pub struct Cli {
+    #[command(flatten)]
+    global: GlobalOptions,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Archive the volumes once and exit
+    Backup(BackupOptions),
+    /// Extract an archive back into its volume directory
+    Restore(RestoreOptions),
+    /// List the runs and archives in the catalog
+    List,
+    /// Check archives match their checksum and can be decompressed
+    Verify(VerifyOptions),
+    /// Delete the archives that fall outside the retention policy
+    Prune(RetentionOptions),
+    /// Validate the configuration and log every setting
+    Validate,
+    /// Archive the volumes each time the schedule fires
+    Schedule(ScheduleOptions),
+    /// Manage the repository used by the repository strategy
+    Repository {
+        #[command(subcommand)]
+        command: RepositoryCommand,
+    },
+}
+
+/// Options that apply to every subcommand
+#[derive(Args)]
+#[command(next_help_heading = "Global Options")]
+struct GlobalOptions {
+    /// TOML or YAML configuration file [env: SALVAGE_CONFIG]
+    #[arg(long, global = true, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// Directory containing a directory for each volume [env: SALVAGE_DATA_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// Directory archives are written to [env: SALVAGE_BACKUP_DIR]
+    #[arg(long, global = true, value_name = "DIR")]
+    backup_dir: Option<PathBuf>,
+
+    /// Prefix of the archive names [env: SALVAGE_ARCHIVE_PREFIX]
+    #[arg(long, global = true, value_name = "PREFIX")]
+    prefix: Option<String>,
+}
+
+/// Options for how the volumes are archived
+#[derive(Args)]
+pub struct ArchiveOptions {
+    /// Archive strategy [env: SALVAGE_ARCHIVE_STRATEGY]
+    #[arg(long, value_parser = ["multiple", "single", "incremental", "repository"])]
+    strategy: Option<String>,
+
+    /// Compression of the archives [env: SALVAGE_ARCHIVE_COMPRESSION]
+    #[arg(long, value_parser = ["bzip2", "gzip", "xz", "zstd"])]
+    compression: Option<String>,
+
+    /// Compression level [env: SALVAGE_ARCHIVE_COMPRESSION_LEVEL]
+    #[arg(long, value_name = "LEVEL")]
+    compression_level: Option<u32>,
+
+    /// Threads used to compress each archive [env: SALVAGE_ARCHIVE_COMPRESSION_THREADS]
+    #[arg(long, value_name = "THREADS")]
+    compression_threads: Option<u32>,
+
+    /// Volumes archived at the same time by the multiple strategy [env: SALVAGE_ARCHIVE_WORKERS]
+    #[arg(long, value_name = "WORKERS")]
+    workers: Option<u32>,
+
+    /// Pattern of paths to exclude from every volume, given once per pattern [env: SALVAGE_EXCLUDE]
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+
+    /// Archive without stopping the containers using the volumes [env: SALVAGE_CONTAINER_MANAGEMENT]
+    #[arg(long)]
+    no_container_management: bool,
+
+    /// How the containers using the volumes are quiesced [env: SALVAGE_QUIESCE_MODE]
+    #[arg(long, value_name = "MODE", value_parser = ["stop", "pause", "none"])]
+    quiesce: Option<String>,
+}
+
+/// Options for archiving once
+#[derive(Args)]
+pub struct BackupOptions {
+    #[command(flatten)]
+    archive: ArchiveOptions,
+
+    /// Log the containers, archives and retention a backup would act on without writing archives or
+    /// changing containers. The retention preview connects to remote storage and reads the
+    /// repository, and only warns when they cannot be read
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Options for the retention policy
+#[derive(Args)]
+pub struct RetentionOptions {
+    /// Number of most recent archives to keep [env: SALVAGE_RETENTION_KEEP_LAST]
+    #[arg(long, value_name = "COUNT")]
+    keep_last: Option<u32>,
+
+    /// Number of days to keep the last archive of [env: SALVAGE_RETENTION_KEEP_DAILY]
+    #[arg(long, value_name = "COUNT")]
+    keep_daily: Option<u32>,
+
+    /// Number of weeks to keep the last archive of [env: SALVAGE_RETENTION_KEEP_WEEKLY]
+    #[arg(long, value_name = "COUNT")]
+    keep_weekly: Option<u32>,
+
+    /// Number of months to keep the last archive of [env: SALVAGE_RETENTION_KEEP_MONTHLY]
+    #[arg(long, value_name = "COUNT")]
+    keep_monthly: Option<u32>,
+
+    /// Number of years to keep the last archive of [env: SALVAGE_RETENTION_KEEP_YEARLY]
+    #[arg(long, value_name = "COUNT")]
+    keep_yearly: Option<u32>,
+
+    /// Delete archives older than this many days [env: SALVAGE_RETENTION_MAX_AGE]
+    #[arg(long, value_name = "DAYS")]
+    max_age: Option<u32>,
+
+    /// Log which archives would be kept or deleted without deleting anything [env: SALVAGE_RETENTION_DRY_RUN]
+    #[arg(long)]
+    dry_run: bool,
+}
+
+/// Options for archiving on a schedule
+#[derive(Args)]
+pub struct ScheduleOptions {
+    /// Cron expression of when to archive [env: SCHEDULE]
+    #[arg(long, value_name = "CRON")]
+    schedule: Option<String>,
+
+    #[command(flatten)]
+    archive: ArchiveOptions,
+}
+
+impl Cli {
+    /// Load the settings, overriding them with the options given on the command line
+    pub fn settings(&self) -> Result<Settings, Error> {
+        let mut settings = Settings::load(self.global.config.clone())?;
+        self.global.apply(&mut settings);
+        match self.command.as_ref() {
+            Some(Command::Backup(options)) => options.apply(&mut settings),
+            Some(Command::Prune(options)) => options.apply(&mut settings),
+            Some(Command::Schedule(options)) => options.apply(&mut settings),
+            _ => (),
+        }
+        Ok(settings)
+    }
+}
+
+impl GlobalOptions {
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(data_dir) = self.data_dir.as_ref() {
+            settings.set_override(DATA_DIR_ENV, data_dir.to_string_lossy(), "--data-dir");
+        }
+        if let Some(backup_dir) = self.backup_dir.as_ref() {
+            settings.set_override(BACKUP_DIR_ENV, backup_dir.to_string_lossy(), "--backup-dir");
+        }
+        if let Some(prefix) = self.prefix.as_ref() {
+            settings.set_override(PREFIX_ENV, prefix, "--prefix");
+        }
+    }
+}
+
+impl ArchiveOptions {
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(strategy) = self.strategy.as_ref() {
+            settings.set_override(STRATEGY_ENV, strategy, "--strategy");
+        }
+        if let Some(compression) = self.compression.as_ref() {
+            settings.set_override(COMPRESSION_ENV, compression, "--compression");
+        }
+        if let Some(level) = self.compression_level {
+            settings.set_override(COMPRESSION_LEVEL_ENV, level, "--compression-level");
+        }
+        if let Some(threads) = self.compression_threads {
+            settings.set_override(COMPRESSION_THREADS_ENV, threads, "--compression-threads");
+        }
+        if let Some(workers) = self.workers {
+            settings.set_override(ARCHIVE_WORKERS_ENV, workers, "--workers");
+        }
+        if !self.exclude.is_empty() {
+            settings.set_override(EXCLUDE_ENV, self.exclude.join(","), "--exclude");
+        }
+        if self.no_container_management {
+            settings.set_override(
+                SALVAGE_CONTAINER_MANAGEMENT_ENV,
+                false,
+                "--no-container-management",
+            );
+        }
+        if let Some(quiesce) = self.quiesce.as_ref() {
+            settings.set_override(QUIESCE_MODE_ENV, quiesce, "--quiesce");
+        }
+    }
+}
+
+impl BackupOptions {
+    fn apply(&self, settings: &mut Settings) {
+        self.archive.apply(settings);
+    }
+}
+
+impl RetentionOptions {
+    fn apply(&self, settings: &mut Settings) {
+        let counts = [
+            (RETENTION_KEEP_LAST_ENV, self.keep_last, "--keep-last"),
+            (RETENTION_KEEP_DAILY_ENV, self.keep_daily, "--keep-daily"),
+            (RETENTION_KEEP_WEEKLY_ENV, self.keep_weekly, "--keep-weekly"),
+            (
+                RETENTION_KEEP_MONTHLY_ENV,
+                self.keep_monthly,
+                "--keep-monthly",
+            ),
+            (RETENTION_KEEP_YEARLY_ENV, self.keep_yearly, "--keep-yearly"),
+            (RETENTION_MAX_AGE_ENV, self.max_age, "--max-age"),
+        ];
+        for (key, count, option) in counts {
+            if let Some(count) = count {
+                settings.set_override(key, count, option);
+            }
+        }
+        if self.dry_run {
+            settings.set_override(RETENTION_DRY_RUN_ENV, true, "--dry-run");
+        }
+    }
+}
+
+impl ScheduleOptions {
+    fn apply(&self, settings: &mut Settings) {
+        if let Some(schedule) = self.schedule.as_ref() {
+            settings.set_override(SCHEDULE_ENV, schedule, "--schedule");
+        }
+        self.archive.apply(settings);
+    }
+}
@@ -0,0 +1,206 @@
+use flate2::write::GzEncoder;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::JoinHandle;
+
+/// Size of the blocks of uncompressed data that are compressed on separate threads
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// Blocks each thread may have queued or compressed but not yet written, which bounds the memory
+/// used while keeping every thread busy
+const BLOCKS_PER_THREAD: usize = 2;
+
+type Member = std::io::Result<Vec<u8>>;
+type Job = (Vec<u8>, SyncSender<Member>);
+
+/// Gzip encoder that compresses blocks of the input on several threads, in the style of pigz.
+/// Each block is written as its own gzip member, so the output is a multi-member gzip file that
+/// `gzip`, `tar` and [`flate2::read::MultiGzDecoder`] decompress as a single stream.
+///
+/// Blocks are handed to a pool of threads that lives as long as the encoder, and the input keeps
+/// being read while earlier blocks are compressed. Members are written in input order.
+pub struct ParallelGzEncoder<W: Write> {
+    inner: W,
+    pool: WorkerPool,
+    block: Vec<u8>,
+    /// Members being compressed, oldest first
+    pending: VecDeque<Receiver<Member>>,
+    capacity: usize,
+    members: usize,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(inner: W, level: flate2::Compression, threads: usize) -> std::io::Result<Self> {
+        let threads = threads.max(1);
+        Ok(Self {
+            inner,
+            pool: WorkerPool::new(level, threads)?,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            pending: VecDeque::new(),
+            capacity: threads * BLOCKS_PER_THREAD,
+            members: 0,
+        })
+    }
+
+    /// Compress the remaining input and return the inner writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        // An empty input still needs one member to be a valid gzip file
+        if !self.block.is_empty() || self.members + self.pending.len() == 0 {
+            self.submit_block()?;
+        }
+        while !self.pending.is_empty() {
+            self.write_member()?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Hand the current block to the pool, first writing the oldest member when the pipeline is full
+    fn submit_block(&mut self) -> std::io::Result<()> {
+        if self.pending.len() >= self.capacity {
+            self.write_member()?;
+        }
+        let block = std::mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+        self.pending.push_back(self.pool.submit(block)?);
+        Ok(())
+    }
+
+    /// Wait for the oldest member and write it
+    fn write_member(&mut self) -> std::io::Result<()> {
+        if let Some(member) = self.pending.pop_front() {
+            let member = member
+                .recv()
+                .map_err(|_| Error::new(ErrorKind::Other, "A compression thread stopped"))??;
+            self.inner.write_all(member.as_slice())?;
+            self.members += 1;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..written]);
+        if self.block.len() == BLOCK_SIZE {
+            self.submit_block()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Threads that compress blocks into gzip members. Each block is sent with the channel its member
+/// is returned on, so members can be collected in order whichever thread compressed them.
+struct WorkerPool {
+    jobs: Option<SyncSender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    fn new(level: flate2::Compression, threads: usize) -> std::io::Result<Self> {
+        let (jobs, queue) = sync_channel::<Job>(threads);
+        let queue = Arc::new(Mutex::new(queue));
+        let workers = (0..threads)
+            .map(|index| {
+                let queue = queue.clone();
+                std::thread::Builder::new()
+                    .name(format!("salvage-gzip-{}", index))
+                    .spawn(move || loop {
+                        let job = queue.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                        let Ok((block, member)) = job else {
+                            return;
+                        };
+                        let mut encoder =
+                            GzEncoder::new(Vec::with_capacity(block.len() / 2), level);
+                        let result = encoder
+                            .write_all(block.as_slice())
+                            .and_then(|_| encoder.finish());
+                        // Nobody is waiting for the member when the encoder was dropped early
+                        let _ = member.send(result);
+                    })
+            })
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            jobs: Some(jobs),
+            workers,
+        })
+    }
+
+    fn submit(&self, block: Vec<u8>) -> std::io::Result<Receiver<Member>> {
+        let (member, receiver) = sync_channel(1);
+        self.jobs
+            .as_ref()
+            .and_then(|jobs| jobs.send((block, member)).ok())
+            .ok_or_else(|| Error::new(ErrorKind::Other, "The compression threads stopped"))?;
+        Ok(receiver)
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        // Closing the queue stops each thread once it has finished its current block
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::Read;
+
+    fn round_trip(data: &[u8], threads: usize) -> Vec<u8> {
+        let mut encoder =
+            ParallelGzEncoder::new(Vec::new(), flate2::Compression::fast(), threads).unwrap();
+        // Write in uneven pieces so blocks are split across writes
+        for piece in data.chunks(300_007) {
+            encoder.write_all(piece).unwrap();
+        }
+        let compressed = encoder.finish().unwrap();
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn writes_members_in_input_order() {
+        let original = data(10 * BLOCK_SIZE + 12_345);
+        for threads in [1, 3, 8] {
+            let decompressed = round_trip(original.as_slice(), threads);
+            assert!(decompressed == original, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn bounds_the_blocks_in_flight() {
+        let original = data(20 * BLOCK_SIZE);
+        let mut encoder =
+            ParallelGzEncoder::new(Vec::new(), flate2::Compression::fast(), 2).unwrap();
+        for block in original.chunks(BLOCK_SIZE) {
+            encoder.write_all(block).unwrap();
+            assert!(encoder.pending.len() <= 2 * BLOCKS_PER_THREAD);
+        }
+        assert_eq!(encoder.members + encoder.pending.len(), 20);
+        encoder.finish().unwrap();
+    }
+
+    #[test]
+    fn writes_a_valid_file_for_empty_input() {
+        let decompressed = round_trip(&[], 4);
+        assert!(decompressed.is_empty());
+    }
+}
@@ -1,14 +1,43 @@
+mod file;
+mod settings;
+
+pub use settings::Settings;
+
+use crate::encryption::{load_recipients, parse_recipients, Encryption, ENCRYPTED_EXTENSION};
 use crate::error::Error;
 use crate::error::Error::{
-    InvalidBackupType, InvalidCompressionType, InvalidPermission, NoVolumeMounted,
+    InvalidBackupType, InvalidCompressionType, InvalidNumber, InvalidPermission,
+    InvalidQuiesceMode, InvalidSetting, MissingSetting, NoVolumeMounted, UnsupportedEncryption,
 };
+use crate::exclude::{check_pattern, ExcludeConfig};
+use crate::metrics::MetricsConfig;
+use crate::notification::email::{SmtpConfig, SmtpSecurity};
+use crate::notification::{GotifyConfig, NotificationConfig, NotifyOn, NtfyConfig, WebhookConfig};
+use crate::retention::RetentionPolicy;
+use crate::scheduler::CronSchedule;
+use crate::storage::s3::{S3Config, DEFAULT_PART_SIZE_MIB, DEFAULT_REGION, MIN_PART_SIZE_MIB};
+use crate::storage::sftp::{SftpConfig, DEFAULT_PORT};
+use crate::storage::StorageConfig;
 use crate::{
-    ARCHIVE_DIR, BACKUP_DIR_ENV, COMPRESSION_ENV, COMPRESSION_LEVEL_ENV, DATA_DIR, DATA_DIR_ENV,
-    GROUP_PERMISSION_ENV, LOG_TARGET, OTHER_PERMISSION_ENV, PREFIX_ENV,
-    SALVAGE_CONTAINER_MANAGEMENT_ENV, SALVAGE_IS_DOCKER, SALVAGE_RUN_ONCE_ENV, STRATEGY_ENV,
+    ARCHIVE_DIR, ARCHIVE_WORKERS_ENV, BACKUP_DIR_ENV, COMPRESSION_ENV, COMPRESSION_LEVEL_ENV,
+    COMPRESSION_THREADS_ENV, DATA_DIR, DATA_DIR_ENV, ENCRYPTION_IDENTITY_FILE_ENV,
+    ENCRYPTION_RECIPIENTS_ENV, ENCRYPTION_RECIPIENTS_FILE_ENV, EXCLUDE_ENV, GROUP_PERMISSION_ENV,
+    INCREMENTAL_FULL_EVERY_ENV, LOG_TARGET, METRICS_ADDRESS_ENV, METRICS_TEXTFILE_DIR_ENV,
+    NOTIFY_GOTIFY_ON_ENV, NOTIFY_GOTIFY_TOKEN_ENV, NOTIFY_GOTIFY_URL_ENV, NOTIFY_NTFY_ON_ENV,
+    NOTIFY_NTFY_TOKEN_ENV, NOTIFY_NTFY_URL_ENV, NOTIFY_ON_ENV, NOTIFY_SMTP_CA_FILE_ENV,
+    NOTIFY_SMTP_FROM_ENV, NOTIFY_SMTP_HOST_ENV, NOTIFY_SMTP_ON_ENV, NOTIFY_SMTP_PASSWORD_ENV,
+    NOTIFY_SMTP_PORT_ENV, NOTIFY_SMTP_SECURITY_ENV, NOTIFY_SMTP_TO_ENV, NOTIFY_SMTP_USERNAME_ENV,
+    NOTIFY_WEBHOOK_ON_ENV, NOTIFY_WEBHOOK_URL_ENV, OTHER_PERMISSION_ENV, PREFIX_ENV,
+    QUIESCE_MODE_ENV, REPOSITORY_DIR_ENV, RETENTION_DRY_RUN_ENV, RETENTION_KEEP_DAILY_ENV,
+    RETENTION_KEEP_LAST_ENV, RETENTION_KEEP_MONTHLY_ENV, RETENTION_KEEP_WEEKLY_ENV,
+    RETENTION_KEEP_YEARLY_ENV, RETENTION_MAX_AGE_ENV, S3_ACCESS_KEY_ID_ENV, S3_BUCKET_ENV,
+    S3_ENDPOINT_ENV, S3_PART_SIZE_ENV, S3_PATH_STYLE_ENV, S3_PREFIX_ENV, S3_REGION_ENV,
+    S3_SECRET_ACCESS_KEY_ENV, SALVAGE_CONTAINER_MANAGEMENT_ENV, SALVAGE_IS_DOCKER,
+    SALVAGE_RUN_ONCE_ENV, SCHEDULE_ENV, SFTP_HOST_ENV, SFTP_HOST_KEY_ENV, SFTP_PORT_ENV,
+    SFTP_PRIVATE_KEY_ENV, SFTP_PRIVATE_KEY_PASSPHRASE_ENV, SFTP_REMOTE_DIR_ENV, SFTP_USERNAME_ENV,
+    STORAGE_DELETE_LOCAL_ENV, STRATEGY_ENV, VOLUME_EXCLUDE_ENV,
 };
 use log::{debug, warn};
-use std::env;
 use std::fmt::{Display, Formatter};
 use std::fs::Permissions;
 use std::num::IntErrorKind;
@@ -17,30 +46,50 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 pub trait DefaultEnv: Default + Display + FromStr<Err = Error> {
-    fn env_or_default<S: AsRef<str>>(key: S) -> Result<Self, Error> {
-        match env::var(key.as_ref()) {
-            Ok(s) => Self::from_str(s.as_str()),
-            Err(e) => {
+    fn env_or_default<S: AsRef<str>>(settings: &Settings, key: S) -> Result<Self, Error> {
+        match settings.get(key.as_ref()) {
+            Some(s) => Self::from_str(s.as_str())
+                .map_err(|e| InvalidSetting(settings.describe(key.as_ref()), e.to_string())),
+            None => {
                 let val = Self::default();
-                debug!(target: LOG_TARGET, "Using default value({}) for environment key {} because {}", val.to_string(),key.as_ref(), e);
+                debug!(target: LOG_TARGET, "Using default value({}) for setting {} because it is not set", val.to_string(), key.as_ref());
                 Ok(val)
             }
         }
     }
 }
 
+/// Directory in the backup directory used for the repository when none is provided
+const REPOSITORY_DIR: &str = "repository";
+/// Number of archives in each incremental chain, including the full archive it starts with
+const DEFAULT_INCREMENTAL_FULL_EVERY: u32 = 7;
+/// Number of volumes archived at the same time by the `Multiple` strategy
+const DEFAULT_ARCHIVE_WORKERS: u32 = 1;
+
 pub struct Configuration {
     pub data_dir: PathBuf,
     pub backup_dir: PathBuf,
     pub archive_strategy: ArchiveStrategy,
     pub archive_compression: ArchiveCompression,
     pub archive_compression_level: u32,
+    pub archive_compression_threads: u32,
     pub archive_prefix: String,
+    pub incremental_full_every: u32,
+    pub archive_workers: u32,
+    pub exclude: ExcludeConfig,
+    pub repository_dir: PathBuf,
     pub group_permission: ArchivePermission,
     pub other_permission: ArchivePermission,
     pub stop_containers: bool,
+    pub quiesce_mode: QuiesceMode,
     pub is_docker: bool,
     pub run_once: bool,
+    pub schedule: CronSchedule,
+    pub retention: RetentionPolicy,
+    pub encryption: Encryption,
+    pub storage: StorageConfig,
+    pub notification: NotificationConfig,
+    pub metrics: MetricsConfig,
 }
 
 #[derive(Default)]
@@ -48,6 +97,8 @@ pub enum ArchiveStrategy {
     #[default]
     Multiple,
     Single,
+    Incremental,
+    Repository,
 }
 
 #[derive(Default)]
@@ -67,10 +118,35 @@ pub enum ArchivePermission {
     None,
 }
 
+/// How the containers sharing a volume are kept from changing it while it is archived
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum QuiesceMode {
+    /// Stop the container and start it again afterwards
+    #[default]
+    Stop,
+    /// Freeze the processes of the container and unfreeze them afterwards, keeping its state in
+    /// memory
+    Pause,
+    /// Leave the container running
+    None,
+}
+
 impl Configuration {
     pub fn container_management_enabled(&self) -> bool {
         self.is_docker && self.stop_containers
     }
+
+    /// Extension of the archives created with this configuration: `tar.{ext}` or `tar.{ext}.age`
+    pub fn archive_extension(&self) -> String {
+        match self.encryption.is_enabled() {
+            true => format!(
+                "tar.{}.{}",
+                self.archive_compression.extension(),
+                ENCRYPTED_EXTENSION
+            ),
+            false => format!("tar.{}", self.archive_compression.extension()),
+        }
+    }
 }
 
 impl DefaultEnv for ArchiveStrategy {}
@@ -80,6 +156,8 @@ impl Display for ArchiveStrategy {
         match self {
             ArchiveStrategy::Single => write!(f, "Single"),
             ArchiveStrategy::Multiple => write!(f, "Multiple"),
+            ArchiveStrategy::Incremental => write!(f, "Incremental"),
+            ArchiveStrategy::Repository => write!(f, "Repository"),
         }
     }
 }
@@ -91,6 +169,8 @@ impl FromStr for ArchiveStrategy {
         match s.to_ascii_lowercase().as_str() {
             "s" | "single" => Ok(Self::Single),
             "m" | "multiple" => Ok(Self::Multiple),
+            "i" | "incremental" => Ok(Self::Incremental),
+            "r" | "repository" => Ok(Self::Repository),
             _ => Err(InvalidBackupType),
         }
     }
@@ -134,6 +214,16 @@ impl ArchiveCompression {
         .to_string()
     }
 
+    pub fn from_extension<S: AsRef<str>>(extension: S) -> Option<Self> {
+        match extension.as_ref() {
+            "bz2" => Some(Self::Bzip2),
+            "gz" => Some(Self::Gzip),
+            "xz" => Some(Self::Xz),
+            "zst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
     pub fn parse_level<S: AsRef<str>>(&self, value: S) -> u32 {
         match value.as_ref().trim().parse::<u32>() {
             Ok(level) => {
@@ -191,6 +281,31 @@ impl FromStr for ArchivePermission {
     }
 }
 
+impl DefaultEnv for QuiesceMode {}
+
+impl Display for QuiesceMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuiesceMode::Stop => write!(f, "Stop"),
+            QuiesceMode::Pause => write!(f, "Pause"),
+            QuiesceMode::None => write!(f, "None"),
+        }
+    }
+}
+
+impl FromStr for QuiesceMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "stop" => Ok(Self::Stop),
+            "pause" => Ok(Self::Pause),
+            "none" => Ok(Self::None),
+            _ => Err(InvalidQuiesceMode),
+        }
+    }
+}
+
 impl Configuration {
     pub fn archive_permission(&self) -> Permissions {
         get_permission(&self.group_permission, &self.other_permission)
@@ -211,24 +326,82 @@ pub fn get_permission(group: &ArchivePermission, other: &ArchivePermission) -> P
     }
 }
 
-pub fn validate_config() -> Result<Configuration, Error> {
-    let data_dir = PathBuf::from(env::var(DATA_DIR_ENV).unwrap_or(DATA_DIR.into()));
-    let backup_dir = PathBuf::from(env::var(BACKUP_DIR_ENV).unwrap_or(ARCHIVE_DIR.into()));
-    let archive_strategy = ArchiveStrategy::env_or_default(STRATEGY_ENV)?;
-    let archive_compression = ArchiveCompression::env_or_default(COMPRESSION_ENV)?;
+pub fn validate_config(settings: &Settings) -> Result<Configuration, Error> {
+    let data_dir = PathBuf::from(settings.get(DATA_DIR_ENV).unwrap_or(DATA_DIR.into()));
+    let backup_dir = PathBuf::from(settings.get(BACKUP_DIR_ENV).unwrap_or(ARCHIVE_DIR.into()));
+    let archive_strategy = ArchiveStrategy::env_or_default(settings, STRATEGY_ENV)?;
+    let archive_compression = ArchiveCompression::env_or_default(settings, COMPRESSION_ENV)?;
     let archive_compression_level =
-        archive_compression.parse_level(env::var(COMPRESSION_LEVEL_ENV).unwrap_or_default());
-    let archive_prefix = env::var(PREFIX_ENV).unwrap_or(LOG_TARGET.to_string());
-    let group_permission = ArchivePermission::env_or_default(GROUP_PERMISSION_ENV)?;
-    let other_permission = ArchivePermission::env_or_default(OTHER_PERMISSION_ENV)?;
-    let stop_containers = get_env_bool(SALVAGE_CONTAINER_MANAGEMENT_ENV, true);
-    let is_docker = get_env_bool(SALVAGE_IS_DOCKER, false);
-    let run_once = get_env_bool(SALVAGE_RUN_ONCE_ENV, false);
+        archive_compression.parse_level(settings.get(COMPRESSION_LEVEL_ENV).unwrap_or_default());
+    let archive_compression_threads = get_env_u32(settings, COMPRESSION_THREADS_ENV)?.unwrap_or(1);
+    if archive_compression_threads > 1 && matches!(archive_compression, ArchiveCompression::Bzip2) {
+        warn!(target: LOG_TARGET, "{} is not supported for bzip2 compression. Using one thread.", COMPRESSION_THREADS_ENV);
+    }
+    let archive_prefix = settings.get(PREFIX_ENV).unwrap_or(LOG_TARGET.to_string());
+    let incremental_full_every = get_env_u32(settings, INCREMENTAL_FULL_EVERY_ENV)?
+        .unwrap_or(DEFAULT_INCREMENTAL_FULL_EVERY);
+    let archive_workers =
+        get_env_u32(settings, ARCHIVE_WORKERS_ENV)?.unwrap_or(DEFAULT_ARCHIVE_WORKERS);
+    let group_permission = ArchivePermission::env_or_default(settings, GROUP_PERMISSION_ENV)?;
+    let other_permission = ArchivePermission::env_or_default(settings, OTHER_PERMISSION_ENV)?;
+    let stop_containers = get_env_bool(settings, SALVAGE_CONTAINER_MANAGEMENT_ENV, true);
+    let quiesce_mode = QuiesceMode::env_or_default(settings, QUIESCE_MODE_ENV)?;
+    let is_docker = get_env_bool(settings, SALVAGE_IS_DOCKER, false);
+    let run_once = get_env_bool(settings, SALVAGE_RUN_ONCE_ENV, false);
+    let schedule = CronSchedule::env_or_default(settings, SCHEDULE_ENV)?;
+    let mut recipients =
+        parse_recipients(settings.get(ENCRYPTION_RECIPIENTS_ENV).unwrap_or_default())?;
+    if let Some(path) = settings.get(ENCRYPTION_RECIPIENTS_FILE_ENV) {
+        recipients.append(&mut load_recipients(path)?);
+    }
+    let encryption = Encryption {
+        recipients,
+        identity_file: settings
+            .get(ENCRYPTION_IDENTITY_FILE_ENV)
+            .map(PathBuf::from),
+    };
+    let retention = RetentionPolicy {
+        keep_last: get_env_u32(settings, RETENTION_KEEP_LAST_ENV)?,
+        keep_daily: get_env_u32(settings, RETENTION_KEEP_DAILY_ENV)?,
+        keep_weekly: get_env_u32(settings, RETENTION_KEEP_WEEKLY_ENV)?,
+        keep_monthly: get_env_u32(settings, RETENTION_KEEP_MONTHLY_ENV)?,
+        keep_yearly: get_env_u32(settings, RETENTION_KEEP_YEARLY_ENV)?,
+        max_age_days: get_env_u32(settings, RETENTION_MAX_AGE_ENV)?,
+        dry_run: get_env_bool(settings, RETENTION_DRY_RUN_ENV, false),
+    };
+    let storage = StorageConfig {
+        s3: s3_config(settings)?,
+        sftp: sftp_config(settings)?,
+        delete_local: get_env_bool(settings, STORAGE_DELETE_LOCAL_ENV, false),
+    };
+    let notification = notification_config(settings)?;
+    let metrics = MetricsConfig {
+        address: settings
+            .get(METRICS_ADDRESS_ENV)
+            .map(|a| a.trim().to_string())
+            .filter(|a| !a.is_empty()),
+        textfile_dir: settings
+            .get(METRICS_TEXTFILE_DIR_ENV)
+            .filter(|d| !d.trim().is_empty())
+            .map(PathBuf::from),
+    };
+
+    let exclude = exclude_config(settings)?;
+
+    let repository_dir = settings
+        .get(REPOSITORY_DIR_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| backup_dir.join(REPOSITORY_DIR));
+    if matches!(archive_strategy, ArchiveStrategy::Repository) && encryption.is_enabled() {
+        return Err(UnsupportedEncryption(archive_strategy.to_string()));
+    }
 
     if !data_dir.as_path().is_dir() {
         return Err(NoVolumeMounted(data_dir.to_string_lossy().into()));
     } else if !backup_dir.as_path().is_dir() {
         return Err(NoVolumeMounted(backup_dir.to_string_lossy().into()));
+    } else if let Some(textfile_dir) = metrics.textfile_dir.as_ref().filter(|d| !d.is_dir()) {
+        return Err(NoVolumeMounted(textfile_dir.to_string_lossy().into()));
     }
 
     let valid_env = Configuration {
@@ -237,20 +410,228 @@ pub fn validate_config() -> Result<Configuration, Error> {
         archive_strategy,
         archive_compression,
         archive_compression_level,
+        archive_compression_threads,
         archive_prefix,
+        incremental_full_every,
+        archive_workers,
+        exclude,
+        repository_dir,
         group_permission,
         other_permission,
         stop_containers,
+        quiesce_mode,
         is_docker,
         run_once,
+        schedule,
+        retention,
+        encryption,
+        storage,
+        notification,
+        metrics,
     };
 
     Ok(valid_env)
 }
 
-fn get_env_bool(key: &str, default: bool) -> bool {
-    match env::var(key) {
-        Ok(value) => value.eq_ignore_ascii_case("true"),
-        Err(_) => default,
+/// Read the notification settings. Each notifier is enabled by setting its URL and is sent for the
+/// outcome in `SALVAGE_NOTIFY_ON` unless it has its own setting.
+fn notification_config(settings: &Settings) -> Result<NotificationConfig, Error> {
+    let default_on = NotifyOn::env_or_default(settings, NOTIFY_ON_ENV)?;
+    let on = |key: &str| match settings.get(key) {
+        Some(value) if !value.trim().is_empty() => NotifyOn::from_str(value.trim())
+            .map_err(|e| InvalidSetting(settings.describe(key), e.to_string())),
+        _ => Ok(default_on),
+    };
+    let url = |key: &str| match settings.get(key) {
+        Some(url) if !url.trim().is_empty() => Some(url.trim().to_string()),
+        _ => None,
+    };
+
+    let webhook = match url(NOTIFY_WEBHOOK_URL_ENV) {
+        Some(url) => Some(WebhookConfig {
+            url,
+            on: on(NOTIFY_WEBHOOK_ON_ENV)?,
+        }),
+        None => None,
+    };
+    let ntfy = match url(NOTIFY_NTFY_URL_ENV) {
+        Some(url) => Some(NtfyConfig {
+            url,
+            token: settings
+                .get(NOTIFY_NTFY_TOKEN_ENV)
+                .filter(|t| !t.trim().is_empty()),
+            on: on(NOTIFY_NTFY_ON_ENV)?,
+        }),
+        None => None,
+    };
+    let gotify = match url(NOTIFY_GOTIFY_URL_ENV) {
+        Some(url) => Some(GotifyConfig {
+            url: url.trim_end_matches('/').to_string(),
+            token: get_env_required(settings, NOTIFY_GOTIFY_TOKEN_ENV)?,
+            on: on(NOTIFY_GOTIFY_ON_ENV)?,
+        }),
+        None => None,
+    };
+    let smtp = match url(NOTIFY_SMTP_HOST_ENV) {
+        Some(host) => {
+            let security = SmtpSecurity::env_or_default(settings, NOTIFY_SMTP_SECURITY_ENV)?;
+            let port = match get_env_u32(settings, NOTIFY_SMTP_PORT_ENV)? {
+                Some(port) => u16::try_from(port)
+                    .map_err(|_| InvalidNumber(settings.describe(NOTIFY_SMTP_PORT_ENV)))?,
+                None => security.default_port(),
+            };
+            let to: Vec<String> = get_env_required(settings, NOTIFY_SMTP_TO_ENV)?
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+            let optional = |key: &str| settings.get(key).filter(|v| !v.is_empty());
+            Some(SmtpConfig {
+                host,
+                port,
+                security,
+                username: optional(NOTIFY_SMTP_USERNAME_ENV),
+                password: optional(NOTIFY_SMTP_PASSWORD_ENV),
+                from: get_env_required(settings, NOTIFY_SMTP_FROM_ENV)?
+                    .trim()
+                    .to_string(),
+                to,
+                ca_file: optional(NOTIFY_SMTP_CA_FILE_ENV).map(PathBuf::from),
+                on: on(NOTIFY_SMTP_ON_ENV)?,
+            })
+        }
+        None => None,
+    };
+
+    Ok(NotificationConfig {
+        webhook,
+        ntfy,
+        gotify,
+        smtp,
+    })
+}
+
+/// Read the S3 storage backend settings, which are enabled by setting the bucket
+fn s3_config(settings: &Settings) -> Result<Option<S3Config>, Error> {
+    let bucket = match settings.get(S3_BUCKET_ENV) {
+        Some(bucket) if !bucket.trim().is_empty() => bucket.trim().to_string(),
+        _ => return Ok(None),
+    };
+    let part_size_mib = match get_env_u32(settings, S3_PART_SIZE_ENV)?.map(u64::from) {
+        Some(size) if size < MIN_PART_SIZE_MIB => {
+            warn!(target: LOG_TARGET, "Provided S3 part size of {} MiB is less than the minimum. Using minimum of {} MiB.", size, MIN_PART_SIZE_MIB);
+            MIN_PART_SIZE_MIB
+        }
+        Some(size) => size,
+        None => DEFAULT_PART_SIZE_MIB,
+    };
+
+    Ok(Some(S3Config {
+        bucket,
+        prefix: settings
+            .get(S3_PREFIX_ENV)
+            .unwrap_or_default()
+            .trim_matches('/')
+            .to_string(),
+        endpoint: settings
+            .get(S3_ENDPOINT_ENV)
+            .map(|e| e.trim_end_matches('/').to_string()),
+        region: settings
+            .get(S3_REGION_ENV)
+            .unwrap_or(DEFAULT_REGION.to_string()),
+        access_key_id: get_env_required(settings, S3_ACCESS_KEY_ID_ENV)?,
+        secret_access_key: get_env_required(settings, S3_SECRET_ACCESS_KEY_ENV)?,
+        path_style: get_env_bool(settings, S3_PATH_STYLE_ENV, false),
+        part_size_mib,
+    }))
+}
+
+/// Read the SFTP storage backend settings, which are enabled by setting the host
+fn sftp_config(settings: &Settings) -> Result<Option<SftpConfig>, Error> {
+    let host = match settings.get(SFTP_HOST_ENV) {
+        Some(host) if !host.trim().is_empty() => host.trim().to_string(),
+        _ => return Ok(None),
+    };
+    let port = match get_env_u32(settings, SFTP_PORT_ENV)? {
+        Some(port) => {
+            u16::try_from(port).map_err(|_| InvalidNumber(settings.describe(SFTP_PORT_ENV)))?
+        }
+        None => DEFAULT_PORT,
+    };
+
+    Ok(Some(SftpConfig {
+        host,
+        port,
+        username: get_env_required(settings, SFTP_USERNAME_ENV)?,
+        private_key: PathBuf::from(get_env_required(settings, SFTP_PRIVATE_KEY_ENV)?),
+        passphrase: settings
+            .get(SFTP_PRIVATE_KEY_PASSPHRASE_ENV)
+            .filter(|p| !p.is_empty()),
+        host_key: settings
+            .get(SFTP_HOST_KEY_ENV)
+            .map(|k| k.trim().to_string())
+            .filter(|k| !k.is_empty()),
+        remote_dir: PathBuf::from(settings.get(SFTP_REMOTE_DIR_ENV).unwrap_or(".".to_string())),
+    }))
+}
+
+/// Read the exclude patterns. Patterns for a single volume are given as `volume:pattern`.
+fn exclude_config(settings: &Settings) -> Result<ExcludeConfig, Error> {
+    let list = |key: &str| -> Result<Vec<String>, Error> {
+        let patterns: Vec<String> = settings
+            .get(key)
+            .unwrap_or_default()
+            .split(',')
+            .map(|p| p.trim().to_string())
+            .filter(|p| !p.is_empty())
+            .collect();
+        for pattern in patterns.iter() {
+            check_pattern(pattern.as_str())
+                .map_err(|e| InvalidSetting(settings.describe(key), e))?;
+        }
+        Ok(patterns)
+    };
+    let volume_patterns = list(VOLUME_EXCLUDE_ENV)?
+        .into_iter()
+        .map(|p| match p.split_once(':') {
+            Some((volume, pattern)) if !volume.is_empty() && !pattern.is_empty() => {
+                Ok((volume.to_string(), pattern.to_string()))
+            }
+            _ => Err(InvalidSetting(
+                settings.describe(VOLUME_EXCLUDE_ENV),
+                format!("{} is not in the format volume:pattern", p),
+            )),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(ExcludeConfig {
+        patterns: list(EXCLUDE_ENV)?,
+        volume_patterns,
+    })
+}
+
+fn get_env_required(settings: &Settings, key: &str) -> Result<String, Error> {
+    match settings.get(key) {
+        Some(value) if !value.trim().is_empty() => Ok(value),
+        _ => Err(MissingSetting(settings.describe(key))),
+    }
+}
+
+fn get_env_bool(settings: &Settings, key: &str, default: bool) -> bool {
+    match settings.get(key) {
+        Some(value) => value.eq_ignore_ascii_case("true"),
+        None => default,
+    }
+}
+
+/// Read an optional whole number from the environment. Empty values and `0` are treated as unset.
+fn get_env_u32(settings: &Settings, key: &str) -> Result<Option<u32>, Error> {
+    match settings.get(key) {
+        Some(value) if value.trim().is_empty() => Ok(None),
+        Some(value) => match value.trim().parse::<u32>() {
+            Ok(0) => Ok(None),
+            Ok(number) => Ok(Some(number)),
+            Err(_) => Err(InvalidNumber(settings.describe(key))),
+        },
+        None => Ok(None),
     }
 }
@@ -0,0 +1,399 @@
+use crate::configuration::{ArchiveCompression, ArchivePermission, ArchiveStrategy, QuiesceMode};
+use crate::error::Error;
+use crate::notification::email::SmtpSecurity;
+use crate::notification::NotifyOn;
+use crate::scheduler::CronSchedule;
+use crate::{
+    ARCHIVE_WORKERS_ENV, BACKUP_DIR_ENV, COMPRESSION_ENV, COMPRESSION_LEVEL_ENV,
+    COMPRESSION_THREADS_ENV, DATA_DIR_ENV, ENCRYPTION_IDENTITY_FILE_ENV, ENCRYPTION_RECIPIENTS_ENV,
+    ENCRYPTION_RECIPIENTS_FILE_ENV, EXCLUDE_ENV, GROUP_PERMISSION_ENV, INCREMENTAL_FULL_EVERY_ENV,
+    METRICS_ADDRESS_ENV, METRICS_TEXTFILE_DIR_ENV, NOTIFY_GOTIFY_ON_ENV, NOTIFY_GOTIFY_TOKEN_ENV,
+    NOTIFY_GOTIFY_URL_ENV, NOTIFY_NTFY_ON_ENV, NOTIFY_NTFY_TOKEN_ENV, NOTIFY_NTFY_URL_ENV,
+    NOTIFY_ON_ENV, NOTIFY_SMTP_CA_FILE_ENV, NOTIFY_SMTP_FROM_ENV, NOTIFY_SMTP_HOST_ENV,
+    NOTIFY_SMTP_ON_ENV, NOTIFY_SMTP_PASSWORD_ENV, NOTIFY_SMTP_PORT_ENV, NOTIFY_SMTP_SECURITY_ENV,
+    NOTIFY_SMTP_TO_ENV, NOTIFY_SMTP_USERNAME_ENV, NOTIFY_WEBHOOK_ON_ENV, NOTIFY_WEBHOOK_URL_ENV,
+    OTHER_PERMISSION_ENV, PREFIX_ENV, QUIESCE_MODE_ENV, REPOSITORY_DIR_ENV, RETENTION_DRY_RUN_ENV,
+    RETENTION_KEEP_DAILY_ENV, RETENTION_KEEP_LAST_ENV, RETENTION_KEEP_MONTHLY_ENV,
+    RETENTION_KEEP_WEEKLY_ENV, RETENTION_KEEP_YEARLY_ENV, RETENTION_MAX_AGE_ENV,
+    S3_ACCESS_KEY_ID_ENV, S3_BUCKET_ENV, S3_ENDPOINT_ENV, S3_PART_SIZE_ENV, S3_PATH_STYLE_ENV,
+    S3_PREFIX_ENV, S3_REGION_ENV, S3_SECRET_ACCESS_KEY_ENV, SALVAGE_CONTAINER_MANAGEMENT_ENV,
+    SALVAGE_IS_DOCKER, SALVAGE_RUN_ONCE_ENV, SCHEDULE_ENV, SFTP_HOST_ENV, SFTP_HOST_KEY_ENV,
+    SFTP_PORT_ENV, SFTP_PRIVATE_KEY_ENV, SFTP_PRIVATE_KEY_PASSPHRASE_ENV, SFTP_REMOTE_DIR_ENV,
+    SFTP_USERNAME_ENV, STORAGE_DELETE_LOCAL_ENV, STRATEGY_ENV, VOLUME_EXCLUDE_ENV,
+};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Key in the configuration file of each setting, identified by its environment variable
+const KEYS: &[(&str, &str)] = &[
+    (DATA_DIR_ENV, "data_dir"),
+    (BACKUP_DIR_ENV, "backup_dir"),
+    (REPOSITORY_DIR_ENV, "repository_dir"),
+    (SCHEDULE_ENV, "schedule"),
+    (SALVAGE_RUN_ONCE_ENV, "run_once"),
+    (SALVAGE_IS_DOCKER, "is_docker"),
+    (STRATEGY_ENV, "archive.strategy"),
+    (PREFIX_ENV, "archive.prefix"),
+    (COMPRESSION_ENV, "archive.compression"),
+    (COMPRESSION_LEVEL_ENV, "archive.compression_level"),
+    (COMPRESSION_THREADS_ENV, "archive.compression_threads"),
+    (ARCHIVE_WORKERS_ENV, "archive.workers"),
+    (INCREMENTAL_FULL_EVERY_ENV, "archive.incremental_full_every"),
+    (GROUP_PERMISSION_ENV, "archive.group_permission"),
+    (OTHER_PERMISSION_ENV, "archive.other_permission"),
+    (EXCLUDE_ENV, "exclude.patterns"),
+    (VOLUME_EXCLUDE_ENV, "exclude.volumes"),
+    (SALVAGE_CONTAINER_MANAGEMENT_ENV, "containers.management"),
+    (QUIESCE_MODE_ENV, "containers.quiesce"),
+    (ENCRYPTION_RECIPIENTS_ENV, "encryption.recipients"),
+    (ENCRYPTION_RECIPIENTS_FILE_ENV, "encryption.recipients_file"),
+    (ENCRYPTION_IDENTITY_FILE_ENV, "encryption.identity_file"),
+    (STORAGE_DELETE_LOCAL_ENV, "storage.delete_local"),
+    (S3_BUCKET_ENV, "storage.s3.bucket"),
+    (S3_PREFIX_ENV, "storage.s3.prefix"),
+    (S3_ENDPOINT_ENV, "storage.s3.endpoint"),
+    (S3_REGION_ENV, "storage.s3.region"),
+    (S3_ACCESS_KEY_ID_ENV, "storage.s3.access_key_id"),
+    (S3_SECRET_ACCESS_KEY_ENV, "storage.s3.secret_access_key"),
+    (S3_PATH_STYLE_ENV, "storage.s3.path_style"),
+    (S3_PART_SIZE_ENV, "storage.s3.part_size"),
+    (SFTP_HOST_ENV, "storage.sftp.host"),
+    (SFTP_PORT_ENV, "storage.sftp.port"),
+    (SFTP_USERNAME_ENV, "storage.sftp.username"),
+    (SFTP_PRIVATE_KEY_ENV, "storage.sftp.private_key"),
+    (
+        SFTP_PRIVATE_KEY_PASSPHRASE_ENV,
+        "storage.sftp.private_key_passphrase",
+    ),
+    (SFTP_HOST_KEY_ENV, "storage.sftp.host_key"),
+    (SFTP_REMOTE_DIR_ENV, "storage.sftp.remote_dir"),
+    (RETENTION_KEEP_LAST_ENV, "retention.keep_last"),
+    (RETENTION_KEEP_DAILY_ENV, "retention.keep_daily"),
+    (RETENTION_KEEP_WEEKLY_ENV, "retention.keep_weekly"),
+    (RETENTION_KEEP_MONTHLY_ENV, "retention.keep_monthly"),
+    (RETENTION_KEEP_YEARLY_ENV, "retention.keep_yearly"),
+    (RETENTION_MAX_AGE_ENV, "retention.max_age"),
+    (RETENTION_DRY_RUN_ENV, "retention.dry_run"),
+    (NOTIFY_ON_ENV, "notify.on"),
+    (NOTIFY_WEBHOOK_URL_ENV, "notify.webhook.url"),
+    (NOTIFY_WEBHOOK_ON_ENV, "notify.webhook.on"),
+    (NOTIFY_NTFY_URL_ENV, "notify.ntfy.url"),
+    (NOTIFY_NTFY_TOKEN_ENV, "notify.ntfy.token"),
+    (NOTIFY_NTFY_ON_ENV, "notify.ntfy.on"),
+    (NOTIFY_GOTIFY_URL_ENV, "notify.gotify.url"),
+    (NOTIFY_GOTIFY_TOKEN_ENV, "notify.gotify.token"),
+    (NOTIFY_GOTIFY_ON_ENV, "notify.gotify.on"),
+    (NOTIFY_SMTP_HOST_ENV, "notify.smtp.host"),
+    (NOTIFY_SMTP_PORT_ENV, "notify.smtp.port"),
+    (NOTIFY_SMTP_SECURITY_ENV, "notify.smtp.security"),
+    (NOTIFY_SMTP_USERNAME_ENV, "notify.smtp.username"),
+    (NOTIFY_SMTP_PASSWORD_ENV, "notify.smtp.password"),
+    (NOTIFY_SMTP_FROM_ENV, "notify.smtp.from"),
+    (NOTIFY_SMTP_TO_ENV, "notify.smtp.to"),
+    (NOTIFY_SMTP_CA_FILE_ENV, "notify.smtp.ca_file"),
+    (NOTIFY_SMTP_ON_ENV, "notify.smtp.on"),
+    (METRICS_ADDRESS_ENV, "metrics.address"),
+    (METRICS_TEXTFILE_DIR_ENV, "metrics.textfile_dir"),
+];
+
+/// Key in the configuration file of the setting with the environment variable `env`
+pub fn file_key(env: &str) -> Option<&'static str> {
+    KEYS.iter().find(|(e, _)| e.eq(&env)).map(|(_, key)| *key)
+}
+
+/// Contents of the configuration file. Unknown keys, values of the wrong type and values that are
+/// not valid for their setting are rejected by the parser, which reports where they are.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    data_dir: Option<String>,
+    backup_dir: Option<String>,
+    repository_dir: Option<String>,
+    #[serde(default, deserialize_with = "parsed::<CronSchedule, _>")]
+    schedule: Option<String>,
+    run_once: Option<bool>,
+    is_docker: Option<bool>,
+    #[serde(default)]
+    archive: ArchiveSection,
+    #[serde(default)]
+    exclude: ExcludeSection,
+    #[serde(default)]
+    containers: ContainersSection,
+    #[serde(default)]
+    encryption: EncryptionSection,
+    #[serde(default)]
+    storage: StorageSection,
+    #[serde(default)]
+    retention: RetentionSection,
+    #[serde(default)]
+    notify: NotifySection,
+    #[serde(default)]
+    metrics: MetricsSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ArchiveSection {
+    #[serde(default, deserialize_with = "parsed::<ArchiveStrategy, _>")]
+    strategy: Option<String>,
+    prefix: Option<String>,
+    #[serde(default, deserialize_with = "parsed::<ArchiveCompression, _>")]
+    compression: Option<String>,
+    compression_level: Option<u32>,
+    compression_threads: Option<u32>,
+    workers: Option<u32>,
+    incremental_full_every: Option<u32>,
+    #[serde(default, deserialize_with = "parsed::<ArchivePermission, _>")]
+    group_permission: Option<String>,
+    #[serde(default, deserialize_with = "parsed::<ArchivePermission, _>")]
+    other_permission: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ExcludeSection {
+    /// Patterns applied to every volume
+    patterns: Option<Vec<String>>,
+    /// Patterns applied to a single volume, keyed by volume
+    volumes: Option<BTreeMap<String, Vec<String>>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ContainersSection {
+    management: Option<bool>,
+    #[serde(default, deserialize_with = "parsed::<QuiesceMode, _>")]
+    quiesce: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EncryptionSection {
+    recipients: Option<Vec<String>>,
+    recipients_file: Option<String>,
+    identity_file: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct StorageSection {
+    delete_local: Option<bool>,
+    #[serde(default)]
+    s3: S3Section,
+    #[serde(default)]
+    sftp: SftpSection,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct S3Section {
+    bucket: Option<String>,
+    prefix: Option<String>,
+    endpoint: Option<String>,
+    region: Option<String>,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    path_style: Option<bool>,
+    part_size: Option<u32>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SftpSection {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    private_key: Option<String>,
+    private_key_passphrase: Option<String>,
+    host_key: Option<String>,
+    remote_dir: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct RetentionSection {
+    keep_last: Option<u32>,
+    keep_daily: Option<u32>,
+    keep_weekly: Option<u32>,
+    keep_monthly: Option<u32>,
+    keep_yearly: Option<u32>,
+    max_age: Option<u32>,
+    dry_run: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct NotifySection {
+    #[serde(default, deserialize_with = "parsed::<NotifyOn, _>")]
+    on: Option<String>,
+    #[serde(default)]
+    webhook: NotifierSection,
+    #[serde(default)]
+    ntfy: NotifierSection,
+    #[serde(default)]
+    gotify: NotifierSection,
+    #[serde(default)]
+    smtp: SmtpSection,
+}
+
+/// Settings of the webhook, ntfy and Gotify notifiers. The webhook has no token.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct NotifierSection {
+    url: Option<String>,
+    token: Option<String>,
+    #[serde(default, deserialize_with = "parsed::<NotifyOn, _>")]
+    on: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct SmtpSection {
+    host: Option<String>,
+    port: Option<u16>,
+    #[serde(default, deserialize_with = "parsed::<SmtpSecurity, _>")]
+    security: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    from: Option<String>,
+    to: Option<Vec<String>>,
+    ca_file: Option<String>,
+    #[serde(default, deserialize_with = "parsed::<NotifyOn, _>")]
+    on: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct MetricsSection {
+    address: Option<String>,
+    textfile_dir: Option<String>,
+}
+
+/// Deserialize text that must be a valid value of `T`, keeping the text
+fn parsed<'de, T, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    T: FromStr<Err = Error>,
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    T::from_str(value.as_str()).map_err(D::Error::custom)?;
+    Ok(Some(value))
+}
+
+/// Values of the settings set in the file, keyed by environment variable
+#[derive(Default)]
+struct Values(Vec<(&'static str, String)>);
+
+impl Values {
+    fn add<T: ToString>(&mut self, env: &'static str, value: Option<T>) {
+        if let Some(value) = value {
+            self.0.push((env, value.to_string()));
+        }
+    }
+
+    /// Lists are joined with commas, which is how they are given in environment variables
+    fn list(&mut self, env: &'static str, values: Option<Vec<String>>) {
+        self.add(env, values.map(|v| v.join(",")));
+    }
+}
+
+impl ConfigFile {
+    /// The settings set in the file as pairs of environment variable and value
+    pub fn into_values(self) -> Vec<(&'static str, String)> {
+        let mut values = Values::default();
+        values.add(DATA_DIR_ENV, self.data_dir);
+        values.add(BACKUP_DIR_ENV, self.backup_dir);
+        values.add(REPOSITORY_DIR_ENV, self.repository_dir);
+        values.add(SCHEDULE_ENV, self.schedule);
+        values.add(SALVAGE_RUN_ONCE_ENV, self.run_once);
+        values.add(SALVAGE_IS_DOCKER, self.is_docker);
+
+        let archive = self.archive;
+        values.add(STRATEGY_ENV, archive.strategy);
+        values.add(PREFIX_ENV, archive.prefix);
+        values.add(COMPRESSION_ENV, archive.compression);
+        values.add(COMPRESSION_LEVEL_ENV, archive.compression_level);
+        values.add(COMPRESSION_THREADS_ENV, archive.compression_threads);
+        values.add(ARCHIVE_WORKERS_ENV, archive.workers);
+        values.add(INCREMENTAL_FULL_EVERY_ENV, archive.incremental_full_every);
+        values.add(GROUP_PERMISSION_ENV, archive.group_permission);
+        values.add(OTHER_PERMISSION_ENV, archive.other_permission);
+
+        values.list(EXCLUDE_ENV, self.exclude.patterns);
+        values.list(
+            VOLUME_EXCLUDE_ENV,
+            self.exclude.volumes.map(|volumes| {
+                volumes
+                    .into_iter()
+                    .flat_map(|(volume, patterns)| {
+                        patterns
+                            .into_iter()
+                            .map(move |p| format!("{}:{}", volume, p))
+                    })
+                    .collect()
+            }),
+        );
+
+        values.add(SALVAGE_CONTAINER_MANAGEMENT_ENV, self.containers.management);
+        values.add(QUIESCE_MODE_ENV, self.containers.quiesce);
+
+        let encryption = self.encryption;
+        values.list(ENCRYPTION_RECIPIENTS_ENV, encryption.recipients);
+        values.add(ENCRYPTION_RECIPIENTS_FILE_ENV, encryption.recipients_file);
+        values.add(ENCRYPTION_IDENTITY_FILE_ENV, encryption.identity_file);
+
+        let (s3, sftp) = (self.storage.s3, self.storage.sftp);
+        values.add(STORAGE_DELETE_LOCAL_ENV, self.storage.delete_local);
+        values.add(S3_BUCKET_ENV, s3.bucket);
+        values.add(S3_PREFIX_ENV, s3.prefix);
+        values.add(S3_ENDPOINT_ENV, s3.endpoint);
+        values.add(S3_REGION_ENV, s3.region);
+        values.add(S3_ACCESS_KEY_ID_ENV, s3.access_key_id);
+        values.add(S3_SECRET_ACCESS_KEY_ENV, s3.secret_access_key);
+        values.add(S3_PATH_STYLE_ENV, s3.path_style);
+        values.add(S3_PART_SIZE_ENV, s3.part_size);
+        values.add(SFTP_HOST_ENV, sftp.host);
+        values.add(SFTP_PORT_ENV, sftp.port);
+        values.add(SFTP_USERNAME_ENV, sftp.username);
+        values.add(SFTP_PRIVATE_KEY_ENV, sftp.private_key);
+        values.add(SFTP_PRIVATE_KEY_PASSPHRASE_ENV, sftp.private_key_passphrase);
+        values.add(SFTP_HOST_KEY_ENV, sftp.host_key);
+        values.add(SFTP_REMOTE_DIR_ENV, sftp.remote_dir);
+
+        let retention = self.retention;
+        values.add(RETENTION_KEEP_LAST_ENV, retention.keep_last);
+        values.add(RETENTION_KEEP_DAILY_ENV, retention.keep_daily);
+        values.add(RETENTION_KEEP_WEEKLY_ENV, retention.keep_weekly);
+        values.add(RETENTION_KEEP_MONTHLY_ENV, retention.keep_monthly);
+        values.add(RETENTION_KEEP_YEARLY_ENV, retention.keep_yearly);
+        values.add(RETENTION_MAX_AGE_ENV, retention.max_age);
+        values.add(RETENTION_DRY_RUN_ENV, retention.dry_run);
+
+        let notify = self.notify;
+        values.add(NOTIFY_ON_ENV, notify.on);
+        values.add(NOTIFY_WEBHOOK_URL_ENV, notify.webhook.url);
+        values.add(NOTIFY_WEBHOOK_ON_ENV, notify.webhook.on);
+        values.add(NOTIFY_NTFY_URL_ENV, notify.ntfy.url);
+        values.add(NOTIFY_NTFY_TOKEN_ENV, notify.ntfy.token);
+        values.add(NOTIFY_NTFY_ON_ENV, notify.ntfy.on);
+        values.add(NOTIFY_GOTIFY_URL_ENV, notify.gotify.url);
+        values.add(NOTIFY_GOTIFY_TOKEN_ENV, notify.gotify.token);
+        values.add(NOTIFY_GOTIFY_ON_ENV, notify.gotify.on);
+        let smtp = notify.smtp;
+        values.add(NOTIFY_SMTP_HOST_ENV, smtp.host);
+        values.add(NOTIFY_SMTP_PORT_ENV, smtp.port);
+        values.add(NOTIFY_SMTP_SECURITY_ENV, smtp.security);
+        values.add(NOTIFY_SMTP_USERNAME_ENV, smtp.username);
+        values.add(NOTIFY_SMTP_PASSWORD_ENV, smtp.password);
+        values.add(NOTIFY_SMTP_FROM_ENV, smtp.from);
+        values.list(NOTIFY_SMTP_TO_ENV, smtp.to);
+        values.add(NOTIFY_SMTP_CA_FILE_ENV, smtp.ca_file);
+        values.add(NOTIFY_SMTP_ON_ENV, smtp.on);
+
+        values.add(METRICS_ADDRESS_ENV, self.metrics.address);
+        values.add(METRICS_TEXTFILE_DIR_ENV, self.metrics.textfile_dir);
+        values.0
+    }
+}
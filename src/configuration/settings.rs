@@ -0,0 +1,347 @@
+use crate::configuration::file::{file_key, ConfigFile};
+use crate::error::Error;
+use crate::error::Error::InvalidConfigFile;
+use crate::{CONFIG_ENV, LOG_TARGET};
+use log::debug;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use toml_edit::{ImDocument, TableLike};
+
+/// Source of the settings read by [`validate_config`](super::validate_config). Each setting is
+/// identified by its environment variable and is taken from the command line, then the environment,
+/// then the configuration file set with `SALVAGE_CONFIG`.
+#[derive(Default)]
+pub struct Settings {
+    /// Values from the command line keyed by environment variable with the option that set them
+    overrides: HashMap<String, (String, String)>,
+    /// Values from the configuration file keyed by environment variable
+    file: HashMap<String, FileValue>,
+    file_path: Option<PathBuf>,
+}
+
+/// A value from the configuration file with the key and line it was set on
+struct FileValue {
+    value: String,
+    key: String,
+    line: Option<usize>,
+}
+
+impl Settings {
+    /// Read the configuration file at `path`, or from `SALVAGE_CONFIG` when no path is provided
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Error> {
+        let mut settings = Self::default();
+        let path = path.or_else(|| {
+            env::var(CONFIG_ENV)
+                .ok()
+                .filter(|p| !p.trim().is_empty())
+                .map(PathBuf::from)
+        });
+        if let Some(path) = path {
+            settings.file = read_file(path.as_path())?;
+            debug!(target: LOG_TARGET, "Read {} settings from {}", settings.file.len(), path.to_string_lossy());
+            settings.file_path = Some(path);
+        }
+        Ok(settings)
+    }
+
+    pub fn file_path(&self) -> Option<&Path> {
+        self.file_path.as_deref()
+    }
+
+    /// Override a setting with the value of a command line `option`
+    pub fn set_override<S: ToString>(&mut self, key: &str, value: S, option: &str) {
+        self.overrides
+            .insert(key.to_string(), (value.to_string(), option.to_string()));
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        if let Some((value, _)) = self.overrides.get(key) {
+            return Some(value.clone());
+        }
+        match env::var(key) {
+            Ok(value) => Some(value),
+            Err(_) => self.file.get(key).map(|f| f.value.clone()),
+        }
+    }
+
+    /// Describe where a setting is read from so errors point at the value that needs fixing
+    pub fn describe(&self, key: &str) -> String {
+        if let Some((_, option)) = self.overrides.get(key) {
+            return option.clone();
+        }
+        if env::var(key).is_ok() {
+            return key.to_string();
+        }
+        let path = self
+            .file_path
+            .as_ref()
+            .map(|p| p.to_string_lossy())
+            .unwrap_or_default();
+        match self.file.get(key) {
+            Some(FileValue {
+                key,
+                line: Some(line),
+                ..
+            }) => format!("{} at line {} of {}", key, line, path),
+            Some(FileValue {
+                key, line: None, ..
+            }) => format!("{} in {}", key, path),
+            None if self.file_path.is_some() => {
+                let file_key = file_key(key).unwrap_or(key);
+                format!("{} or {} in {}", key, file_key, path)
+            }
+            None => key.to_string(),
+        }
+    }
+}
+
+/// Read a TOML file, or a YAML file when it has a `.yaml` or `.yml` extension, into settings keyed by
+/// environment variable. The parsers report the line of unknown keys and invalid values.
+fn read_file(path: &Path) -> Result<HashMap<String, FileValue>, Error> {
+    let invalid = |message: String| InvalidConfigFile(path.to_string_lossy().into(), message);
+    let text = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let yaml = path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("yaml") || e.eq_ignore_ascii_case("yml"));
+
+    let (file, lines) = match yaml {
+        true => {
+            let file: ConfigFile = match text.trim().is_empty() {
+                true => ConfigFile::default(),
+                false => serde_yaml::from_str(text.as_str()).map_err(|e| invalid(e.to_string()))?,
+            };
+            (file, yaml_lines(text.as_str()))
+        }
+        false => {
+            let file: ConfigFile =
+                toml::from_str(text.as_str()).map_err(|e| invalid(e.to_string()))?;
+            (file, toml_lines(text.as_str()))
+        }
+    };
+
+    let mut settings = HashMap::new();
+    for (env, value) in file.into_values() {
+        let key = file_key(env).unwrap_or(env).to_string();
+        let line = lines.get(key.as_str()).copied();
+        settings.insert(env.to_string(), FileValue { value, key, line });
+    }
+    Ok(settings)
+}
+
+fn join(prefix: &str, key: &str) -> String {
+    match prefix.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", prefix, key),
+    }
+}
+
+/// Find the line each key of a TOML file is set on from the position of the key in the document,
+/// which also finds keys in inline tables, dotted keys and keys of values across several lines
+fn toml_lines(text: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    if let Ok(document) = ImDocument::parse(text) {
+        table_lines(text, "", document.as_table(), &mut lines);
+    }
+    lines
+}
+
+fn table_lines(
+    text: &str,
+    prefix: &str,
+    table: &dyn TableLike,
+    lines: &mut HashMap<String, usize>,
+) {
+    for (name, _) in table.iter() {
+        let Some((key, item)) = table.get_key_value(name) else {
+            continue;
+        };
+        let name = join(prefix, name);
+        if let Some(span) = key.span().or_else(|| item.span()) {
+            let line = text.get(..span.start).unwrap_or_default().matches('\n');
+            lines.entry(name.clone()).or_insert(line.count() + 1);
+        }
+        if let Some(table) = item.as_table_like() {
+            table_lines(text, name.as_str(), table, lines);
+        }
+    }
+}
+
+/// Find the line each key of a YAML file is set on from the indentation of the keys. Only keys of
+/// block mappings are found, so errors about keys in flow mappings name the key without a line.
+fn yaml_lines(text: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut parents: Vec<(usize, String)> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let content = line.trim_start();
+        if content.is_empty() || content.starts_with(['#', '-']) || content.starts_with("---") {
+            continue;
+        }
+        let indent = line.len() - content.len();
+        let Some((key, _)) = content
+            .split_once(": ")
+            .or_else(|| content.strip_suffix(':').map(|key| (key, "")))
+        else {
+            continue;
+        };
+        let key = key.trim().trim_matches(['"', '\'']);
+        while parents.last().is_some_and(|(i, _)| *i >= indent) {
+            parents.pop();
+        }
+        let name = join(
+            parents.last().map(|(_, p)| p.as_str()).unwrap_or_default(),
+            key,
+        );
+        lines.entry(name.clone()).or_insert(number + 1);
+        parents.push((indent, name));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        COMPRESSION_LEVEL_ENV, EXCLUDE_ENV, S3_BUCKET_ENV, S3_PATH_STYLE_ENV, STRATEGY_ENV,
+        VOLUME_EXCLUDE_ENV,
+    };
+
+    fn load(name: &str, text: &str) -> Result<Settings, Error> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name);
+        std::fs::write(path.as_path(), text).unwrap();
+        Settings::load(Some(path))
+    }
+
+    fn error(name: &str, text: &str) -> String {
+        match load(name, text) {
+            Err(e) => e.to_string(),
+            Ok(_) => panic!("{} was accepted", name),
+        }
+    }
+
+    #[test]
+    fn reads_nested_toml_sections() {
+        let text = r#"# Settings
+schedule = "0 3 * * *"
+archive = { strategy = "incremental", compression_level = 3 }
+
+[exclude]
+patterns = ["cache/", "*.log"]
+volumes = { db = ["*.tmp", "wal/"] }
+
+[storage.s3]
+bucket = "backups"
+path_style = true
+"#;
+        let settings = load("salvage.toml", text).unwrap();
+        assert_eq!(settings.get(S3_BUCKET_ENV).unwrap(), "backups");
+        assert_eq!(settings.get(S3_PATH_STYLE_ENV).unwrap(), "true");
+        assert_eq!(settings.get(STRATEGY_ENV).unwrap(), "incremental");
+        assert_eq!(settings.get(COMPRESSION_LEVEL_ENV).unwrap(), "3");
+        assert_eq!(settings.get(EXCLUDE_ENV).unwrap(), "cache/,*.log");
+        assert_eq!(
+            settings.get(VOLUME_EXCLUDE_ENV).unwrap(),
+            "db:*.tmp,db:wal/"
+        );
+        assert!(settings
+            .describe(S3_BUCKET_ENV)
+            .starts_with("storage.s3.bucket at line 10 of "));
+        assert!(settings
+            .describe(STRATEGY_ENV)
+            .starts_with("archive.strategy at line 3 of "));
+    }
+
+    #[test]
+    fn reads_nested_yaml_sections() {
+        let text = "
+archive:
+  strategy: incremental
+exclude:
+  volumes:
+    db:
+      - '*.tmp'
+storage:
+  s3:
+    bucket: backups
+";
+        let settings = load("salvage.yaml", text).unwrap();
+        assert_eq!(settings.get(S3_BUCKET_ENV).unwrap(), "backups");
+        assert_eq!(settings.get(VOLUME_EXCLUDE_ENV).unwrap(), "db:*.tmp");
+        assert!(settings
+            .describe(S3_BUCKET_ENV)
+            .starts_with("storage.s3.bucket at line 10 of "));
+        assert!(load("empty.yml", "").is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_keys_with_their_line() {
+        let toml = error(
+            "salvage.toml",
+            "[storage.s3]\nbucket = \"a\"\nbuket = \"b\"\n",
+        );
+        assert!(toml.contains("unknown field `buket`"), "{}", toml);
+        assert!(toml.contains("line 3"), "{}", toml);
+        let flat = error("salvage.toml", "storage_s3_bucket = \"a\"\n");
+        assert!(
+            flat.contains("unknown field `storage_s3_bucket`"),
+            "{}",
+            flat
+        );
+
+        let yaml = error("salvage.yml", "storage:\n  s3:\n    buket: a\n");
+        assert!(yaml.contains("unknown field `buket`"), "{}", yaml);
+        assert!(yaml.contains("line 3"), "{}", yaml);
+    }
+
+    #[test]
+    fn rejects_invalid_values_with_their_line() {
+        let toml = error("salvage.toml", "[archive]\n\nstrategy = \"weekly\"\n");
+        assert!(toml.contains("ArchiveStrategy"), "{}", toml);
+        assert!(toml.contains("line 3"), "{}", toml);
+        let toml = error("salvage.toml", "[retention]\nkeep_last = \"many\"\n");
+        assert!(toml.contains("line 2"), "{}", toml);
+
+        let yaml = error("salvage.yaml", "notify:\n  smtp:\n    security: plain\n");
+        assert!(yaml.contains("SmtpSecurity"), "{}", yaml);
+        assert!(yaml.contains("line 3"), "{}", yaml);
+    }
+
+    #[test]
+    fn finds_the_line_of_each_toml_key() {
+        let text = r#"# Settings
+schedule = """
+0 3 * * *"""
+"archive".compression = "zstd"
+retention = { keep_daily = 7, keep_weekly = 4 }
+
+[storage.s3]
+bucket = "backups"
+"#;
+        let lines = toml_lines(text);
+        assert_eq!(lines.get("schedule"), Some(&2));
+        assert_eq!(lines.get("archive.compression"), Some(&4));
+        assert_eq!(lines.get("retention.keep_daily"), Some(&5));
+        assert_eq!(lines.get("retention.keep_weekly"), Some(&5));
+        assert_eq!(lines.get("storage.s3.bucket"), Some(&8));
+    }
+
+    #[test]
+    fn finds_the_line_of_each_yaml_key() {
+        let text = "# Settings
+schedule: '0 3 * * *'
+exclude:
+  patterns:
+    - cache/
+  volumes:
+    db: ['*.tmp']
+retention:
+  keep_daily: 7
+";
+        let lines = yaml_lines(text);
+        assert_eq!(lines.get("schedule"), Some(&2));
+        assert_eq!(lines.get("exclude.patterns"), Some(&4));
+        assert_eq!(lines.get("exclude.volumes.db"), Some(&7));
+        assert_eq!(lines.get("retention.keep_daily"), Some(&9));
+    }
+}
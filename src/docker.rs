@@ -1,93 +1,641 @@
-use crate::configuration::Configuration;
+use crate::configuration::{Configuration, QuiesceMode};
 use crate::error::Error;
-use crate::error::Error::NoSalvageContainer;
+use crate::error::Error::{
+    ContainersNotStarted, ContainersNotUnpaused, ExecFailed, HookFailed, NoSalvageContainer,
+    RestartFailed,
+};
+use crate::signals::SignalListener;
 use crate::{LOG_TARGET, SALVAGE_LABEL};
 use bollard::container::{
     ListContainersOptions, RemoveContainerOptions, StartContainerOptions, StopContainerOptions,
 };
+use bollard::exec::{CreateExecOptions, StartExecResults};
 use bollard::models::ContainerSummary;
 use bollard::Docker;
-use log::{debug, info, trace, warn};
+use futures_util::StreamExt;
+use log::{debug, error, info, trace, warn};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
-use std::time::Instant;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+use tokio::sync::oneshot;
+
+/// Label to set to `false` on a container that should keep running while its volumes are archived,
+/// the same as setting the quiesce label to `none`
+const STOP_LABEL: &str = "ca.wheelans.salvage.stop";
+/// Label with the quiesce mode of the container, overriding the configured mode
+const QUIESCE_LABEL: &str = "ca.wheelans.salvage.quiesce";
+/// Label to set to `true` on a container that Salvage should ignore entirely
+const EXCLUDE_LABEL: &str = "ca.wheelans.salvage.exclude";
+/// Label with the number of seconds to wait for a container to stop before it is killed
+const STOP_TIMEOUT_LABEL: &str = "ca.wheelans.salvage.stop-timeout";
+/// Label with a command to run in the container before its volumes are archived
+const PRE_EXEC_LABEL: &str = "ca.wheelans.salvage.pre-exec";
+/// Label with a command to run in the container after its volumes are archived
+const POST_EXEC_LABEL: &str = "ca.wheelans.salvage.post-exec";
+/// Label with the number of seconds a pre-exec or post-exec command may run
+const EXEC_TIMEOUT_LABEL: &str = "ca.wheelans.salvage.exec-timeout";
+const DEFAULT_EXEC_TIMEOUT_SECS: u64 = 300;
+
+/// Salvage settings read from the labels of an application container
+struct ContainerLabels {
+    /// Quiesce mode set by label, which takes precedence over the configured mode
+    quiesce: Option<QuiesceMode>,
+    exclude: bool,
+    stop_timeout: Option<i64>,
+    pre_exec: Option<String>,
+    post_exec: Option<String>,
+    exec_timeout: u64,
+}
+
+impl ContainerLabels {
+    fn parse(container: &ContainerSummary) -> Self {
+        let id = container.id.as_deref().unwrap_or_default();
+        let labels = container.labels.clone().unwrap_or_default();
+        let bool_label = |key: &str, default: bool| match labels.get(key).map(|v| v.trim()) {
+            None => default,
+            Some(v) if v.eq_ignore_ascii_case("true") => true,
+            Some(v) if v.eq_ignore_ascii_case("false") => false,
+            Some(v) => {
+                warn!(target: LOG_TARGET, "Ignoring invalid value {} of label {} on container {}", v, key, id);
+                default
+            }
+        };
+        let stop_timeout = labels
+            .get(STOP_TIMEOUT_LABEL)
+            .and_then(|v| match v.trim().parse::<i64>() {
+                Ok(timeout) if timeout >= 0 => Some(timeout),
+                _ => {
+                    warn!(target: LOG_TARGET, "Ignoring invalid value {} of label {} on container {}", v, STOP_TIMEOUT_LABEL, id);
+                    None
+                }
+            });
+
+        let exec_timeout = match labels
+            .get(EXEC_TIMEOUT_LABEL)
+            .map(|v| v.trim().parse::<u64>())
+        {
+            None => DEFAULT_EXEC_TIMEOUT_SECS,
+            Some(Ok(timeout)) if timeout > 0 => timeout,
+            Some(_) => {
+                warn!(target: LOG_TARGET, "Ignoring invalid value of label {} on container {}", EXEC_TIMEOUT_LABEL, id);
+                DEFAULT_EXEC_TIMEOUT_SECS
+            }
+        };
+        let quiesce = match labels.get(QUIESCE_LABEL) {
+            None => None,
+            Some(v) => match v.parse::<QuiesceMode>() {
+                Ok(mode) => Some(mode),
+                Err(_) => {
+                    warn!(target: LOG_TARGET, "Ignoring invalid value {} of label {} on container {}", v, QUIESCE_LABEL, id);
+                    None
+                }
+            },
+        };
+        let command = |key: &str| {
+            labels
+                .get(key)
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+        };
+
+        Self {
+            quiesce: match bool_label(STOP_LABEL, true) {
+                true => quiesce,
+                false => quiesce.or(Some(QuiesceMode::None)),
+            },
+            exclude: bool_label(EXCLUDE_LABEL, false),
+            stop_timeout,
+            pre_exec: command(PRE_EXEC_LABEL),
+            post_exec: command(POST_EXEC_LABEL),
+            exec_timeout,
+        }
+    }
+
+    /// Quiesce mode of the container, which is `default` unless it is set by label
+    fn quiesce_mode(&self, default: QuiesceMode) -> QuiesceMode {
+        self.quiesce.unwrap_or(default)
+    }
+}
+
+/// Containers handled by the pre-processing that need to be undone by the post-processing
+#[derive(Default)]
+pub struct ProcessedContainers {
+    /// IDs of the containers that were stopped
+    pub stopped: Vec<String>,
+    /// IDs of the containers that were paused
+    pub paused: Vec<String>,
+    /// Containers with a post-exec hook to run once archiving is complete, with the paths in the
+    /// Salvage container of the volumes they share
+    pub post_exec: Vec<(ContainerSummary, Vec<PathBuf>)>,
+    /// Paths in the Salvage container of the volumes whose pre-exec hook failed
+    pub failed_paths: Vec<PathBuf>,
+    /// Paths in the Salvage container of the volumes shared with each stopped or paused container
+    pub quiesced_paths: Vec<(String, Vec<PathBuf>)>,
+}
+
+/// A container that the pre-archive processing would act on
+pub struct PlannedContainer {
+    pub name: String,
+    /// Whether the container would be stopped, paused or left running
+    pub quiesce: QuiesceMode,
+    /// Whether the container is running, as containers that are not are left as they are
+    pub running: bool,
+    pub pre_exec: Option<String>,
+    pub post_exec: Option<String>,
+    /// Paths in the Salvage container of the volumes shared with the container
+    pub paths: Vec<PathBuf>,
+}
 
+/// Run the post-archive processing on docker containers to unpause the containers that were paused,
+/// start the containers that were stopped and run any post-exec hooks. The IDs of the containers
+/// that were started are added to `started` and those that were unpaused to `unpaused`. A failed
+/// post-exec hook only concerns the volumes its container shares, so their paths are added to
+/// `hook_failed` rather than failing the processing.
 pub async fn post_archive_container_processing(
-    container_ids: Option<Vec<String>>,
+    processed: Option<ProcessedContainers>,
+    started: &mut Vec<String>,
+    unpaused: &mut Vec<String>,
+    hook_failed: &mut Vec<PathBuf>,
 ) -> Result<(), Error> {
     let start_time = Instant::now();
     let docker = connect_docker()?;
-    match container_ids {
-        None => debug!(target: LOG_TARGET, "No containers to restart"),
-        Some(ids) => start_containers(&docker, ids.as_slice()).await?,
+    let processed = match processed {
+        None => {
+            debug!(target: LOG_TARGET, "No containers to restart");
+            return Ok(());
+        }
+        Some(processed) => processed,
+    };
+    let mut failures = Vec::new();
+    if let Err(error) = unpause_containers(&docker, processed.paused.as_slice(), unpaused).await {
+        error!(target: LOG_TARGET, "{}", error);
+        failures.push(error.to_string());
+    }
+    if let Err(error) = start_containers(&docker, processed.stopped.as_slice(), started).await {
+        error!(target: LOG_TARGET, "{}", error);
+        failures.push(error.to_string());
+    }
+
+    for (container, paths) in processed.post_exec.iter() {
+        if let Err(error) = run_hook(&docker, container, POST_EXEC_LABEL).await {
+            error!(target: LOG_TARGET, "{}", error);
+            hook_failed.extend(paths.iter().cloned());
+        }
     }
 
     debug!(target: LOG_TARGET, "Post-archive container processing complete after {} milliseconds", start_time.elapsed().as_millis());
-    Ok(())
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(HookFailed(failures.join("; "))),
+    }
 }
 
-/// Run the pre-archive processing on docker containers to identify the Salvage container and its mounts
-/// and stop any containers with those mounts. Return container IDs of all containers that were stopped.
-pub async fn pre_archive_container_processing(
+/// Guard that runs the post-archive processing when it is dropped, so the containers stopped or
+/// paused by the pre-processing are started or unpaused again even when archiving returns early or
+/// panics. The guard listens for SIGINT and SIGTERM before any container is changed, and records
+/// each container as it is handled, so a signal received at any point also resumes the containers
+/// handled so far before the process exits.
+pub struct RestartGuard<'a> {
+    runtime: &'a Runtime,
+    processed: Arc<Mutex<Option<ProcessedContainers>>>,
+    watcher: Option<(oneshot::Sender<()>, JoinHandle<()>)>,
+    stopped: Vec<String>,
+    paused: Vec<String>,
+    quiesced_paths: Vec<(String, Vec<PathBuf>)>,
+    restarted: Vec<String>,
+    unpaused: Vec<String>,
+    hook_failed_paths: Vec<PathBuf>,
+}
+
+impl<'a> RestartGuard<'a> {
+    /// Create a guard, which only listens for signals and handles containers when container
+    /// management is `enabled`
+    pub fn new(runtime: &'a Runtime, enabled: bool) -> Result<Self, Error> {
+        let processed = Arc::new(Mutex::new(enabled.then(ProcessedContainers::default)));
+        let watcher = match enabled {
+            true => Some(watch_signals(processed.clone())?),
+            false => None,
+        };
+        Ok(Self {
+            runtime,
+            processed,
+            watcher,
+            stopped: Vec::new(),
+            paused: Vec::new(),
+            quiesced_paths: Vec::new(),
+            restarted: Vec::new(),
+            unpaused: Vec::new(),
+            hook_failed_paths: Vec::new(),
+        })
+    }
+
+    /// Run the pre-archive processing when container management is enabled
+    pub fn pre_archive(&self, config: &Configuration) -> Result<(), Error> {
+        match lock(&self.processed).is_some() {
+            true => self
+                .runtime
+                .block_on(pre_archive_container_processing(config, &self.processed)),
+            false => Ok(()),
+        }
+    }
+
+    /// Run the pre-restore processing for the restore target when container management is enabled
+    pub fn pre_restore(&self, target: &Path) -> Result<(), Error> {
+        match lock(&self.processed).is_some() {
+            true => self
+                .runtime
+                .block_on(pre_restore_container_processing(target, &self.processed)),
+            false => Ok(()),
+        }
+    }
+
+    /// Paths in the Salvage container of the volumes whose pre-exec hook failed
+    pub fn failed_paths(&self) -> Vec<PathBuf> {
+        lock(&self.processed)
+            .as_ref()
+            .map(|p| p.failed_paths.clone())
+            .unwrap_or_default()
+    }
+
+    /// Paths in the Salvage container of the volumes whose post-exec hook failed, once
+    /// [`RestartGuard::finish`] has run
+    pub fn hook_failed_paths(&self) -> &[PathBuf] {
+        self.hook_failed_paths.as_slice()
+    }
+
+    /// IDs of the containers that were stopped by the pre-processing, once
+    /// [`RestartGuard::finish`] has run
+    pub fn stopped(&self) -> &[String] {
+        self.stopped.as_slice()
+    }
+
+    /// IDs of the containers that were paused by the pre-processing, once
+    /// [`RestartGuard::finish`] has run
+    pub fn paused(&self) -> &[String] {
+        self.paused.as_slice()
+    }
+
+    /// IDs of the stopped or paused containers that share the volume at `path` in the Salvage
+    /// container, once [`RestartGuard::finish`] has run
+    pub fn quiesced_sharing(&self, path: &Path) -> Vec<String> {
+        self.quiesced_paths
+            .iter()
+            .filter(|(_, paths)| {
+                paths
+                    .iter()
+                    .any(|p| p.starts_with(path) || path.starts_with(p))
+            })
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// IDs of the containers that were started again by [`RestartGuard::finish`]
+    pub fn restarted(&self) -> &[String] {
+        self.restarted.as_slice()
+    }
+
+    /// IDs of the containers that were unpaused by [`RestartGuard::finish`]
+    pub fn unpaused(&self) -> &[String] {
+        self.unpaused.as_slice()
+    }
+
+    /// Run the post-archive processing and combine any failure with the result of the work done
+    /// while the containers were stopped or paused, keeping the original error.
+    pub fn finish<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        self.stop_watcher();
+        let processed = lock(&self.processed).take();
+        if let Some(processed) = processed.as_ref() {
+            self.stopped = processed.stopped.clone();
+            self.paused = processed.paused.clone();
+            self.quiesced_paths = processed.quiesced_paths.clone();
+        }
+        let restart = match processed {
+            None => Ok(()),
+            processed => self.runtime.block_on(post_archive_container_processing(
+                processed,
+                &mut self.restarted,
+                &mut self.unpaused,
+                &mut self.hook_failed_paths,
+            )),
+        };
+        match (result, restart) {
+            (result, Ok(())) => result,
+            (Ok(_), Err(restart)) => Err(restart),
+            (Err(error), Err(restart)) => Err(RestartFailed(Box::new(error), Box::new(restart))),
+        }
+    }
+
+    fn stop_watcher(&mut self) {
+        if let Some((cancel, handle)) = self.watcher.take() {
+            let _ = cancel.send(());
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for RestartGuard<'_> {
+    fn drop(&mut self) {
+        self.stop_watcher();
+        let processed = lock(&self.processed).take();
+        if processed.is_some() {
+            warn!(target: LOG_TARGET, "Resuming containers after the process was interrupted");
+            if let Err(error) = self.runtime.block_on(post_archive_container_processing(
+                processed,
+                &mut Vec::new(),
+                &mut Vec::new(),
+                &mut Vec::new(),
+            )) {
+                error!(target: LOG_TARGET, "{}", error);
+            }
+        }
+    }
+}
+
+/// Listen for SIGINT and SIGTERM on a separate thread, as archiving blocks the main thread. When
+/// either is received the post-archive processing is run before the process exits.
+fn watch_signals(
+    processed: Arc<Mutex<Option<ProcessedContainers>>>,
+) -> Result<(oneshot::Sender<()>, JoinHandle<()>), Error> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    // Register the handlers before returning so no signal is missed
+    let mut signals = runtime.block_on(async { SignalListener::new() })?;
+    let (cancel, cancelled) = oneshot::channel::<()>();
+
+    let handle = std::thread::Builder::new()
+        .name("salvage-signals".into())
+        .spawn(move || {
+            runtime.block_on(async {
+                let (name, code) = tokio::select! {
+                    _ = cancelled => return,
+                    signal = signals.recv() => signal,
+                };
+                warn!(target: LOG_TARGET, "Received {}. Resuming stopped and paused containers before exiting", name);
+                let processed = lock(&processed).take();
+                if let Err(error) = post_archive_container_processing(processed, &mut Vec::new(), &mut Vec::new(), &mut Vec::new()).await {
+                    error!(target: LOG_TARGET, "{}", error);
+                }
+                std::process::exit(code);
+            })
+        })?;
+    Ok((cancel, handle))
+}
+
+fn lock(
+    processed: &Mutex<Option<ProcessedContainers>>,
+) -> MutexGuard<'_, Option<ProcessedContainers>> {
+    processed.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Record a change to the containers to undo, unless a signal has already taken them to undo
+fn record<F: FnOnce(&mut ProcessedContainers)>(
+    processed: &Mutex<Option<ProcessedContainers>>,
+    change: F,
+) {
+    if let Some(processed) = lock(processed).as_mut() {
+        change(processed);
+    }
+}
+
+/// Run the pre-archive processing on docker containers to identify the Salvage container and its mounts,
+/// run the pre-exec hooks of the containers with those mounts and stop or pause them according to
+/// their quiesce mode. Each container is recorded in `processed` as it is handled.
+async fn pre_archive_container_processing(
     config: &Configuration,
-) -> Result<Vec<String>, Error> {
+    processed: &Mutex<Option<ProcessedContainers>>,
+) -> Result<(), Error> {
     let start_time = Instant::now();
     let docker = connect_docker()?;
-    let salvage = find_salvage_container(&docker).await?;
-    trace!(target: LOG_TARGET ,"Salvage container: {:?}", salvage);
+    let mut to_quiesce = Vec::new();
 
-    let archive_volume_sources = get_archive_volumes(&salvage, config.data_dir.to_string_lossy());
-    debug!(target: LOG_TARGET ,"Salvage archive volume sources: {:?}", archive_volume_sources);
+    for (container, destinations) in
+        find_containers_sharing(&docker, config.data_dir.as_path(), true).await?
+    {
+        if !is_running(&container) {
+            debug!(target: LOG_TARGET, "Container {} is not running, so it is left as it is", container_name(&container));
+            continue;
+        }
+        if let Err(error) = run_hook(&docker, &container, PRE_EXEC_LABEL).await {
+            error!(target: LOG_TARGET, "{}", error);
+            record(processed, |p| p.failed_paths.extend(destinations));
+            continue;
+        }
+        if ContainerLabels::parse(&container).post_exec.is_some() {
+            record(processed, |p| {
+                p.post_exec.push((container.clone(), destinations.clone()))
+            });
+        }
+        to_quiesce.push((container, destinations));
+    }
 
-    let containers = find_containers_with_mounts(
+    quiesce_containers(
         &docker,
-        archive_volume_sources.as_slice(),
-        salvage.id.unwrap_or_default(),
+        to_quiesce,
+        |labels| labels.quiesce_mode(config.quiesce_mode),
+        processed,
     )
     .await?;
-    trace!(target: LOG_TARGET ,"Containers to be shutdown before archive : {:?}", containers);
+    debug!(target: LOG_TARGET, "Pre-archive container processing complete after {} milliseconds", start_time.elapsed().as_millis());
+    Ok(())
+}
 
-    let containers = containers
-        .into_iter()
-        .filter_map(|c| c.id)
-        .collect::<Vec<String>>();
+/// Find the containers that the pre-archive processing would run hooks in and stop or pause, without
+/// running any hook or stopping, starting or removing any container.
+pub async fn plan_container_processing(
+    config: &Configuration,
+) -> Result<Vec<PlannedContainer>, Error> {
+    let docker = connect_docker()?;
+    Ok(
+        find_containers_sharing(&docker, config.data_dir.as_path(), false)
+            .await?
+            .into_iter()
+            .map(|(container, paths)| {
+                let labels = ContainerLabels::parse(&container);
+                PlannedContainer {
+                    name: container_name(&container),
+                    quiesce: labels.quiesce_mode(config.quiesce_mode),
+                    running: is_running(&container),
+                    pre_exec: labels.pre_exec,
+                    post_exec: labels.post_exec,
+                    paths,
+                }
+            })
+            .collect(),
+    )
+}
+
+/// Run the pre-restore processing on docker containers to stop any containers that share the mounts
+/// of the restore target, recording each in `processed` as it is stopped. Containers set to pause
+/// are stopped too, as the state they keep in memory would not match the restored files.
+async fn pre_restore_container_processing(
+    target: &Path,
+    processed: &Mutex<Option<ProcessedContainers>>,
+) -> Result<(), Error> {
+    let start_time = Instant::now();
+    let docker = connect_docker()?;
+    let containers = find_containers_sharing(&docker, target, true).await?;
+    quiesce_containers(
+        &docker,
+        containers,
+        |labels| match labels.quiesce_mode(QuiesceMode::Stop) {
+            QuiesceMode::None => QuiesceMode::None,
+            _ => QuiesceMode::Stop,
+        },
+        processed,
+    )
+    .await?;
+    debug!(target: LOG_TARGET, "Pre-restore container processing complete after {} milliseconds", start_time.elapsed().as_millis());
+    Ok(())
+}
+
+/// Identify the Salvage container mounts that overlap with the provided path and find the other
+/// containers with those mounts. Return each container with the destinations in the Salvage
+/// container of the mounts it shares. Older Salvage containers are removed when
+/// `remove_duplicates` is set.
+async fn find_containers_sharing<P: AsRef<Path>>(
+    docker: &Docker,
+    path: P,
+    remove_duplicates: bool,
+) -> Result<Vec<(ContainerSummary, Vec<PathBuf>)>, Error> {
+    let salvage = find_salvage_container(docker, remove_duplicates).await?;
+    trace!(target: LOG_TARGET ,"Salvage container: {:?}", salvage);
 
-    stop_containers(&docker, containers.as_slice()).await?;
+    let archive_volumes = get_archive_volumes(&salvage, path);
+    debug!(target: LOG_TARGET ,"Salvage archive volumes: {:?}", archive_volumes);
+    let sources: Vec<String> = archive_volumes.keys().cloned().collect();
 
-    debug!(target: LOG_TARGET, "Pre-archive container processing complete after {} milliseconds", start_time.elapsed().as_millis());
-    Ok(containers)
+    let containers =
+        find_containers_with_mounts(docker, sources.as_slice(), salvage.id.unwrap_or_default())
+            .await?;
+    trace!(target: LOG_TARGET ,"Containers with archive volumes: {:?}", containers);
+
+    Ok(containers
+        .into_iter()
+        .map(|container| {
+            let destinations = container
+                .mounts
+                .iter()
+                .flatten()
+                .filter_map(|m| m.source.as_ref())
+                .filter_map(|s| archive_volumes.get(s).cloned())
+                .collect();
+            (container, destinations)
+        })
+        .collect())
 }
 
-fn get_archive_volumes<S: AsRef<str>>(
+/// Get the sources of the container mounts whose destination contains or is contained by the provided
+/// path, mapped to their destination
+fn get_archive_volumes<P: AsRef<Path>>(
     container: &ContainerSummary,
-    archive_path: S,
-) -> Vec<String> {
-    trace!(target: LOG_TARGET, "Salvage archive path: {}", archive_path.as_ref());
+    path: P,
+) -> HashMap<String, PathBuf> {
+    let path = path.as_ref();
+    trace!(target: LOG_TARGET, "Salvage archive path: {}", path.to_string_lossy());
     trace!(target: LOG_TARGET, "Salvage mounts: {:?}", container.mounts.as_ref().unwrap());
     container
         .mounts
         .clone()
         .unwrap_or_default()
         .into_iter()
-        .filter(|m| {
-            m.destination
-                .clone()
-                .unwrap_or_default()
-                .starts_with(archive_path.as_ref())
+        .filter_map(|m| {
+            let destination = PathBuf::from(m.destination?);
+            match destination.starts_with(path) || path.starts_with(destination.as_path()) {
+                true => Some((m.source?, destination)),
+                false => None,
+            }
         })
-        .filter_map(|m| m.source)
-        .collect::<Vec<_>>()
+        .collect()
+}
+
+/// Run the command in the hook label of the container with `sh -c`, logging its output. The hook
+/// fails when the command exits with a non-zero code or does not finish within the exec timeout.
+async fn run_hook(docker: &Docker, container: &ContainerSummary, label: &str) -> Result<(), Error> {
+    let labels = ContainerLabels::parse(container);
+    let command = match label {
+        PRE_EXEC_LABEL => labels.pre_exec,
+        _ => labels.post_exec,
+    };
+    let (Some(command), Some(id)) = (command, container.id.as_deref()) else {
+        return Ok(());
+    };
+    let name = container_name(container);
+    let failed = |reason: String| ExecFailed(label.to_string(), name.clone(), reason);
+    info!(target: LOG_TARGET, "Running {} hook in container {}: {}", label, name, command);
+
+    let exec_options = CreateExecOptions {
+        cmd: Some(vec!["sh", "-c", command.as_str()]),
+        attach_stdout: Some(true),
+        attach_stderr: Some(true),
+        ..Default::default()
+    };
+    let exec = docker.create_exec(id, exec_options).await?;
+    let output = async {
+        if let StartExecResults::Attached { mut output, .. } =
+            docker.start_exec(&exec.id, None).await?
+        {
+            while let Some(line) = output.next().await {
+                for line in line?.to_string().lines().filter(|l| !l.is_empty()) {
+                    info!(target: LOG_TARGET, "[{}] {}", name, line);
+                }
+            }
+        }
+        Ok::<(), Error>(())
+    };
+    tokio::time::timeout(Duration::from_secs(labels.exec_timeout), output)
+        .await
+        .map_err(|_| failed(format!("timed out after {} seconds", labels.exec_timeout)))??;
+
+    match docker.inspect_exec(&exec.id).await?.exit_code {
+        Some(0) => {
+            debug!(target: LOG_TARGET, "{} hook in container {} succeeded", label, name);
+            Ok(())
+        }
+        Some(code) => Err(failed(format!("exited with code {}", code))),
+        None => Err(failed("no exit code was returned".to_string())),
+    }
+}
+
+/// Whether the container is running, assuming it is when Docker does not report its state
+fn is_running(container: &ContainerSummary) -> bool {
+    container.state.as_deref().map_or(true, |s| s.eq("running"))
+}
+
+fn container_name(container: &ContainerSummary) -> String {
+    container
+        .names
+        .as_ref()
+        .and_then(|n| n.first())
+        .map(|n| n.trim_start_matches('/').to_string())
+        .or_else(|| container.id.clone())
+        .unwrap_or_default()
 }
 
-/// Find running the salvage containers by label and remove all but the most recent.
-async fn find_salvage_container(docker: &Docker) -> Result<ContainerSummary, Error> {
+/// Find running the salvage containers by label and, when `remove_duplicates` is set, remove all but
+/// the most recent.
+async fn find_salvage_container(
+    docker: &Docker,
+    remove_duplicates: bool,
+) -> Result<ContainerSummary, Error> {
     let list_options = Some(ListContainersOptions {
         filters: HashMap::from([("label", vec![SALVAGE_LABEL])]),
         ..Default::default()
     });
     let containers = docker.list_containers(list_options).await?;
 
-    if containers.len() > 1 {
+    if containers.len() > 1 && !remove_duplicates {
+        info!(target: LOG_TARGET, "Multiple running Salvage containers found. Using the most recent.");
+        Ok(containers
+            .iter()
+            .max_by_key(|c| c.created.unwrap_or_default())
+            .unwrap()
+            .clone())
+    } else if containers.len() > 1 {
         info!(target: LOG_TARGET ,"Multiple running Salvage containers found. Removing all but the most recent will be removed");
         let remove_options = Some(RemoveContainerOptions {
             force: true,
@@ -120,14 +668,15 @@ async fn find_salvage_container(docker: &Docker) -> Result<ContainerSummary, Err
         }
         Ok(salvage_container.clone())
     } else {
-        match containers.get(0) {
+        match containers.first() {
             None => Err(NoSalvageContainer),
             Some(container) => Ok(container.clone()),
         }
     }
 }
 
-/// Find containers with the provided mounts and filter out the Salvage container
+/// Find containers with the provided mounts and filter out the Salvage container and containers
+/// excluded by label
 async fn find_containers_with_mounts<S: AsRef<str>>(
     docker: &Docker,
     sources: &[String],
@@ -159,31 +708,120 @@ async fn find_containers_with_mounts<S: AsRef<str>>(
                     .any(|m| m.source.as_ref().is_some_and(|s| sources.contains(s)))
             })
         })
+        .filter(|c| {
+            let exclude = ContainerLabels::parse(c).exclude;
+            if exclude {
+                debug!(target: LOG_TARGET, "Container {} is excluded by label", c.id.as_deref().unwrap_or_default());
+            }
+            !exclude
+        })
         .collect();
 
     Ok(containers)
 }
 
-async fn stop_containers<S: AsRef<str>>(docker: &Docker, containers: &[S]) -> Result<(), Error> {
-    let stop_options = Some(StopContainerOptions::default());
-    for container in containers {
-        debug!(target: LOG_TARGET ,"Stopping container: {}", container.as_ref());
-        docker
-            .stop_container(container.as_ref(), stop_options)
-            .await?;
+/// Stop or pause each container according to the quiesce mode `mode` returns for its labels,
+/// using each container's stop timeout when it is stopped. Each container is recorded in
+/// `processed` with the paths it shares before it is stopped or paused, so a signal received while
+/// waiting on Docker still resumes it, and removed again when Docker fails to stop or pause it.
+async fn quiesce_containers<F>(
+    docker: &Docker,
+    containers: Vec<(ContainerSummary, Vec<PathBuf>)>,
+    mode: F,
+    processed: &Mutex<Option<ProcessedContainers>>,
+) -> Result<(), Error>
+where
+    F: Fn(&ContainerLabels) -> QuiesceMode,
+{
+    for (container, paths) in containers {
+        let Some(id) = container.id.clone() else {
+            continue;
+        };
+        // A paused container cannot be stopped and is already quiesced, and one that has exited
+        // or is restarting is not writing to the volume, so neither is recorded to be resumed
+        if !is_running(&container) {
+            debug!(target: LOG_TARGET, "Container {} is {}, so it is left as it is", id, container.state.as_deref().unwrap_or_default());
+            continue;
+        }
+        let labels = ContainerLabels::parse(&container);
+        let result = match mode(&labels) {
+            QuiesceMode::Stop => {
+                let stop_options = labels.stop_timeout.map(|t| StopContainerOptions { t });
+                debug!(target: LOG_TARGET ,"Stopping container: {}", id);
+                record(processed, |p| {
+                    p.stopped.push(id.clone());
+                    p.quiesced_paths.push((id.clone(), paths));
+                });
+                docker.stop_container(id.as_str(), stop_options).await
+            }
+            QuiesceMode::Pause => {
+                debug!(target: LOG_TARGET ,"Pausing container: {}", id);
+                record(processed, |p| {
+                    p.paused.push(id.clone());
+                    p.quiesced_paths.push((id.clone(), paths));
+                });
+                docker.pause_container(id.as_str()).await
+            }
+            QuiesceMode::None => {
+                debug!(target: LOG_TARGET ,"Container {} is left running because its quiesce mode is none", id);
+                Ok(())
+            }
+        };
+        if let Err(error) = result {
+            record(processed, |p| {
+                p.stopped.retain(|s| s.ne(&id));
+                p.paused.retain(|s| s.ne(&id));
+                p.quiesced_paths.retain(|(s, _)| s.ne(&id));
+            });
+            return Err(error.into());
+        }
     }
     Ok(())
 }
 
-async fn start_containers<S: AsRef<str>>(docker: &Docker, containers: &[S]) -> Result<(), Error> {
+/// Start every container, continuing past failures so one container does not keep the others down
+async fn start_containers<S: AsRef<str>>(
+    docker: &Docker,
+    containers: &[S],
+    started: &mut Vec<String>,
+) -> Result<(), Error> {
+    let mut failures = Vec::new();
     for container in containers {
         let start_options = Some(StartContainerOptions::<&str>::default());
         debug!(target: LOG_TARGET ,"Starting container: {}", container.as_ref());
-        docker
+        match docker
             .start_container(container.as_ref(), start_options)
-            .await?
+            .await
+        {
+            Ok(()) => started.push(container.as_ref().to_string()),
+            Err(error) => failures.push(format!("{}: {}", container.as_ref(), error)),
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(ContainersNotStarted(failures.join("; "))),
+    }
+}
+
+/// Unpause every container, continuing past failures so one container does not keep the others
+/// frozen
+async fn unpause_containers<S: AsRef<str>>(
+    docker: &Docker,
+    containers: &[S],
+    unpaused: &mut Vec<String>,
+) -> Result<(), Error> {
+    let mut failures = Vec::new();
+    for container in containers {
+        debug!(target: LOG_TARGET ,"Unpausing container: {}", container.as_ref());
+        match docker.unpause_container(container.as_ref()).await {
+            Ok(()) => unpaused.push(container.as_ref().to_string()),
+            Err(error) => failures.push(format!("{}: {}", container.as_ref(), error)),
+        }
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(ContainersNotUnpaused(failures.join("; "))),
     }
-    Ok(())
 }
 
 fn connect_docker() -> Result<Docker, Error> {
@@ -0,0 +1,218 @@
+use crate::configuration::{ArchiveStrategy, Configuration, QuiesceMode};
+use crate::docker::plan_container_processing;
+use crate::error::Error;
+use crate::exclude::VolumeFilter;
+use crate::incremental::plan_incremental;
+use crate::notification::format_size;
+use crate::repository::prune_repository;
+use crate::retention::{preview_retention, ArchiveFile};
+use crate::storage::apply_remote_retention;
+use crate::{timestamp, volume_directories, LOG_TARGET};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+/// An archive that a backup would write
+pub struct PlannedArchive {
+    pub name: String,
+    /// Volume the archive is for, which is `None` for the `Single` strategy
+    pub volume: Option<String>,
+    /// Number of entries below the volume directories that would be archived
+    pub entries: u64,
+    /// Size in bytes of the files that would be archived, before compression
+    pub bytes: u64,
+}
+
+/// Log what a backup would do with the current configuration: the containers it would run hooks in
+/// and stop or pause, the archives it would write with an estimate of their size, and the archives
+/// the retention policies would delete afterwards. Nothing is written and no container is changed.
+pub fn dry_run(config: &Configuration, runtime: &Runtime) -> Result<(), Error> {
+    let start_time = Instant::now();
+    info!(target: LOG_TARGET, "Dry run started");
+    let directories = volume_directories(config)?;
+
+    match config.container_management_enabled() {
+        true => {
+            for container in runtime.block_on(plan_container_processing(config))? {
+                let paths: Vec<_> = container
+                    .paths
+                    .iter()
+                    .map(|p| p.to_string_lossy())
+                    .collect();
+                if let Some(command) = container.pre_exec.as_ref().filter(|_| container.running) {
+                    info!(target: LOG_TARGET, "Would run pre-exec hook in container {}: {}", container.name, command);
+                }
+                match container.quiesce {
+                    _ if !container.running => {
+                        info!(target: LOG_TARGET, "Would leave container {} sharing {} as it is, because it is not running", container.name, paths.join(", "))
+                    }
+                    QuiesceMode::Stop => {
+                        info!(target: LOG_TARGET, "Would stop container {} sharing {}", container.name, paths.join(", "))
+                    }
+                    QuiesceMode::Pause => {
+                        info!(target: LOG_TARGET, "Would pause container {} sharing {}", container.name, paths.join(", "))
+                    }
+                    QuiesceMode::None => {
+                        info!(target: LOG_TARGET, "Would keep container {} sharing {} running", container.name, paths.join(", "))
+                    }
+                }
+                if let Some(command) = container.post_exec.as_ref().filter(|_| container.running) {
+                    info!(target: LOG_TARGET, "Would run post-exec hook in container {}: {}", container.name, command);
+                }
+            }
+        }
+        false => info!(target: LOG_TARGET, "Container management is disabled"),
+    }
+
+    let timestamp = timestamp()?;
+    let archives = match config.archive_strategy {
+        ArchiveStrategy::Single => {
+            let mut planned = PlannedArchive {
+                name: format!(
+                    "{}_{}.{}",
+                    config.archive_prefix,
+                    timestamp,
+                    config.archive_extension()
+                ),
+                volume: None,
+                entries: 0,
+                bytes: 0,
+            };
+            for (name, path) in directories.iter() {
+                let (entries, bytes) =
+                    estimate(config, name.to_string_lossy().as_ref(), path, true)?;
+                planned.entries += entries;
+                planned.bytes += bytes;
+            }
+            vec![planned]
+        }
+        ArchiveStrategy::Multiple => directories
+            .iter()
+            .map(|(name, path)| {
+                let volume = name.to_string_lossy().to_string();
+                let (entries, bytes) = estimate(config, volume.as_str(), path, true)?;
+                Ok(PlannedArchive {
+                    name: format!(
+                        "{}_{}_{}.{}",
+                        config.archive_prefix,
+                        volume,
+                        timestamp,
+                        config.archive_extension()
+                    ),
+                    volume: Some(volume),
+                    entries,
+                    bytes,
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+        ArchiveStrategy::Incremental => {
+            plan_incremental(directories.as_slice(), config, timestamp.as_str())?
+        }
+        ArchiveStrategy::Repository => directories
+            .iter()
+            .map(|(name, path)| {
+                let volume = name.to_string_lossy().to_string();
+                let (entries, bytes) = estimate(config, volume.as_str(), path, false)?;
+                Ok(PlannedArchive {
+                    name: format!("{}_{}_{}", config.archive_prefix, volume, timestamp),
+                    volume: Some(volume),
+                    entries,
+                    bytes,
+                })
+            })
+            .collect::<Result<_, Error>>()?,
+    };
+
+    let mut total = 0;
+    for archive in archives.iter() {
+        let volume = archive.volume.as_deref().unwrap_or("all volumes");
+        match config.archive_strategy {
+            ArchiveStrategy::Repository => {
+                info!(target: LOG_TARGET, "Would store snapshot {} for {} with {} entries ({} before deduplication)", archive.name, volume, archive.entries, format_size(archive.bytes))
+            }
+            _ => {
+                info!(target: LOG_TARGET, "Would write archive {} for {} with {} entries ({} before compression)", archive.name, volume, archive.entries, format_size(archive.bytes))
+            }
+        }
+        total += archive.bytes;
+    }
+    info!(target: LOG_TARGET, "Would archive {} in total from {} volumes", format_size(total), directories.len());
+
+    // Snapshots are not archive files, so the repository has its own retention
+    let planned = archives
+        .iter()
+        .filter_map(|a| {
+            ArchiveFile::parse(
+                config.backup_dir.join(a.name.as_str()),
+                &config.archive_prefix,
+            )
+        })
+        .collect();
+    preview_retention(config, planned)?;
+    // The remote and repository previews read remote storage and the chunk store, which a dry run
+    // should not fail on
+    if let Err(error) = apply_remote_retention(config, true) {
+        warn!(target: LOG_TARGET, "Could not preview the retention of remote storage: {}", error);
+    }
+    if matches!(config.archive_strategy, ArchiveStrategy::Repository) {
+        if let Err(error) = prune_repository(config, true) {
+            warn!(target: LOG_TARGET, "Could not preview the retention of the repository: {}", error);
+        }
+    }
+
+    info!(target: LOG_TARGET, "Dry run finished after {} milliseconds", start_time.elapsed().as_millis());
+    Ok(())
+}
+
+/// Count the entries below a volume directory that are not excluded and the total size of its
+/// files, walking it the way the archive strategies do. Symlinks to directories are followed when
+/// `follow` is set, as the tarball builder does, but a directory already visited is not walked again.
+fn estimate(
+    config: &Configuration,
+    volume: &str,
+    root: &Path,
+    follow: bool,
+) -> Result<(u64, u64), Error> {
+    let mut filter = VolumeFilter::new(&config.exclude, volume, root)?;
+    let metadata = std::fs::metadata(root)?;
+    let mut visited = HashSet::from([(metadata.dev(), metadata.ino())]);
+    walk(root, &mut filter, follow, &mut visited)
+}
+
+fn walk(
+    directory: &Path,
+    filter: &mut VolumeFilter,
+    follow: bool,
+    visited: &mut HashSet<(u64, u64)>,
+) -> Result<(u64, u64), Error> {
+    let (mut entries, mut bytes) = (0, 0);
+    for entry in filter.read_dir(directory)? {
+        let path = entry.path();
+        entries += 1;
+        let metadata = match follow {
+            true => match std::fs::metadata(path.as_path()) {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    warn!(target: LOG_TARGET, "Could not follow {}: {}", path.to_string_lossy(), error);
+                    continue;
+                }
+            },
+            false => entry.metadata()?,
+        };
+        if metadata.is_dir() {
+            if !visited.insert((metadata.dev(), metadata.ino())) {
+                warn!(target: LOG_TARGET, "Not walking {} again as it was already visited", path.to_string_lossy());
+                continue;
+            }
+            let (dir_entries, dir_bytes) = walk(path.as_path(), filter, follow, visited)?;
+            entries += dir_entries;
+            bytes += dir_bytes;
+        } else if metadata.is_file() {
+            bytes += metadata.len();
+        }
+    }
+    Ok((entries, bytes))
+}
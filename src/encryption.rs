@@ -0,0 +1,136 @@
+use crate::error::Error;
+use crate::error::Error::{InvalidIdentity, InvalidRecipient, KeyFile, NoIdentity};
+use age::stream::StreamWriter;
+use age::{x25519, Decryptor, Encryptor};
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Extension appended to the archive name when the archive is encrypted
+pub const ENCRYPTED_EXTENSION: &str = "age";
+
+#[derive(Default)]
+pub struct Encryption {
+    pub recipients: Vec<x25519::Recipient>,
+    pub identity_file: Option<PathBuf>,
+}
+
+/// Writer that encrypts everything written through it when recipients are configured
+pub enum EncryptionWriter<W: Write> {
+    None(W),
+    Age(StreamWriter<W>),
+}
+
+impl Encryption {
+    pub fn is_enabled(&self) -> bool {
+        !self.recipients.is_empty()
+    }
+
+    /// Wrap the writer to encrypt to all configured recipients
+    pub fn wrap_writer<W: Write>(&self, writer: W) -> Result<EncryptionWriter<W>, Error> {
+        if !self.is_enabled() {
+            return Ok(EncryptionWriter::None(writer));
+        }
+        let recipients = self.recipients.iter().map(|r| r as &dyn age::Recipient);
+        let encryptor = Encryptor::with_recipients(recipients)?;
+        Ok(EncryptionWriter::Age(encryptor.wrap_output(writer)?))
+    }
+}
+
+impl<W: Write> EncryptionWriter<W> {
+    /// Write the final encrypted chunk and return the inner writer
+    pub fn finish(self) -> std::io::Result<W> {
+        match self {
+            EncryptionWriter::None(writer) => Ok(writer),
+            EncryptionWriter::Age(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptionWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            EncryptionWriter::None(writer) => writer.write(buf),
+            EncryptionWriter::Age(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            EncryptionWriter::None(writer) => writer.flush(),
+            EncryptionWriter::Age(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Parse recipients separated by commas or whitespace
+pub fn parse_recipients<S: AsRef<str>>(value: S) -> Result<Vec<x25519::Recipient>, Error> {
+    value
+        .as_ref()
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|r| !r.is_empty())
+        .map(|r| x25519::Recipient::from_str(r).map_err(|_| InvalidRecipient(r.to_string())))
+        .collect()
+}
+
+/// Load recipients from a file with one recipient per line. Empty lines and lines starting with `#` are ignored.
+pub fn load_recipients<P: AsRef<Path>>(path: P) -> Result<Vec<x25519::Recipient>, Error> {
+    let mut recipients = Vec::new();
+    for line in read_key_file(path.as_ref())?.lines().map(str::trim) {
+        if !line.is_empty() && !line.starts_with('#') {
+            recipients.append(&mut parse_recipients(line)?);
+        }
+    }
+    Ok(recipients)
+}
+
+/// Load identities from a file in the format written by `age-keygen`
+pub fn load_identities<P: AsRef<Path>>(path: P) -> Result<Vec<x25519::Identity>, Error> {
+    let path = path.as_ref();
+    let mut identities = Vec::new();
+    for (index, line) in read_key_file(path)?.lines().map(str::trim).enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let identity = x25519::Identity::from_str(line)
+            .map_err(|_| InvalidIdentity(path.to_string_lossy().into(), index + 1))?;
+        identities.push(identity);
+    }
+    match identities.is_empty() {
+        true => Err(KeyFile(
+            path.to_string_lossy().into(),
+            "no identities found".into(),
+        )),
+        false => Ok(identities),
+    }
+}
+
+fn read_key_file(path: &Path) -> Result<String, Error> {
+    std::fs::read_to_string(path).map_err(|e| KeyFile(path.to_string_lossy().into(), e.to_string()))
+}
+
+pub fn is_encrypted<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .extension()
+        .is_some_and(|e| e.eq(ENCRYPTED_EXTENSION))
+}
+
+/// Open an archive for reading, decrypting it with the identities in the identity file when the
+/// archive is encrypted.
+pub fn open_archive<P: AsRef<Path>>(
+    path: P,
+    identity_file: Option<&Path>,
+) -> Result<Box<dyn Read>, Error> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    if !is_encrypted(path) {
+        return Ok(Box::new(file));
+    }
+
+    let identity_file = identity_file.ok_or_else(|| NoIdentity(path.to_string_lossy().into()))?;
+    let identities = load_identities(identity_file)?;
+    let decryptor = Decryptor::new_buffered(BufReader::new(file))?;
+    let reader = decryptor.decrypt(identities.iter().map(|i| i as &dyn age::Identity))?;
+    Ok(Box::new(reader))
+}
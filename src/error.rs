@@ -1,5 +1,19 @@
 use thiserror::Error;
 
+/// Exit code when the command line arguments are not valid, matching the code used for errors
+/// reported while parsing the arguments
+pub const EXIT_USAGE: u8 = 2;
+/// Exit code when the configuration is not valid
+pub const EXIT_CONFIGURATION: u8 = 3;
+/// Exit code when a container could not be managed or a container hook failed
+pub const EXIT_CONTAINER: u8 = 4;
+/// Exit code when an archive is damaged, does not match its checksum or cannot be found
+pub const EXIT_INTEGRITY: u8 = 5;
+/// Exit code when a storage backend operation fails
+pub const EXIT_STORAGE: u8 = 6;
+/// Exit code for any other failure, such as an I/O error while archiving
+pub const EXIT_FAILURE: u8 = 1;
+
 #[derive(Error, Debug)]
 pub enum Error {
     /// Error return when conversion to [`ArchiveStrategy`] fails
@@ -14,10 +28,148 @@ pub enum Error {
     #[error("Provided value cannot be converted to ArchivePermission enum")]
     InvalidPermission,
 
+    /// Error return when conversion to [`QuiesceMode`] fails
+    #[error("Provided value cannot be converted to QuiesceMode enum")]
+    InvalidQuiesceMode,
+
+    /// Error return when conversion to [`NotifyOn`] fails
+    #[error("Provided value cannot be converted to NotifyOn enum")]
+    InvalidNotifyOn,
+
+    /// Error return when conversion to [`SmtpSecurity`] fails
+    #[error("Provided value cannot be converted to SmtpSecurity enum")]
+    InvalidSmtpSecurity,
+
+    /// Error return when a setting cannot be converted to a whole number
+    #[error("Provided value for {0} cannot be converted to a whole number")]
+    InvalidNumber(String),
+
+    /// Error returned when a setting required by another setting is not provided
+    #[error("Required setting {0} is not set")]
+    MissingSetting(String),
+
+    /// Error returned when the value of a setting is not valid
+    #[error("Invalid value for {0}: {1}")]
+    InvalidSetting(String, String),
+
+    /// Error returned when the configuration file cannot be read or parsed
+    #[error("Invalid configuration file {0}: {1}")]
+    InvalidConfigFile(String, String),
+
+    /// Error returned when a storage backend operation fails
+    #[error("Storage backend error: {0}")]
+    Storage(String),
+
+    /// Error returned when a notification cannot be sent
+    #[error("Notification error: {0}")]
+    Notification(String),
+
+    /// Error returned when an email cannot be sent
+    #[error("SMTP error: {0}")]
+    Smtp(String),
+
+    /// Error returned when the metrics server cannot be started
+    #[error("Metrics server error: {0}")]
+    MetricsServer(String),
+
     /// Error returned when a required directory does not exit
     #[error("No volume mounted at: {0}")]
     NoVolumeMounted(String),
 
+    /// Error returned when the schedule is not a valid cron expression
+    #[error("Invalid cron expression in SCHEDULE ({0}): {1}")]
+    InvalidSchedule(String, String),
+
+    /// Error returned when the schedule has no upcoming run time
+    #[error("The schedule has no upcoming run time")]
+    NoScheduledRun,
+
+    /// Error returned when the command line arguments are not valid
+    #[error("Invalid arguments: {0}")]
+    InvalidArguments(String),
+
+    /// Error returned when the compression of an archive cannot be determined from its name
+    #[error("Unable to determine archive compression from extension: {0}")]
+    UnknownArchiveType(String),
+
+    /// Error returned when an archive entry would be extracted outside the restore directory
+    #[error("Archive entry has an unsafe path: {0}")]
+    UnsafeArchivePath(String),
+
+    /// Error returned when the checksum of an archive does not match its sidecar
+    #[error("Checksum mismatch: expected {0} but found {1}")]
+    ChecksumMismatch(String, String),
+
+    /// Error returned when one or more archives fail verification
+    #[error("{0} archives failed verification")]
+    VerificationFailed(usize),
+
+    /// Error returned when an age recipient cannot be parsed
+    #[error("Invalid age recipient: {0}")]
+    InvalidRecipient(String),
+
+    /// Error returned when an age identity cannot be parsed
+    #[error("Invalid age identity in {0} on line {1}")]
+    InvalidIdentity(String, usize),
+
+    /// Error returned when a recipients or identity file cannot be loaded
+    #[error("Unable to load key file {0}: {1}")]
+    KeyFile(String, String),
+
+    /// Error returned when an encrypted archive is read without an identity file
+    #[error("Archive {0} is encrypted but no identity file was provided")]
+    NoIdentity(String),
+
+    /// Error returned when the full archive an incremental archive is based on cannot be found
+    #[error("No full archive found for incremental archive {0}")]
+    MissingFullArchive(String),
+
+    /// Error returned when an archive an incremental archive builds on is not in the catalog
+    #[error("Incremental archive {0} cannot be restored without {1}, which is not in the catalog")]
+    MissingParentArchive(String, String),
+
+    /// Error returned when a snapshot cannot be found in the repository
+    #[error("Snapshot {0} was not found in the repository")]
+    SnapshotNotFound(String),
+
+    /// Error returned when encryption is configured with an archive strategy that does not support it
+    #[error("Encryption is not supported by the {0} archive strategy")]
+    UnsupportedEncryption(String),
+
+    /// Error returned when a pre-exec or post-exec hook does not succeed
+    #[error("{0} hook in container {1} failed: {2}")]
+    ExecFailed(String, String, String),
+
+    /// Error returned when one or more hooks failed
+    #[error("Container hooks failed: {0}")]
+    HookFailed(String),
+
+    /// Error returned when one or more stopped containers could not be started
+    #[error("Unable to start containers: {0}")]
+    ContainersNotStarted(String),
+
+    /// Error returned when one or more paused containers could not be unpaused
+    #[error("Unable to unpause containers: {0}")]
+    ContainersNotUnpaused(String),
+
+    /// Error returned when the work done while containers were stopped or paused failed and the
+    /// containers could not be started or unpaused again afterward
+    #[error("{0}. Resuming the stopped or paused containers also failed: {1}")]
+    RestartFailed(Box<Error>, Box<Error>),
+
+    /// Error returned when volumes were not archived because their pre-exec hook failed
+    #[error("Volumes were not archived because a pre-exec hook failed: {0}")]
+    VolumesSkipped(String),
+
+    /// Error returned when volumes were archived but the post-exec hook of a container sharing them
+    /// failed
+    #[error("Volumes were archived but a post-exec hook failed: {0}")]
+    PostExecFailed(String),
+
+    /// Error returned when archiving a volume fails, naming the volume
+    #[error("Archiving volume {0} failed: {1}")]
+    VolumeFailed(String, Box<Error>),
+
     /// Error returned when no instance of a running salvage container can be found
     #[error("No running salvage container was found")]
     NoSalvageContainer,
@@ -27,6 +179,34 @@ pub enum Error {
     #[error("bollard::errors::Error: {0}")]
     DockerApi(#[from] bollard::errors::Error),
 
+    /// Pass-thru `ssh2::Error`
+    #[error("ssh2::Error: {0}")]
+    Ssh(#[from] ssh2::Error),
+
+    /// Pass-thru `fastcdc::v2020::Error`
+    #[error("fastcdc::v2020::Error: {0}")]
+    Chunking(#[from] fastcdc::v2020::Error),
+
+    /// Pass-thru `ignore::Error`
+    #[error("ignore::Error: {0}")]
+    Ignore(#[from] ignore::Error),
+
+    /// Pass-thru `rustls::Error`
+    #[error("rustls::Error: {0}")]
+    Tls(#[from] rustls::Error),
+
+    /// Pass-thru `serde_json::Error`
+    #[error("serde_json::Error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// Pass-thru `age::EncryptError`
+    #[error("age::EncryptError: {0}")]
+    Encrypt(#[from] age::EncryptError),
+
+    /// Pass-thru `age::DecryptError`
+    #[error("age::DecryptError: {0}")]
+    Decrypt(#[from] age::DecryptError),
+
     /// Pass-thru [`std::io::Error`].
     #[error("std::io Error: {0}")]
     IO(#[from] std::io::Error),
@@ -43,3 +223,50 @@ pub enum Error {
     #[error("time::error::Format Error: {0}")]
     TimeFormat(#[from] time::error::Format),
 }
+
+impl Error {
+    /// Exit code for the class of failure this error belongs to
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::InvalidArguments(_) => EXIT_USAGE,
+            Self::InvalidBackupType
+            | Self::InvalidCompressionType
+            | Self::InvalidPermission
+            | Self::InvalidQuiesceMode
+            | Self::InvalidNotifyOn
+            | Self::InvalidSmtpSecurity
+            | Self::InvalidNumber(_)
+            | Self::MissingSetting(_)
+            | Self::InvalidSetting(_, _)
+            | Self::InvalidConfigFile(_, _)
+            | Self::NoVolumeMounted(_)
+            | Self::InvalidSchedule(_, _)
+            | Self::NoScheduledRun
+            | Self::InvalidRecipient(_)
+            | Self::InvalidIdentity(_, _)
+            | Self::KeyFile(_, _)
+            | Self::NoIdentity(_)
+            | Self::UnsupportedEncryption(_) => EXIT_CONFIGURATION,
+            Self::DockerApi(_)
+            | Self::ExecFailed(_, _, _)
+            | Self::HookFailed(_)
+            | Self::ContainersNotStarted(_)
+            | Self::ContainersNotUnpaused(_)
+            | Self::RestartFailed(_, _)
+            | Self::VolumesSkipped(_)
+            | Self::PostExecFailed(_)
+            | Self::NoSalvageContainer => EXIT_CONTAINER,
+            Self::UnknownArchiveType(_)
+            | Self::UnsafeArchivePath(_)
+            | Self::ChecksumMismatch(_, _)
+            | Self::VerificationFailed(_)
+            | Self::MissingFullArchive(_)
+            | Self::MissingParentArchive(_, _)
+            | Self::SnapshotNotFound(_)
+            | Self::Decrypt(_) => EXIT_INTEGRITY,
+            Self::Storage(_) | Self::Ssh(_) => EXIT_STORAGE,
+            Self::VolumeFailed(_, error) => error.exit_code(),
+            _ => EXIT_FAILURE,
+        }
+    }
+}
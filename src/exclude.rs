@@ -0,0 +1,231 @@
+use crate::error::Error;
+use crate::LOG_TARGET;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::{trace, warn};
+use std::collections::HashSet;
+use std::fs::DirEntry;
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// File listing patterns to exclude from the directory it is in and below, using the syntax of
+/// `.gitignore`
+pub const IGNORE_FILE: &str = ".salvageignore";
+
+/// Exclude patterns set in the configuration
+#[derive(Default)]
+pub struct ExcludeConfig {
+    /// Patterns applied to every volume
+    pub patterns: Vec<String>,
+    /// Patterns applied to a single volume, as pairs of volume name and pattern
+    pub volume_patterns: Vec<(String, String)>,
+}
+
+impl ExcludeConfig {
+    /// Patterns that apply to a volume. The patterns of the volume come last so they can include
+    /// paths excluded by the patterns of every volume.
+    fn patterns<'a>(&'a self, volume: &'a str) -> impl Iterator<Item = &'a str> {
+        self.patterns.iter().map(String::as_str).chain(
+            self.volume_patterns
+                .iter()
+                .filter(move |(v, _)| v.eq(volume))
+                .map(|(_, p)| p.as_str()),
+        )
+    }
+}
+
+/// Check that a pattern can be parsed, returning the reason when it cannot
+pub fn check_pattern(pattern: &str) -> Result<(), String> {
+    GitignoreBuilder::new("/")
+        .add_line(None, pattern)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Decides which entries below a volume directory are archived. Entries are excluded by the
+/// `.salvageignore` files of the directories they are in, the deepest file taking precedence as
+/// with `.gitignore`, and then by the patterns in the configuration. The contents of an excluded
+/// directory are never read, so they cannot be included again.
+pub struct VolumeFilter {
+    patterns: Gitignore,
+    /// The `.salvageignore` files of the directories being walked, outermost first
+    ignore_files: Vec<(PathBuf, Gitignore)>,
+}
+
+impl VolumeFilter {
+    pub fn new(config: &ExcludeConfig, volume: &str, root: &Path) -> Result<Self, Error> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in config.patterns(volume) {
+            builder.add_line(None, pattern)?;
+        }
+        Ok(Self {
+            patterns: builder.build()?,
+            ignore_files: Vec::new(),
+        })
+    }
+
+    /// Read the entries of a directory that are not excluded, sorted by name. Directories must be
+    /// read after their parent directory, as they are in a depth first walk, for the
+    /// `.salvageignore` files of the parents to apply.
+    pub fn read_dir(&mut self, directory: &Path) -> Result<Vec<DirEntry>, Error> {
+        self.ignore_files.retain(|(d, _)| directory.starts_with(d));
+        let ignore_file = directory.join(IGNORE_FILE);
+        if ignore_file.is_file() {
+            let (matcher, error) = Gitignore::new(ignore_file.as_path());
+            if let Some(error) = error {
+                warn!(target: LOG_TARGET, "Ignoring invalid patterns in {}", error);
+            }
+            self.ignore_files.push((directory.to_path_buf(), matcher));
+        }
+
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+            match self.is_excluded(path.as_path(), entry.file_type()?.is_dir()) {
+                true => trace!(target: LOG_TARGET, "Excluding {}", path.to_string_lossy()),
+                false => entries.push(entry),
+            }
+        }
+        entries.sort_by_key(DirEntry::file_name);
+        Ok(entries)
+    }
+
+    fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        for (_, matcher) in self.ignore_files.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        self.patterns.matched(path, is_dir).is_ignore()
+    }
+}
+
+/// Append a volume directory to the tarball as `name` along with every entry below it that is not
+/// excluded, in place of [`tar::Builder::append_dir_all`]. Return the number of entries appended
+/// below the volume directory. Symlinks to directories are followed, but a directory already
+/// appended is not walked again, so a symlink loop is archived once.
+pub fn append_volume<W: Write>(
+    tar: &mut tar::Builder<W>,
+    name: &Path,
+    root: &Path,
+    filter: &mut VolumeFilter,
+) -> Result<u64, Error> {
+    tar.append_dir(name, root)?;
+    let metadata = std::fs::metadata(root)?;
+    let mut visited = HashSet::from([(metadata.dev(), metadata.ino())]);
+    append_entries(tar, name, root, filter, &mut visited)
+}
+
+fn append_entries<W: Write>(
+    tar: &mut tar::Builder<W>,
+    name: &Path,
+    directory: &Path,
+    filter: &mut VolumeFilter,
+    visited: &mut HashSet<(u64, u64)>,
+) -> Result<u64, Error> {
+    let mut count = 0;
+    for entry in filter.read_dir(directory)? {
+        let path = entry.path();
+        let entry_name = name.join(entry.file_name());
+        tar.append_path_with_name(path.as_path(), entry_name.as_path())?;
+        count += 1;
+        // The builder follows symlinks, so a link to a directory is archived as the directory
+        match std::fs::metadata(path.as_path()) {
+            Ok(metadata) if metadata.is_dir() => {
+                if !visited.insert((metadata.dev(), metadata.ino())) {
+                    warn!(target: LOG_TARGET, "Not archiving the contents of {} again as it was already archived", path.to_string_lossy());
+                    continue;
+                }
+                count +=
+                    append_entries(tar, entry_name.as_path(), path.as_path(), filter, visited)?;
+            }
+            _ => (),
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn archived(root: &Path, config: &ExcludeConfig) -> Vec<String> {
+        let mut filter = VolumeFilter::new(config, "volume", root).unwrap();
+        let mut tar = tar::Builder::new(Vec::new());
+        append_volume(&mut tar, Path::new("volume"), root, &mut filter).unwrap();
+        let data = tar.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn archives_a_symlink_loop_once() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        fs::write(root.path().join("sub/file"), "data").unwrap();
+        std::os::unix::fs::symlink("..", root.path().join("sub/loop")).unwrap();
+
+        assert_eq!(
+            archived(root.path(), &ExcludeConfig::default()),
+            ["volume", "volume/sub", "volume/sub/file", "volume/sub/loop"]
+        );
+    }
+
+    #[test]
+    fn deepest_ignore_file_takes_precedence() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("sub")).unwrap();
+        fs::write(root.path().join(IGNORE_FILE), "*.log\n").unwrap();
+        fs::write(root.path().join("sub").join(IGNORE_FILE), "!keep.log\n").unwrap();
+        for file in ["a.log", "sub/keep.log", "sub/other.log"] {
+            fs::write(root.path().join(file), "data").unwrap();
+        }
+
+        let entries = archived(root.path(), &ExcludeConfig::default());
+        assert!(entries.contains(&"volume/sub/keep.log".to_string()));
+        assert!(!entries.contains(&"volume/sub/other.log".to_string()));
+        assert!(!entries.contains(&"volume/a.log".to_string()));
+    }
+
+    #[test]
+    fn ignore_files_take_precedence_over_configured_patterns() {
+        let root = tempfile::tempdir().unwrap();
+        fs::write(root.path().join(IGNORE_FILE), "!keep.tmp\n").unwrap();
+        for file in ["keep.tmp", "other.tmp"] {
+            fs::write(root.path().join(file), "data").unwrap();
+        }
+        let config = ExcludeConfig {
+            patterns: vec!["*.tmp".to_string()],
+            volume_patterns: Vec::new(),
+        };
+
+        let entries = archived(root.path(), &config);
+        assert!(entries.contains(&"volume/keep.tmp".to_string()));
+        assert!(!entries.contains(&"volume/other.tmp".to_string()));
+    }
+
+    #[test]
+    fn volume_patterns_apply_after_global_patterns() {
+        let root = tempfile::tempdir().unwrap();
+        for file in ["keep.tmp", "other.tmp"] {
+            fs::write(root.path().join(file), "data").unwrap();
+        }
+        let config = ExcludeConfig {
+            patterns: vec!["*.tmp".to_string()],
+            volume_patterns: vec![("volume".to_string(), "!keep.tmp".to_string())],
+        };
+
+        let entries = archived(root.path(), &config);
+        assert!(entries.contains(&"volume/keep.tmp".to_string()));
+        assert!(!entries.contains(&"volume/other.tmp".to_string()));
+    }
+}
@@ -0,0 +1,608 @@
+use crate::configuration::Configuration;
+use crate::dry_run::PlannedArchive;
+use crate::error::Error;
+use crate::error::Error::MissingFullArchive;
+use crate::exclude::VolumeFilter;
+use crate::retention::group_archives;
+use crate::{finish_archive, select_encoder, WrittenArchive, LOG_TARGET};
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Extension added before `.tar` in the name of incremental archives
+pub const INCREMENTAL_EXTENSION: &str = "incr";
+/// Name of the entry in each volume of an incremental archive listing the paths deleted since the
+/// previous archive
+pub const DELETED_ENTRY: &str = ".salvage-deleted";
+/// Key of the PAX extended header record marking the entry as the list of deleted paths, so a file
+/// in the volume with the same name is archived and restored like any other file
+const DELETED_PAX_KEY: &str = "SALVAGE.deleted";
+const INDEX_EXTENSION: &str = "index";
+/// Version 1 indexes did not record the last archive, so they are ignored and a full archive made
+const INDEX_HEADER: &str = "salvage-index 2";
+
+/// Metadata used to detect whether a path changed since the previous archive
+#[derive(PartialEq)]
+struct IndexEntry {
+    inode: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+/// Snapshot of a volume as of the last archive, stored in the backup directory as
+/// `.{prefix}_{volume}.index`
+struct SnapshotIndex {
+    full_archive: String,
+    incrementals: u32,
+    /// Archive the index was saved for, which the next incremental archive builds on
+    last_archive: String,
+    entries: BTreeMap<PathBuf, IndexEntry>,
+}
+
+impl SnapshotIndex {
+    fn path(config: &Configuration, volume: &OsStr) -> PathBuf {
+        config.backup_dir.join(format!(
+            ".{}_{}.{}",
+            config.archive_prefix,
+            volume.to_string_lossy(),
+            INDEX_EXTENSION
+        ))
+    }
+
+    /// Load the index from disk. A missing or unreadable index results in `None`, which forces a
+    /// full archive.
+    fn load(path: &Path) -> Result<Option<Self>, Error> {
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let mut lines = BufReader::new(File::open(path)?).split(b'\n');
+        let mut header = || lines.next().transpose().map(|l| l.unwrap_or_default());
+        let version = header()?;
+        let full_archive = String::from_utf8_lossy(header()?.as_slice()).to_string();
+        let incrementals = String::from_utf8_lossy(header()?.as_slice()).parse::<u32>();
+        let last_archive = String::from_utf8_lossy(header()?.as_slice()).to_string();
+        let (Ok(incrementals), true) = (incrementals, version.eq(INDEX_HEADER.as_bytes())) else {
+            warn!(target: LOG_TARGET, "Ignoring unreadable snapshot index {}", path.to_string_lossy());
+            return Ok(None);
+        };
+
+        let mut entries = BTreeMap::new();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.splitn(5, |b| *b == b'\t');
+            let mut number = || {
+                fields
+                    .next()
+                    .and_then(|f| std::str::from_utf8(f).ok())
+                    .and_then(|f| f.parse::<i64>().ok())
+            };
+            let (Some(inode), Some(size), Some(mtime), Some(mtime_nsec)) =
+                (number(), number(), number(), number())
+            else {
+                warn!(target: LOG_TARGET, "Ignoring unreadable snapshot index {}", path.to_string_lossy());
+                return Ok(None);
+            };
+            let path = unescape(fields.next().unwrap_or_default());
+            let entry = IndexEntry {
+                inode: inode as u64,
+                size: size as u64,
+                mtime,
+                mtime_nsec,
+            };
+            entries.insert(path, entry);
+        }
+
+        Ok(Some(Self {
+            full_archive,
+            incrementals,
+            last_archive,
+            entries,
+        }))
+    }
+
+    /// Write the index next to its final path and rename it into place so an interrupted run
+    /// never leaves a partial index behind.
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut temporary = path.as_os_str().to_os_string();
+        temporary.push(".tmp");
+        let mut writer = BufWriter::new(File::create(temporary.as_os_str())?);
+        writeln!(writer, "{}", INDEX_HEADER)?;
+        writeln!(writer, "{}", self.full_archive)?;
+        writeln!(writer, "{}", self.incrementals)?;
+        writeln!(writer, "{}", self.last_archive)?;
+        for (path, entry) in self.entries.iter() {
+            write!(
+                writer,
+                "{}\t{}\t{}\t{}\t",
+                entry.inode, entry.size, entry.mtime, entry.mtime_nsec
+            )?;
+            writer.write_all(escape(path.as_os_str()).as_slice())?;
+            writer.write_all(b"\n")?;
+        }
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        std::fs::rename(temporary, path)?;
+        Ok(())
+    }
+}
+
+/// Snapshot index of an archive that has been written, to be saved once the archive is in the
+/// catalog so the next run never builds on an archive the catalog does not know about
+pub struct PendingIndex {
+    path: PathBuf,
+    index: SnapshotIndex,
+}
+
+impl PendingIndex {
+    pub fn save(&self) -> Result<(), Error> {
+        self.index.save(self.path.as_path())
+    }
+}
+
+/// Archive each directory into its own archive containing only the paths that changed since the
+/// previous archive, along with the paths that were deleted. A full archive is created when no
+/// snapshot index exists, the previous full archive is missing or the configured number of
+/// archives has been made since the last full archive. Archives are added to `written` as they
+/// are finished, each with the snapshot index to save once it is in the catalog.
+pub fn incremental_archive(
+    directories: Vec<(OsString, PathBuf)>,
+    config: &Configuration,
+    timestamp: &str,
+    written: &mut Vec<WrittenArchive>,
+) -> Result<(), Error> {
+    for (name, path) in directories {
+        let start_time = Instant::now();
+        let index_path = SnapshotIndex::path(config, name.as_os_str());
+        let previous = previous_index(config, index_path.as_path())?;
+
+        let mut entries = BTreeMap::new();
+        let mut filter = VolumeFilter::new(
+            &config.exclude,
+            name.to_string_lossy().as_ref(),
+            path.as_path(),
+        )?;
+        scan(path.as_path(), Path::new(""), &mut filter, &mut entries)?;
+
+        let archive_name = archive_name(config, name.as_os_str(), timestamp, previous.is_some());
+        let archive_path = config.backup_dir.join(archive_name.as_str());
+        let compressor = select_encoder(
+            archive_path.as_path(),
+            &config.archive_compression,
+            config.archive_compression_level,
+            config.archive_compression_threads,
+            &config.encryption,
+        )?;
+        let mut tar = tar::Builder::new(compressor);
+        tar.follow_symlinks(false);
+        tar.append_dir(name.as_os_str(), path.as_path())?;
+
+        let mut changed = 0;
+        for (relative, entry) in entries.iter() {
+            let unchanged = previous
+                .as_ref()
+                .and_then(|p| p.entries.get(relative))
+                .is_some_and(|p| p.eq(entry));
+            if !unchanged {
+                tar.append_path_with_name(path.join(relative), Path::new(&name).join(relative))?;
+                changed += 1;
+            }
+        }
+
+        if let Some(previous) = previous.as_ref() {
+            let mut deleted = Vec::new();
+            for relative in previous.entries.keys() {
+                if !entries.contains_key(relative) {
+                    deleted.extend_from_slice(escape(relative.as_os_str()).as_slice());
+                    deleted.push(b'\n');
+                }
+            }
+            append_deleted(&mut tar, Path::new(&name), deleted.as_slice())?;
+        }
+        let digest = finish_archive(tar, archive_path.as_path(), config)?;
+
+        let (index, parent) = match previous {
+            Some(previous) => (
+                SnapshotIndex {
+                    full_archive: previous.full_archive,
+                    incrementals: previous.incrementals + 1,
+                    last_archive: archive_name.clone(),
+                    entries,
+                },
+                Some(previous.last_archive),
+            ),
+            None => (
+                SnapshotIndex {
+                    full_archive: archive_name.clone(),
+                    incrementals: 0,
+                    last_archive: archive_name.clone(),
+                    entries,
+                },
+                None,
+            ),
+        };
+        info!(target: LOG_TARGET, "Archive {} contains {} changed paths", archive_name, changed);
+        debug!(target: LOG_TARGET, "Archive {} took {} milliseconds", archive_name, start_time.elapsed().as_millis());
+        let volume = name.to_string_lossy().to_string();
+        let base = (index.incrementals > 0).then(|| index.full_archive.clone());
+        written.push(WrittenArchive {
+            base,
+            parent,
+            index: Some(PendingIndex {
+                path: index_path,
+                index,
+            }),
+            ..WrittenArchive::new(archive_path, Some(volume), changed, digest, start_time)?
+        });
+    }
+    Ok(())
+}
+
+/// Append the list of deleted paths of a volume, preceded by the PAX extended header that marks it
+fn append_deleted<W: Write>(
+    tar: &mut tar::Builder<W>,
+    volume: &Path,
+    deleted: &[u8],
+) -> Result<(), Error> {
+    let path = volume.join(DELETED_ENTRY);
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    // Each record is `{length} {key}={value}\n`, where the length counts its own two digits
+    let record = format!(" {}=1\n", DELETED_PAX_KEY);
+    let record = format!("{}{}", record.len() + 2, record);
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::XHeader);
+    header.set_size(record.len() as u64);
+    header.set_mode(0o600);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    tar.append_data(
+        &mut header,
+        volume.join("PaxHeaders").join(DELETED_ENTRY),
+        record.as_bytes(),
+    )?;
+
+    let mut header = tar::Header::new_ustar();
+    header.set_size(deleted.len() as u64);
+    header.set_mode(0o600);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(mtime);
+    tar.append_data(&mut header, path, deleted)?;
+    Ok(())
+}
+
+/// Check whether an entry of an incremental archive is the list of deleted paths of its volume
+/// rather than a file of the volume with the same name
+pub fn is_deleted_list<R: std::io::Read>(entry: &mut tar::Entry<R>) -> Result<bool, Error> {
+    // Lists written before the marker was added have a GNU header without an owner, which a file
+    // archived from the volume always has
+    let Some(extensions) = entry.pax_extensions()? else {
+        let header = entry.header();
+        return Ok(header.as_gnu().is_some() && header.uid().is_err());
+    };
+    for extension in extensions {
+        let extension = extension?;
+        if extension.key_bytes().eq(DELETED_PAX_KEY.as_bytes()) && extension.value_bytes().eq(b"1")
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Describe the archives [`incremental_archive`] would write without writing anything, with the
+/// number and size of the changed paths each archive would contain
+pub fn plan_incremental(
+    directories: &[(OsString, PathBuf)],
+    config: &Configuration,
+    timestamp: &str,
+) -> Result<Vec<PlannedArchive>, Error> {
+    let mut archives = Vec::new();
+    for (name, path) in directories {
+        let index_path = SnapshotIndex::path(config, name.as_os_str());
+        let previous = previous_index(config, index_path.as_path())?;
+        let mut entries = BTreeMap::new();
+        let mut filter = VolumeFilter::new(
+            &config.exclude,
+            name.to_string_lossy().as_ref(),
+            path.as_path(),
+        )?;
+        scan(path.as_path(), Path::new(""), &mut filter, &mut entries)?;
+
+        let mut planned = PlannedArchive {
+            name: archive_name(config, name.as_os_str(), timestamp, previous.is_some()),
+            volume: Some(name.to_string_lossy().to_string()),
+            entries: 0,
+            bytes: 0,
+        };
+        for (relative, entry) in entries.iter() {
+            let unchanged = previous
+                .as_ref()
+                .and_then(|p| p.entries.get(relative))
+                .is_some_and(|p| p.eq(entry));
+            if !unchanged {
+                planned.entries += 1;
+                if !path.join(relative).is_dir() {
+                    planned.bytes += entry.size;
+                }
+            }
+        }
+        archives.push(planned);
+    }
+    Ok(archives)
+}
+
+/// Load the snapshot index of the previous archive when the next archive should build on it. A
+/// full archive is needed when there is no index, its full archive or last archive is missing or
+/// the configured number of archives has been made since the full archive.
+fn previous_index(
+    config: &Configuration,
+    index_path: &Path,
+) -> Result<Option<SnapshotIndex>, Error> {
+    Ok(SnapshotIndex::load(index_path)?.filter(|index| {
+        // Local archives are expected to be missing when they are deleted after upload
+        let missing = [index.full_archive.as_str(), index.last_archive.as_str()]
+            .into_iter()
+            .find(|a| !config.storage.delete_local && !config.backup_dir.join(a).is_file());
+        if let Some(missing) = missing {
+            warn!(target: LOG_TARGET, "Archive {} is missing. Creating a new full archive.", missing);
+        }
+        missing.is_none() && index.incrementals + 1 < config.incremental_full_every
+    }))
+}
+
+fn archive_name(
+    config: &Configuration,
+    volume: &OsStr,
+    timestamp: &str,
+    incremental: bool,
+) -> String {
+    match incremental {
+        true => format!(
+            "{}_{}_{}.{}.{}",
+            config.archive_prefix,
+            volume.to_string_lossy(),
+            timestamp,
+            INCREMENTAL_EXTENSION,
+            config.archive_extension()
+        ),
+        false => format!(
+            "{}_{}_{}.{}",
+            config.archive_prefix,
+            volume.to_string_lossy(),
+            timestamp,
+            config.archive_extension()
+        ),
+    }
+}
+
+/// Record the metadata of every path below `root` that is not excluded, keyed by its path relative
+/// to the volume
+fn scan(
+    root: &Path,
+    relative: &Path,
+    filter: &mut VolumeFilter,
+    entries: &mut BTreeMap<PathBuf, IndexEntry>,
+) -> Result<(), Error> {
+    for dir_entry in filter.read_dir(root.join(relative).as_path())? {
+        let path = relative.join(dir_entry.file_name());
+        let metadata = dir_entry.metadata()?;
+        entries.insert(
+            path.clone(),
+            IndexEntry {
+                inode: metadata.ino(),
+                size: metadata.size(),
+                mtime: metadata.mtime(),
+                mtime_nsec: metadata.mtime_nsec(),
+            },
+        );
+        if metadata.is_dir() {
+            scan(root, path.as_path(), filter, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Check whether the archive was created by the `Incremental` strategy as an incremental archive
+pub fn is_incremental<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref()
+        .file_name()
+        .map(|f| f.to_string_lossy())
+        .is_some_and(|f| f.contains(format!(".{}.tar.", INCREMENTAL_EXTENSION).as_str()))
+}
+
+/// Find the archives needed to restore an incremental archive, from the full archive it is based
+/// on through to the archive itself, oldest first.
+pub fn incremental_chain(archive: &Path, prefix: &str) -> Result<Vec<PathBuf>, Error> {
+    let directory = archive.parent().unwrap_or(Path::new("."));
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        paths.push(entry?.path());
+    }
+
+    let missing = || MissingFullArchive(archive.to_string_lossy().into());
+    let archive_name = archive.file_name().ok_or_else(missing)?;
+    let volume = crate::retention::ArchiveFile::parse(archive, prefix)
+        .and_then(|a| a.volume)
+        .ok_or_else(missing)?;
+    let archives = group_archives(paths, prefix, &HashSet::from([volume.clone()]))
+        .remove(&Some(volume))
+        .unwrap_or_default();
+
+    // Archives are sorted newest first so walk back until the full archive is found
+    let mut chain = Vec::new();
+    for candidate in archives
+        .into_iter()
+        .skip_while(|a| a.path.file_name().ne(&Some(archive_name)))
+    {
+        let incremental = candidate.incremental;
+        chain.push(candidate.path);
+        if !incremental {
+            chain.reverse();
+            return Ok(chain);
+        }
+    }
+    Err(missing())
+}
+
+/// Escape backslashes and newlines so each path fits on a single line
+fn escape(path: &OsStr) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(path.len());
+    for byte in path.as_bytes() {
+        match byte {
+            b'\\' => escaped.extend_from_slice(b"\\\\"),
+            b'\n' => escaped.extend_from_slice(b"\\n"),
+            b => escaped.push(*b),
+        }
+    }
+    escaped
+}
+
+/// Reverse [`escape`]
+pub fn unescape(escaped: &[u8]) -> PathBuf {
+    let mut path = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.iter();
+    while let Some(byte) = bytes.next() {
+        match (byte, bytes.as_slice().first()) {
+            (b'\\', Some(b'n')) => {
+                path.push(b'\n');
+                bytes.next();
+            }
+            (b'\\', Some(b'\\')) => {
+                path.push(b'\\');
+                bytes.next();
+            }
+            (b, _) => path.push(*b),
+        }
+    }
+    PathBuf::from(OsString::from_vec(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_paths_onto_a_single_line() {
+        let path = OsStr::from_bytes(b"dir\\name\nwith\ttab\xff");
+        let escaped = escape(path);
+        assert_eq!(escaped, b"dir\\\\name\\nwith\ttab\xff");
+        assert!(!escaped.contains(&b'\n'));
+        assert_eq!(unescape(escaped.as_slice()).as_os_str(), path);
+    }
+
+    #[test]
+    fn unescapes_a_trailing_backslash_as_is() {
+        assert_eq!(unescape(b"name\\"), PathBuf::from("name\\"));
+        assert_eq!(unescape(b"a\\tb"), PathBuf::from("a\\tb"));
+    }
+
+    #[test]
+    fn saves_and_loads_the_index() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(".backup_db.index");
+        let index = SnapshotIndex {
+            full_archive: "backup_db_2024-01-01_00-00-00.tar.gz".to_string(),
+            incrementals: 3,
+            last_archive: "backup_db_2024-01-04_00-00-00.incr.tar.gz".to_string(),
+            entries: BTreeMap::from([
+                (
+                    PathBuf::from("dir"),
+                    IndexEntry {
+                        inode: 2,
+                        size: 4096,
+                        mtime: 1_700_000_000,
+                        mtime_nsec: 0,
+                    },
+                ),
+                (
+                    PathBuf::from("dir/new\nline"),
+                    IndexEntry {
+                        inode: 3,
+                        size: 12,
+                        mtime: -1,
+                        mtime_nsec: 999_999_999,
+                    },
+                ),
+            ]),
+        };
+        index.save(path.as_path()).unwrap();
+
+        let loaded = SnapshotIndex::load(path.as_path()).unwrap().unwrap();
+        assert_eq!(loaded.full_archive, index.full_archive);
+        assert_eq!(loaded.incrementals, index.incrementals);
+        assert_eq!(loaded.last_archive, index.last_archive);
+        assert!(loaded.entries == index.entries);
+    }
+
+    #[test]
+    fn ignores_missing_and_unreadable_indexes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join(".backup_db.index");
+        assert!(SnapshotIndex::load(path.as_path()).unwrap().is_none());
+
+        std::fs::write(path.as_path(), "salvage-index 1\nfull.tar.gz\n1\n").unwrap();
+        assert!(SnapshotIndex::load(path.as_path()).unwrap().is_none());
+
+        std::fs::write(
+            path.as_path(),
+            format!(
+                "{}\nfull.tar.gz\n1\nlast.tar.gz\n1\tx\t2\t3\tpath\n",
+                INDEX_HEADER
+            ),
+        )
+        .unwrap();
+        assert!(SnapshotIndex::load(path.as_path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn walks_the_chain_back_to_the_full_archive() {
+        let directory = tempfile::tempdir().unwrap();
+        let names = [
+            "backup_db_2024-01-01_00-00-00.tar.gz",
+            "backup_db_2024-01-02_00-00-00.incr.tar.gz",
+            "backup_db_2024-01-03_00-00-00.tar.gz",
+            "backup_db_2024-01-04_00-00-00.incr.tar.gz",
+            "backup_db_2024-01-05_00-00-00.incr.tar.gz",
+            "backup_other_2024-01-04_00-00-00.tar.gz",
+        ];
+        for name in names {
+            std::fs::write(directory.path().join(name), "").unwrap();
+        }
+
+        let chain = incremental_chain(directory.path().join(names[4]).as_path(), "backup").unwrap();
+        assert_eq!(
+            chain,
+            [names[2], names[3], names[4]].map(|n| directory.path().join(n))
+        );
+        let chain = incremental_chain(directory.path().join(names[1]).as_path(), "backup").unwrap();
+        assert_eq!(
+            chain,
+            [names[0], names[1]].map(|n| directory.path().join(n))
+        );
+    }
+
+    #[test]
+    fn reports_a_missing_full_archive() {
+        let directory = tempfile::tempdir().unwrap();
+        let name = "backup_db_2024-01-02_00-00-00.incr.tar.gz";
+        std::fs::write(directory.path().join(name), "").unwrap();
+        assert!(matches!(
+            incremental_chain(directory.path().join(name).as_path(), "backup"),
+            Err(MissingFullArchive(_))
+        ));
+    }
+}
@@ -1,36 +1,84 @@
-use crate::configuration::{validate_config, ArchiveCompression, ArchiveStrategy, Configuration};
-use crate::docker::{post_archive_container_processing, pre_archive_container_processing};
+use crate::catalog::{list_catalog, Catalog, CatalogArchive, CatalogRun};
+use crate::checksum::{write_sidecar, ChecksumWriter};
+use crate::cli::{Cli, Command};
+use crate::compression::ParallelGzEncoder;
+use crate::configuration::{
+    validate_config, ArchiveCompression, ArchiveStrategy, Configuration, Settings,
+};
+use crate::docker::RestartGuard;
+use crate::dry_run::dry_run;
+use crate::encryption::{Encryption, EncryptionWriter};
 use crate::error::Error;
+use crate::error::Error::{PostExecFailed, VolumeFailed, VolumesSkipped};
+use crate::exclude::{append_volume, VolumeFilter};
+use crate::incremental::{incremental_archive, PendingIndex};
+use crate::metrics::{record_run, serve_metrics};
+use crate::notification::{notify, ArchiveSummary, RunSummary};
+use crate::repository::{prune_repository, repository_archive, run_repository_command};
+use crate::restore::restore;
+use crate::retention::apply_retention;
+use crate::scheduler::run_schedule;
+use crate::storage::{apply_remote_retention, upload_archives};
+use crate::verify::verify;
+use bzip2::read::BzDecoder;
 use bzip2::write::BzEncoder;
+use chrono::{Local, SecondsFormat};
+use clap::Parser;
+use flate2::read::MultiGzDecoder;
 use flate2::write::GzEncoder;
-use log::{debug, error, info, LevelFilter};
-use std::collections::HashSet;
+use log::{debug, error, info, trace, warn, LevelFilter};
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
 use time::macros::format_description;
 use time::OffsetDateTime;
+use tokio::runtime::Runtime;
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, MtStreamBuilder};
 use xz2::write::XzEncoder;
-use zstd::Encoder as ZstdEncoder;
+use zstd::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
 
+mod catalog;
+mod checksum;
+mod cli;
+mod compression;
 mod configuration;
 mod docker;
+mod dry_run;
+mod encryption;
 mod error;
+mod exclude;
+mod incremental;
+mod metrics;
+mod notification;
+mod repository;
+mod restore;
+mod retention;
+mod scheduler;
+mod signals;
+mod storage;
+mod verify;
 
 const LOG_TARGET: &str = "salvage";
 const TIMESTAMP_FORMAT: &[time::format_description::FormatItem<'_>] =
     format_description!("[year]-[month]-[day]_[hour]-[minute]-[second]");
 
+/// Extension of an archive that is still being written
+const PARTIAL_EXTENSION: &str = "partial";
+
 // Default Paths
 const ARCHIVE_DIR: &str = "/archive";
 const DATA_DIR: &str = "/data";
 
 // Environment Variable Names
+const CONFIG_ENV: &str = "SALVAGE_CONFIG";
 const BACKUP_DIR_ENV: &str = "SALVAGE_BACKUP_DIR";
 const DATA_DIR_ENV: &str = "SALVAGE_DATA_DIR";
 const LOG_LEVEL: &str = "SALVAGE_LOG_LEVEL";
@@ -38,11 +86,65 @@ const STRATEGY_ENV: &str = "SALVAGE_ARCHIVE_STRATEGY";
 const PREFIX_ENV: &str = "SALVAGE_ARCHIVE_PREFIX";
 const COMPRESSION_ENV: &str = "SALVAGE_ARCHIVE_COMPRESSION";
 const COMPRESSION_LEVEL_ENV: &str = "SALVAGE_ARCHIVE_COMPRESSION_LEVEL";
+const COMPRESSION_THREADS_ENV: &str = "SALVAGE_ARCHIVE_COMPRESSION_THREADS";
+const INCREMENTAL_FULL_EVERY_ENV: &str = "SALVAGE_INCREMENTAL_FULL_EVERY";
+const ARCHIVE_WORKERS_ENV: &str = "SALVAGE_ARCHIVE_WORKERS";
+const EXCLUDE_ENV: &str = "SALVAGE_EXCLUDE";
+const VOLUME_EXCLUDE_ENV: &str = "SALVAGE_VOLUME_EXCLUDE";
+const REPOSITORY_DIR_ENV: &str = "SALVAGE_REPOSITORY_DIR";
 const GROUP_PERMISSION_ENV: &str = "SALVAGE_ARCHIVE_GROUP_PERMISSION";
 const OTHER_PERMISSION_ENV: &str = "SALVAGE_ARCHIVE_OTHER_PERMISSION";
 const SALVAGE_CONTAINER_MANAGEMENT_ENV: &str = "SALVAGE_CONTAINER_MANAGEMENT";
+const QUIESCE_MODE_ENV: &str = "SALVAGE_QUIESCE_MODE";
 const SALVAGE_RUN_ONCE_ENV: &str = "SALVAGE_RUN_ONCE";
 const SALVAGE_IS_DOCKER: &str = "SALVAGE_IS_DOCKER";
+const SCHEDULE_ENV: &str = "SCHEDULE";
+const ENCRYPTION_RECIPIENTS_ENV: &str = "SALVAGE_ENCRYPTION_RECIPIENTS";
+const ENCRYPTION_RECIPIENTS_FILE_ENV: &str = "SALVAGE_ENCRYPTION_RECIPIENTS_FILE";
+const ENCRYPTION_IDENTITY_FILE_ENV: &str = "SALVAGE_ENCRYPTION_IDENTITY_FILE";
+const STORAGE_DELETE_LOCAL_ENV: &str = "SALVAGE_STORAGE_DELETE_LOCAL";
+const S3_BUCKET_ENV: &str = "SALVAGE_S3_BUCKET";
+const S3_PREFIX_ENV: &str = "SALVAGE_S3_PREFIX";
+const S3_ENDPOINT_ENV: &str = "SALVAGE_S3_ENDPOINT";
+const S3_REGION_ENV: &str = "SALVAGE_S3_REGION";
+const S3_ACCESS_KEY_ID_ENV: &str = "SALVAGE_S3_ACCESS_KEY_ID";
+const S3_SECRET_ACCESS_KEY_ENV: &str = "SALVAGE_S3_SECRET_ACCESS_KEY";
+const S3_PATH_STYLE_ENV: &str = "SALVAGE_S3_PATH_STYLE";
+const S3_PART_SIZE_ENV: &str = "SALVAGE_S3_PART_SIZE";
+const SFTP_HOST_ENV: &str = "SALVAGE_SFTP_HOST";
+const SFTP_PORT_ENV: &str = "SALVAGE_SFTP_PORT";
+const SFTP_USERNAME_ENV: &str = "SALVAGE_SFTP_USERNAME";
+const SFTP_PRIVATE_KEY_ENV: &str = "SALVAGE_SFTP_PRIVATE_KEY";
+const SFTP_PRIVATE_KEY_PASSPHRASE_ENV: &str = "SALVAGE_SFTP_PRIVATE_KEY_PASSPHRASE";
+const SFTP_HOST_KEY_ENV: &str = "SALVAGE_SFTP_HOST_KEY";
+const SFTP_REMOTE_DIR_ENV: &str = "SALVAGE_SFTP_REMOTE_DIR";
+const RETENTION_KEEP_LAST_ENV: &str = "SALVAGE_RETENTION_KEEP_LAST";
+const RETENTION_KEEP_DAILY_ENV: &str = "SALVAGE_RETENTION_KEEP_DAILY";
+const RETENTION_KEEP_WEEKLY_ENV: &str = "SALVAGE_RETENTION_KEEP_WEEKLY";
+const RETENTION_KEEP_MONTHLY_ENV: &str = "SALVAGE_RETENTION_KEEP_MONTHLY";
+const RETENTION_KEEP_YEARLY_ENV: &str = "SALVAGE_RETENTION_KEEP_YEARLY";
+const RETENTION_MAX_AGE_ENV: &str = "SALVAGE_RETENTION_MAX_AGE";
+const RETENTION_DRY_RUN_ENV: &str = "SALVAGE_RETENTION_DRY_RUN";
+const NOTIFY_ON_ENV: &str = "SALVAGE_NOTIFY_ON";
+const NOTIFY_WEBHOOK_URL_ENV: &str = "SALVAGE_NOTIFY_WEBHOOK_URL";
+const NOTIFY_WEBHOOK_ON_ENV: &str = "SALVAGE_NOTIFY_WEBHOOK_ON";
+const NOTIFY_NTFY_URL_ENV: &str = "SALVAGE_NOTIFY_NTFY_URL";
+const NOTIFY_NTFY_TOKEN_ENV: &str = "SALVAGE_NOTIFY_NTFY_TOKEN";
+const NOTIFY_NTFY_ON_ENV: &str = "SALVAGE_NOTIFY_NTFY_ON";
+const NOTIFY_GOTIFY_URL_ENV: &str = "SALVAGE_NOTIFY_GOTIFY_URL";
+const NOTIFY_GOTIFY_TOKEN_ENV: &str = "SALVAGE_NOTIFY_GOTIFY_TOKEN";
+const NOTIFY_GOTIFY_ON_ENV: &str = "SALVAGE_NOTIFY_GOTIFY_ON";
+const NOTIFY_SMTP_HOST_ENV: &str = "SALVAGE_NOTIFY_SMTP_HOST";
+const NOTIFY_SMTP_PORT_ENV: &str = "SALVAGE_NOTIFY_SMTP_PORT";
+const NOTIFY_SMTP_SECURITY_ENV: &str = "SALVAGE_NOTIFY_SMTP_SECURITY";
+const NOTIFY_SMTP_USERNAME_ENV: &str = "SALVAGE_NOTIFY_SMTP_USERNAME";
+const NOTIFY_SMTP_PASSWORD_ENV: &str = "SALVAGE_NOTIFY_SMTP_PASSWORD";
+const NOTIFY_SMTP_FROM_ENV: &str = "SALVAGE_NOTIFY_SMTP_FROM";
+const NOTIFY_SMTP_TO_ENV: &str = "SALVAGE_NOTIFY_SMTP_TO";
+const NOTIFY_SMTP_CA_FILE_ENV: &str = "SALVAGE_NOTIFY_SMTP_CA_FILE";
+const NOTIFY_SMTP_ON_ENV: &str = "SALVAGE_NOTIFY_SMTP_ON";
+const METRICS_ADDRESS_ENV: &str = "SALVAGE_METRICS_ADDRESS";
+const METRICS_TEXTFILE_DIR_ENV: &str = "SALVAGE_METRICS_TEXTFILE_DIR";
 
 // Docker Labels
 const SALVAGE_LABEL: &str = "ca.wheelans.salvage";
@@ -64,31 +166,107 @@ fn main() -> ExitCode {
 
     if let Err(error) = run() {
         error!(target: LOG_TARGET, "{}", error);
-        return ExitCode::FAILURE;
+        return ExitCode::from(error.exit_code());
     }
     debug!(target: LOG_TARGET, "Function main ended successfully");
     ExitCode::SUCCESS
 }
 
 fn run() -> Result<(), Error> {
-    let args: HashSet<String> = env::args().collect();
-    let config = validate_config()?;
-
-    if args.contains("-v") || args.contains("--validate") {
-        info!(target: LOG_TARGET, "Input Data Directory: {}", config.data_dir.to_string_lossy());
-        info!(target: LOG_TARGET, "Archive Directory: {}", config.backup_dir.to_string_lossy());
-        info!(target: LOG_TARGET, "Archive Compression: {}", config.archive_compression.to_string());
-        info!(target: LOG_TARGET, "Archive Compression Level: {}", config.archive_compression_level);
-        info!(target: LOG_TARGET, "Archive Strategy: {}", config.archive_strategy.to_string());
-        info!(target: LOG_TARGET, "Archive Prefix: {}", config.archive_prefix.as_str());
-        info!(target: LOG_TARGET, "Archive Group Permission: {}", config.group_permission.to_string());
-        info!(target: LOG_TARGET, "Archive Other Permission: {}", config.other_permission.to_string());
-        info!(target: LOG_TARGET, "Container Management Flag: {}", config.stop_containers);
-        info!(target: LOG_TARGET, "Is Docker: {}", config.is_docker);
-        info!(target: LOG_TARGET, "Run Once: {}", config.run_once);
-        info!(target: LOG_TARGET, "Configuration validated successfully.");
-    } else {
-        archive(config)?;
+    let cli = Cli::parse();
+    let settings = cli.settings()?;
+    let config = validate_config(&settings)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    match cli.command {
+        Some(Command::Backup(options)) if options.dry_run => dry_run(&config, &runtime)?,
+        Some(Command::Backup(_)) => run_once(&config, &runtime)?,
+        Some(Command::Restore(options)) => restore(&config, &runtime, options)?,
+        Some(Command::List) => list_catalog(&config)?,
+        Some(Command::Verify(options)) => verify(&config, options)?,
+        Some(Command::Prune(_)) => apply_retention_policy(&config, config.retention.dry_run)?,
+        Some(Command::Validate) => log_configuration(&settings, &config),
+        Some(Command::Schedule(_)) => schedule(&config, &runtime)?,
+        Some(Command::Repository { command }) => {
+            run_repository_command(&config, &runtime, command)?
+        }
+        None if config.run_once => run_once(&config, &runtime)?,
+        None => schedule(&config, &runtime)?,
+    }
+    Ok(())
+}
+
+/// Archive once and exit
+fn run_once(config: &Configuration, runtime: &Runtime) -> Result<(), Error> {
+    // Wait to ensure container status is running
+    if config.is_docker {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    if config.metrics.address.is_some() {
+        warn!(target: LOG_TARGET, "Metrics are only served when running on a schedule. Use {} with cron instead.", METRICS_TEXTFILE_DIR_ENV);
+    }
+    archive(config, runtime)
+}
+
+/// Serve metrics when enabled and archive each time the schedule fires
+fn schedule(config: &Configuration, runtime: &Runtime) -> Result<(), Error> {
+    if let Some(address) = config.metrics.address.as_deref() {
+        serve_metrics(address)?;
+    }
+    run_schedule(config, runtime)
+}
+
+/// Log every setting of the validated configuration
+fn log_configuration(settings: &Settings, config: &Configuration) {
+    info!(target: LOG_TARGET, "Configuration File: {}", settings.file_path().map(|p| p.to_string_lossy()).unwrap_or("Disabled".into()));
+    info!(target: LOG_TARGET, "Input Data Directory: {}", config.data_dir.to_string_lossy());
+    info!(target: LOG_TARGET, "Archive Directory: {}", config.backup_dir.to_string_lossy());
+    info!(target: LOG_TARGET, "Archive Compression: {}", config.archive_compression.to_string());
+    info!(target: LOG_TARGET, "Archive Compression Level: {}", config.archive_compression_level);
+    info!(target: LOG_TARGET, "Archive Compression Threads: {}", config.archive_compression_threads);
+    info!(target: LOG_TARGET, "Archive Strategy: {}", config.archive_strategy.to_string());
+    info!(target: LOG_TARGET, "Archive Workers: {}", config.archive_workers);
+    info!(target: LOG_TARGET, "Incremental Full Every: {}", config.incremental_full_every);
+    info!(target: LOG_TARGET, "Exclude Patterns: {}", config.exclude.patterns.len());
+    info!(target: LOG_TARGET, "Volume Exclude Patterns: {}", config.exclude.volume_patterns.len());
+    info!(target: LOG_TARGET, "Repository Directory: {}", config.repository_dir.to_string_lossy());
+    info!(target: LOG_TARGET, "Archive Prefix: {}", config.archive_prefix.as_str());
+    info!(target: LOG_TARGET, "Archive Group Permission: {}", config.group_permission.to_string());
+    info!(target: LOG_TARGET, "Archive Other Permission: {}", config.other_permission.to_string());
+    info!(target: LOG_TARGET, "Container Management Flag: {}", config.stop_containers);
+    info!(target: LOG_TARGET, "Quiesce Mode: {}", config.quiesce_mode);
+    info!(target: LOG_TARGET, "Is Docker: {}", config.is_docker);
+    info!(target: LOG_TARGET, "Run Once: {}", config.run_once);
+    info!(target: LOG_TARGET, "Schedule: {}", config.schedule);
+    info!(target: LOG_TARGET, "Encryption Recipients: {}", config.encryption.recipients.len());
+    info!(target: LOG_TARGET, "S3 Bucket: {}", display_option(config.storage.s3.as_ref().map(|s| s.bucket.as_str())));
+    info!(target: LOG_TARGET, "SFTP Host: {}", display_option(config.storage.sftp.as_ref().map(|s| s.host.as_str())));
+    info!(target: LOG_TARGET, "Storage Delete Local: {}", config.storage.delete_local);
+    info!(target: LOG_TARGET, "Notify Webhook: {}", display_option(config.notification.webhook.as_ref().map(|n| n.on)));
+    info!(target: LOG_TARGET, "Notify ntfy: {}", display_option(config.notification.ntfy.as_ref().map(|n| n.on)));
+    info!(target: LOG_TARGET, "Notify Gotify: {}", display_option(config.notification.gotify.as_ref().map(|n| n.on)));
+    info!(target: LOG_TARGET, "Metrics Address: {}", display_option(config.metrics.address.as_deref()));
+    info!(target: LOG_TARGET, "Metrics Textfile Directory: {}", display_option(config.metrics.textfile_dir.as_ref().map(|p| p.to_string_lossy())));
+    info!(target: LOG_TARGET, "Notify SMTP Host: {}", display_option(config.notification.smtp.as_ref().map(|s| format!("{}:{} ({}, {})", s.host, s.port, s.security, s.on))));
+    info!(target: LOG_TARGET, "Encryption Identity File: {}", display_option(config.encryption.identity_file.as_ref().map(|p| p.to_string_lossy())));
+    info!(target: LOG_TARGET, "Retention Keep Last: {}", display_option(config.retention.keep_last));
+    info!(target: LOG_TARGET, "Retention Keep Daily: {}", display_option(config.retention.keep_daily));
+    info!(target: LOG_TARGET, "Retention Keep Weekly: {}", display_option(config.retention.keep_weekly));
+    info!(target: LOG_TARGET, "Retention Keep Monthly: {}", display_option(config.retention.keep_monthly));
+    info!(target: LOG_TARGET, "Retention Keep Yearly: {}", display_option(config.retention.keep_yearly));
+    info!(target: LOG_TARGET, "Retention Max Age (days): {}", display_option(config.retention.max_age_days));
+    info!(target: LOG_TARGET, "Retention Dry Run: {}", config.retention.dry_run);
+    info!(target: LOG_TARGET, "Configuration validated successfully.");
+}
+
+/// Apply the retention policy to the local archives, the storage backends and the repository
+fn apply_retention_policy(config: &Configuration, dry_run: bool) -> Result<(), Error> {
+    apply_retention(config, dry_run)?;
+    apply_remote_retention(config, dry_run)?;
+    if matches!(config.archive_strategy, ArchiveStrategy::Repository) {
+        prune_repository(config, dry_run)?;
     }
     Ok(())
 }
@@ -98,13 +276,8 @@ fn set_logging_level() -> LevelFilter {
         .unwrap_or(LevelFilter::Info)
 }
 
-fn archive(config: Configuration) -> Result<(), Error> {
-    let start_time = Instant::now();
-    info!(target: LOG_TARGET, "Archive process started");
-    let runtime = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()?;
-
+/// Find the directories in the data directory to be archived, as pairs of volume name and path
+fn volume_directories(config: &Configuration) -> Result<Vec<(OsString, PathBuf)>, Error> {
     // Get paths of all directories to be archived
     let backup_paths: Vec<_> = std::fs::read_dir(config.data_dir.as_path())?
         .map(|r| r.map(|e| e.path()))
@@ -117,31 +290,248 @@ fn archive(config: Configuration) -> Result<(), Error> {
     }
 
     // Get vector of directory name and path pairs
-    let backup_paths: Vec<(_, _)> = backup_paths
+    Ok(backup_paths
         .iter()
         .filter(|p| p.as_path().file_name().is_some())
         .map(|f| (f.file_name().unwrap().to_os_string(), f.to_path_buf()))
+        .collect())
+}
+
+/// Run the archive process and send the summary of the run to the configured notifiers
+fn archive(config: &Configuration, runtime: &Runtime) -> Result<(), Error> {
+    let mut summary = RunSummary::start(config);
+    let result = archive_volumes(config, runtime, &mut summary);
+    summary.finish(result.as_ref().err());
+    record_run(config, &summary);
+    notify(config, &summary);
+    result
+}
+
+fn archive_volumes(
+    config: &Configuration,
+    runtime: &Runtime,
+    summary: &mut RunSummary,
+) -> Result<(), Error> {
+    let start_time = Instant::now();
+    info!(target: LOG_TARGET, "Archive process started");
+    remove_partial_archives(config)?;
+
+    let backup_paths = volume_directories(config)?;
+
+    // Run pre-exec hooks and stop or pause containers that contain volumes that are being archived
+    // up. The guard resumes them again however archiving ends, including on SIGINT or SIGTERM.
+    let mut restart_guard = RestartGuard::new(runtime, config.container_management_enabled())?;
+    if let Err(error) = restart_guard.pre_archive(config) {
+        // Run the post-exec hooks and resume the containers handled before the failure
+        let result = restart_guard.finish(Err(error));
+        summary.containers_stopped = restart_guard.stopped().to_vec();
+        summary.containers_paused = restart_guard.paused().to_vec();
+        summary.containers_restarted = restart_guard.restarted().to_vec();
+        summary.containers_unpaused = restart_guard.unpaused().to_vec();
+        return result;
+    }
+
+    // Skip volumes whose pre-exec hook failed
+    let failed_paths = restart_guard.failed_paths();
+    let (skipped, backup_paths): (Vec<_>, Vec<_>) =
+        backup_paths.into_iter().partition(|(_, path)| {
+            failed_paths
+                .iter()
+                .any(|f| f.starts_with(path) || path.starts_with(f))
+        });
+    for (name, _) in skipped.iter() {
+        error!(target: LOG_TARGET, "Skipping volume {} because a pre-exec hook failed", name.to_string_lossy());
+    }
+    let volumes = backup_paths.clone();
+
+    // Archives based on selected strategy. Archives are collected as they are finished, so those
+    // written before a failure are still cataloged and uploaded.
+    let run_id = timestamp()?;
+    let mut archives = Vec::new();
+    let result = match config.archive_strategy {
+        ArchiveStrategy::Single => {
+            single_archive(backup_paths, config, run_id.as_str(), &mut archives)
+        }
+        ArchiveStrategy::Multiple => {
+            multiple_archive(backup_paths, config, run_id.as_str(), &mut archives)
+        }
+        ArchiveStrategy::Incremental => {
+            incremental_archive(backup_paths, config, run_id.as_str(), &mut archives)
+        }
+        ArchiveStrategy::Repository => {
+            repository_archive(backup_paths, config, run_id.as_str(), &mut archives)
+        }
+    };
+
+    // Start or unpause containers that were stopped or paused for archiving.
+    let mut result = restart_guard.finish(result);
+    summary.containers_stopped = restart_guard.stopped().to_vec();
+    summary.containers_paused = restart_guard.paused().to_vec();
+    summary.containers_restarted = restart_guard.restarted().to_vec();
+    summary.containers_unpaused = restart_guard.unpaused().to_vec();
+    summary.archives = archives.iter().map(ArchiveSummary::from).collect();
+    if !archives.is_empty() {
+        result = first_error(
+            result,
+            record_archives(config, &restart_guard, summary, run_id, &archives),
+        );
+        // Copy archives to the storage backends. Repository snapshots stay in the repository.
+        if !matches!(config.archive_strategy, ArchiveStrategy::Repository) {
+            let paths: Vec<_> = archives.into_iter().map(|a| a.path).collect();
+            result = first_error(result, upload_archives(config, paths.as_slice()));
+        }
+    }
+    // Old archives are only removed once every volume has been archived
+    result?;
+
+    // Remove archives that fall outside the retention policy
+    apply_retention_policy(config, config.retention.dry_run)?;
+
+    info!(target: LOG_TARGET, "Archive process finished after {} milliseconds", start_time.elapsed().as_millis());
+    // Volumes whose hooks failed are reported once every other volume has been handled
+    let hook_failed: Vec<_> = volumes
+        .iter()
+        .filter(|(_, path)| {
+            restart_guard
+                .hook_failed_paths()
+                .iter()
+                .any(|f| f.starts_with(path) || path.starts_with(f))
+        })
+        .map(|(name, _)| name.to_string_lossy())
+        .collect();
+    let skipped: Vec<_> = skipped
+        .iter()
+        .map(|(name, _)| name.to_string_lossy())
         .collect();
+    first_error(
+        match skipped.is_empty() {
+            true => Ok(()),
+            false => Err(VolumesSkipped(skipped.join(", "))),
+        },
+        match hook_failed.is_empty() {
+            true => Ok(()),
+            false => Err(PostExecFailed(hook_failed.join(", "))),
+        },
+    )
+}
 
-    // Stop containers that contain volumes that are being archived up
-    let pre_archive = match config.container_management_enabled() {
-        true => Some(runtime.block_on(pre_archive_container_processing(&config))?),
-        false => None,
+/// Record the run in the catalog before retention reads it, then save the snapshot indexes of the
+/// incremental archives so the next run only builds on archives the catalog knows about
+fn record_archives(
+    config: &Configuration,
+    restart_guard: &RestartGuard,
+    summary: &RunSummary,
+    run_id: String,
+    archives: &[WrittenArchive],
+) -> Result<(), Error> {
+    let run = CatalogRun {
+        run_id,
+        started_at: summary.started_at.clone(),
+        finished_at: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+        strategy: config.archive_strategy.to_string(),
+        compression: config.archive_compression.to_string(),
+        compression_level: config.archive_compression_level,
+        encrypted: config.encryption.is_enabled(),
+        archives: archives
+            .iter()
+            .map(|archive| {
+                let container_ids = match archive.volume.as_ref() {
+                    Some(volume) => {
+                        restart_guard.quiesced_sharing(config.data_dir.join(volume).as_path())
+                    }
+                    None => [restart_guard.stopped(), restart_guard.paused()].concat(),
+                };
+                archive.catalog_entry(container_ids)
+            })
+            .collect(),
     };
+    Catalog::record(config, &run)?;
+    archives
+        .iter()
+        .filter_map(|a| a.index.as_ref())
+        .try_for_each(|index| index.save())
+}
+
+/// Keep the error of the earlier step, logging the error of the later step so it is not lost
+fn first_error(result: Result<(), Error>, next: Result<(), Error>) -> Result<(), Error> {
+    match (result, next) {
+        (Err(error), Err(next)) => {
+            error!(target: LOG_TARGET, "{}", next);
+            Err(error)
+        }
+        (Ok(()), next) => next,
+        (result, Ok(())) => result,
+    }
+}
+
+fn display_option<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "Disabled".to_string())
+}
 
-    // Archives based on selected strategy
-    match config.archive_strategy {
-        ArchiveStrategy::Single => single_archive(backup_paths, &config)?,
-        ArchiveStrategy::Multiple => multiple_archive(backup_paths, &config)?,
+/// An archive, or repository snapshot, written for one run of the archive process
+pub struct WrittenArchive {
+    pub path: PathBuf,
+    /// Volume in the archive, or `None` when the archive contains every volume
+    pub volume: Option<String>,
+    /// Size of the archive, or of the new data stored for a repository snapshot
+    pub size: u64,
+    pub duration: Duration,
+    /// Number of files, directories and links in the archive
+    pub file_count: u64,
+    /// Hex encoded SHA-256 digest of the archive file
+    pub checksum: Option<String>,
+    /// Name of the full archive an incremental archive builds on
+    pub base: Option<String>,
+    /// Name of the archive an incremental archive builds on directly
+    pub parent: Option<String>,
+    /// Snapshot index to save once the archive is in the catalog
+    pub index: Option<PendingIndex>,
+}
+
+impl WrittenArchive {
+    /// Describe an archive file that has been finished
+    fn new(
+        path: PathBuf,
+        volume: Option<String>,
+        file_count: u64,
+        checksum: String,
+        start_time: Instant,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            size: std::fs::metadata(path.as_path())?.len(),
+            path,
+            volume,
+            duration: start_time.elapsed(),
+            file_count,
+            checksum: Some(checksum),
+            base: None,
+            parent: None,
+            index: None,
+        })
     }
 
-    // Start containers that were stopped for archiving.
-    if config.container_management_enabled() {
-        runtime.block_on(post_archive_container_processing(pre_archive))?;
+    fn catalog_entry(&self, container_ids: Vec<String>) -> CatalogArchive {
+        CatalogArchive {
+            path: self.path.clone(),
+            volume: self.volume.clone(),
+            size: self.size,
+            file_count: self.file_count,
+            checksum: self.checksum.clone(),
+            incremental: self.base.is_some(),
+            base: self.base.clone(),
+            parent: self.parent.clone(),
+            container_ids,
+        }
     }
 
-    info!(target: LOG_TARGET, "Archive process finished after {} milliseconds", start_time.elapsed().as_millis());
-    Ok(())
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
 }
 
 fn timestamp() -> Result<String, Error> {
@@ -152,72 +542,282 @@ fn timestamp() -> Result<String, Error> {
 fn single_archive(
     directories: Vec<(OsString, PathBuf)>,
     config: &Configuration,
+    timestamp: &str,
+    written: &mut Vec<WrittenArchive>,
 ) -> Result<(), Error> {
     let start_time = Instant::now();
-    let timestamp = timestamp()?;
     let archive_name = format!(
-        "{}_{}.tar.{}",
+        "{}_{}.{}",
         config.archive_prefix,
         timestamp,
-        config.archive_compression.extension()
+        config.archive_extension()
     );
     let archive_path = config.backup_dir.as_path().join(archive_name.as_str());
     let compressor = select_encoder(
         archive_path.as_path(),
         &config.archive_compression,
         config.archive_compression_level,
+        config.archive_compression_threads,
+        &config.encryption,
     )?;
     let mut tar = tar::Builder::new(compressor);
 
+    let mut file_count = 0;
     for (name, path) in directories {
-        tar.append_dir_all(name, path)?;
+        let mut filter = VolumeFilter::new(
+            &config.exclude,
+            name.to_string_lossy().as_ref(),
+            path.as_path(),
+        )?;
+        file_count += append_volume(&mut tar, Path::new(&name), path.as_path(), &mut filter)?;
     }
-    std::fs::set_permissions(archive_path.as_path(), config.archive_permission())?;
+    let digest = finish_archive(tar, archive_path.as_path(), config)?;
     debug!(target: LOG_TARGET, "Archive {} took {} milliseconds", archive_name, start_time.elapsed().as_millis());
+    written.push(WrittenArchive::new(
+        archive_path,
+        None,
+        file_count,
+        digest,
+        start_time,
+    )?);
     Ok(())
 }
 
+/// Archive each directory into its own archive, with up to the configured number of workers
+/// archiving volumes at the same time. Once a volume fails no further volumes are started, while
+/// the archives already finished are still added to `written`.
 fn multiple_archive(
     directories: Vec<(OsString, PathBuf)>,
     config: &Configuration,
+    timestamp: &str,
+    written: &mut Vec<WrittenArchive>,
 ) -> Result<(), Error> {
-    let timestamp = timestamp()?;
-    for (name, path) in directories {
-        let start_time = Instant::now();
-        let archive_name = format!(
-            "{}_{}_{}.tar.{}",
-            config.archive_prefix,
-            name.to_string_lossy(),
-            timestamp,
-            config.archive_compression.extension()
-        );
-        let archive_path = config.backup_dir.as_path().join(archive_name.as_str());
-        let compressor = select_encoder(
-            archive_path.as_path(),
-            &config.archive_compression,
-            config.archive_compression_level,
-        )?;
-        let mut tar = tar::Builder::new(compressor);
-        tar.append_dir_all(name, path)?;
-        tar.finish()?;
-        std::fs::set_permissions(archive_path.as_path(), config.archive_permission())?;
-        debug!(target: LOG_TARGET, "Archive {} took {} milliseconds", archive_name, start_time.elapsed().as_millis());
+    let workers = (config.archive_workers as usize).clamp(1, directories.len().max(1));
+    debug!(target: LOG_TARGET, "Archiving {} volumes with {} workers", directories.len(), workers);
+    let queue = Mutex::new(directories.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+
+    std::thread::scope(|scope| {
+        for worker in 0..workers {
+            std::thread::Builder::new()
+                .name(format!("salvage-worker-{}", worker))
+                .spawn_scoped(scope, || {
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((index, (name, path))) = lock(&queue).next() else {
+                            break;
+                        };
+                        let volume = name.to_string_lossy().to_string();
+                        let result = archive_volume(name, path, config, timestamp)
+                            .map_err(|e| VolumeFailed(volume, Box::new(e)));
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+                        lock(&results).push((index, result));
+                    }
+                })?;
+        }
+        Ok::<(), Error>(())
+    })?;
+
+    let mut results = results.into_inner().unwrap_or_else(PoisonError::into_inner);
+    results.sort_by_key(|(index, _)| *index);
+    let mut first_error = None;
+    for (_, result) in results {
+        match result {
+            Ok(archive) => written.push(archive),
+            Err(error) if first_error.is_none() => first_error = Some(error),
+            Err(error) => error!(target: LOG_TARGET, "{}", error),
+        }
+    }
+    match first_error {
+        None => Ok(()),
+        Some(error) => Err(error),
+    }
+}
+
+fn archive_volume(
+    name: OsString,
+    path: PathBuf,
+    config: &Configuration,
+    timestamp: &str,
+) -> Result<WrittenArchive, Error> {
+    let start_time = Instant::now();
+    let archive_name = format!(
+        "{}_{}_{}.{}",
+        config.archive_prefix,
+        name.to_string_lossy(),
+        timestamp,
+        config.archive_extension()
+    );
+    debug!(target: LOG_TARGET, "Archiving volume {} into {}", name.to_string_lossy(), archive_name);
+    let archive_path = config.backup_dir.as_path().join(archive_name.as_str());
+    let compressor = select_encoder(
+        archive_path.as_path(),
+        &config.archive_compression,
+        config.archive_compression_level,
+        config.archive_compression_threads,
+        &config.encryption,
+    )?;
+    let mut tar = tar::Builder::new(compressor);
+    let mut filter = VolumeFilter::new(
+        &config.exclude,
+        name.to_string_lossy().as_ref(),
+        path.as_path(),
+    )?;
+    let file_count = append_volume(&mut tar, Path::new(&name), path.as_path(), &mut filter)?;
+    let digest = finish_archive(tar, archive_path.as_path(), config)?;
+    debug!(target: LOG_TARGET, "Archive {} took {} milliseconds", archive_name, start_time.elapsed().as_millis());
+    let volume = name.to_string_lossy().to_string();
+    WrittenArchive::new(archive_path, Some(volume), file_count, digest, start_time)
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Finish writing the tarball, compression and encryption streams to the partial file, sync it to
+/// disk, set its permissions and rename it into place. Then write the checksum sidecar and return the
+/// SHA-256 digest of the archive.
+fn finish_archive(
+    tar: tar::Builder<ArchiveEncoder<EncryptionWriter<ChecksumWriter<File>>>>,
+    archive_path: &Path,
+    config: &Configuration,
+) -> Result<String, Error> {
+    let (file, digest) = tar.into_inner()?.finish()?.finish()?.finalize();
+    file.sync_all()?;
+    drop(file);
+    let partial = partial_path(archive_path);
+    std::fs::set_permissions(partial.as_path(), config.archive_permission())?;
+    std::fs::rename(partial.as_path(), archive_path)?;
+    if let Some(directory) = archive_path.parent() {
+        File::open(directory)?.sync_all()?;
     }
+    write_sidecar(archive_path, digest.as_str(), config.archive_permission())?;
+    trace!(target: LOG_TARGET, "Archive {} has SHA-256 {}", archive_path.to_string_lossy(), digest);
+    Ok(digest)
+}
 
+/// Hidden path an archive is written to until it is complete: `.{archive}.partial`
+fn partial_path(archive: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(archive.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    archive.with_file_name(name)
+}
+
+/// Remove partial archives left behind by a run that was killed or failed part way through
+fn remove_partial_archives(config: &Configuration) -> Result<(), Error> {
+    let prefix = format!(".{}_", config.archive_prefix);
+    let extension = format!(".{}", PARTIAL_EXTENSION);
+    for entry in std::fs::read_dir(config.backup_dir.as_path())? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with(prefix.as_str()) && name.ends_with(extension.as_str()) && path.is_file()
+        {
+            warn!(target: LOG_TARGET, "Removing partial archive {}", path.to_string_lossy());
+            std::fs::remove_file(path.as_path())?;
+        }
+    }
     Ok(())
 }
 
+/// Compression stream for an archive that can be explicitly finished to surface any errors
+enum ArchiveEncoder<W: Write> {
+    Bzip2(BzEncoder<W>),
+    Gzip(GzEncoder<W>),
+    ParallelGzip(ParallelGzEncoder<W>),
+    Xz(XzEncoder<W>),
+    Zstd(ZstdEncoder<'static, W>),
+}
+
+impl<W: Write> ArchiveEncoder<W> {
+    /// Write the end of the compression stream and return the inner writer
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            ArchiveEncoder::Bzip2(encoder) => encoder.finish(),
+            ArchiveEncoder::Gzip(encoder) => encoder.finish(),
+            ArchiveEncoder::ParallelGzip(encoder) => encoder.finish(),
+            ArchiveEncoder::Xz(encoder) => encoder.finish(),
+            ArchiveEncoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for ArchiveEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            ArchiveEncoder::Bzip2(encoder) => encoder.write(buf),
+            ArchiveEncoder::Gzip(encoder) => encoder.write(buf),
+            ArchiveEncoder::ParallelGzip(encoder) => encoder.write(buf),
+            ArchiveEncoder::Xz(encoder) => encoder.write(buf),
+            ArchiveEncoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            ArchiveEncoder::Bzip2(encoder) => encoder.flush(),
+            ArchiveEncoder::Gzip(encoder) => encoder.flush(),
+            ArchiveEncoder::ParallelGzip(encoder) => encoder.flush(),
+            ArchiveEncoder::Xz(encoder) => encoder.flush(),
+            ArchiveEncoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Create the compression stream for an archive. With more than one thread, zstd and xz use their
+/// multithreaded encoders and gzip compresses blocks in parallel, while bzip2 stays single threaded.
 fn select_encoder<P: AsRef<Path>>(
     path: P,
     compression: &ArchiveCompression,
     level: u32,
-) -> Result<Box<dyn Write>, Error> {
-    let file = File::create(path.as_ref())?;
-    let encoder: Box<dyn Write> = match compression {
-        ArchiveCompression::Bzip2 => Box::new(BzEncoder::new(file, bzip2::Compression::new(level))),
-        ArchiveCompression::Gzip => Box::new(GzEncoder::new(file, flate2::Compression::new(level))),
-        ArchiveCompression::Xz => Box::new(XzEncoder::new(file, level)),
-        ArchiveCompression::Zstd => Box::new(ZstdEncoder::new(file, level as i32)?.auto_finish()),
+    threads: u32,
+    encryption: &Encryption,
+) -> Result<ArchiveEncoder<EncryptionWriter<ChecksumWriter<File>>>, Error> {
+    let file = File::create(partial_path(path.as_ref()))?;
+    let file = encryption.wrap_writer(ChecksumWriter::new(file))?;
+    let encoder = match (compression, threads) {
+        (ArchiveCompression::Bzip2, _) => {
+            ArchiveEncoder::Bzip2(BzEncoder::new(file, bzip2::Compression::new(level)))
+        }
+        (ArchiveCompression::Gzip, 0 | 1) => {
+            ArchiveEncoder::Gzip(GzEncoder::new(file, flate2::Compression::new(level)))
+        }
+        (ArchiveCompression::Gzip, threads) => ArchiveEncoder::ParallelGzip(
+            ParallelGzEncoder::new(file, flate2::Compression::new(level), threads as usize)?,
+        ),
+        (ArchiveCompression::Xz, 0 | 1) => ArchiveEncoder::Xz(XzEncoder::new(file, level)),
+        (ArchiveCompression::Xz, threads) => {
+            let stream = MtStreamBuilder::new()
+                .preset(level)
+                .threads(threads)
+                .check(Check::Crc64)
+                .encoder()
+                .map_err(std::io::Error::from)?;
+            ArchiveEncoder::Xz(XzEncoder::new_stream(file, stream))
+        }
+        (ArchiveCompression::Zstd, threads) => {
+            let mut encoder = ZstdEncoder::new(file, level as i32)?;
+            if threads > 1 {
+                encoder.multithread(threads)?;
+            }
+            ArchiveEncoder::Zstd(encoder)
+        }
     };
     Ok(encoder)
 }
+
+fn select_decoder<R: Read + 'static>(
+    reader: R,
+    compression: &ArchiveCompression,
+) -> Result<Box<dyn Read>, Error> {
+    let decoder: Box<dyn Read> = match compression {
+        ArchiveCompression::Bzip2 => Box::new(BzDecoder::new(reader)),
+        ArchiveCompression::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        ArchiveCompression::Xz => Box::new(XzDecoder::new(reader)),
+        ArchiveCompression::Zstd => Box::new(ZstdDecoder::new(reader)?),
+    };
+    Ok(decoder)
+}
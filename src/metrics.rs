@@ -0,0 +1,303 @@
+use crate::configuration::Configuration;
+use crate::error::Error;
+use crate::error::Error::MetricsServer;
+use crate::notification::RunSummary;
+use crate::LOG_TARGET;
+use log::{debug, error, info, trace};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const METRICS_PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const TEXTFILE_EXTENSION: &str = "prom";
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+const LAST_SUCCESS: &str = "salvage_last_success_timestamp";
+const ARCHIVE_BYTES: &str = "salvage_archive_bytes";
+const ARCHIVE_DURATION: &str = "salvage_archive_duration_seconds";
+const CONTAINERS_STOPPED: &str = "salvage_containers_stopped";
+const CONTAINERS_PAUSED: &str = "salvage_containers_paused";
+const RUN_FAILURES: &str = "salvage_run_failures_total";
+/// Volume label of archives created by the `single` strategy, which contain every volume
+const ALL_VOLUMES: &str = "all";
+
+pub struct MetricsConfig {
+    /// Address to serve `/metrics` on while running on a schedule
+    pub address: Option<String>,
+    /// node_exporter textfile collector directory to write the metrics to after each run
+    pub textfile_dir: Option<PathBuf>,
+}
+
+/// Metrics of the archive runs made by this process. The scheduler and the metrics server run
+/// on different threads, so they are shared through a static.
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+struct Metrics {
+    last_success: Option<f64>,
+    archive_bytes: BTreeMap<String, u64>,
+    archive_duration: BTreeMap<String, f64>,
+    containers_stopped: usize,
+    containers_paused: usize,
+    run_failures: u64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            last_success: None,
+            archive_bytes: BTreeMap::new(),
+            archive_duration: BTreeMap::new(),
+            containers_stopped: 0,
+            containers_paused: 0,
+            run_failures: 0,
+        }
+    }
+
+    /// Render the metrics in the Prometheus text exposition format
+    fn render(&self) -> String {
+        let mut text = String::new();
+        let mut family =
+            |name: &str, kind: &str, help: &str, samples: Vec<(Option<&str>, String)>| {
+                if samples.is_empty() {
+                    return;
+                }
+                let _ = writeln!(text, "# HELP {} {}", name, help);
+                let _ = writeln!(text, "# TYPE {} {}", name, kind);
+                for (volume, value) in samples {
+                    let _ = match volume {
+                        Some(volume) => {
+                            writeln!(text, "{}{{volume=\"{}\"}} {}", name, escape(volume), value)
+                        }
+                        None => writeln!(text, "{} {}", name, value),
+                    };
+                }
+            };
+
+        family(
+            LAST_SUCCESS,
+            "gauge",
+            "Unix time of the last successful archive run.",
+            self.last_success
+                .iter()
+                .map(|t| (None, t.to_string()))
+                .collect(),
+        );
+        family(
+            ARCHIVE_BYTES,
+            "gauge",
+            "Size in bytes of the last archive of each volume.",
+            self.archive_bytes
+                .iter()
+                .map(|(v, b)| (Some(v.as_str()), b.to_string()))
+                .collect(),
+        );
+        family(
+            ARCHIVE_DURATION,
+            "gauge",
+            "Seconds taken to write the last archive of each volume.",
+            self.archive_duration
+                .iter()
+                .map(|(v, d)| (Some(v.as_str()), d.to_string()))
+                .collect(),
+        );
+        family(
+            CONTAINERS_STOPPED,
+            "gauge",
+            "Number of containers stopped during the last archive run.",
+            vec![(None, self.containers_stopped.to_string())],
+        );
+        family(
+            CONTAINERS_PAUSED,
+            "gauge",
+            "Number of containers paused during the last archive run.",
+            vec![(None, self.containers_paused.to_string())],
+        );
+        family(
+            RUN_FAILURES,
+            "counter",
+            "Number of archive runs that failed.",
+            vec![(None, self.run_failures.to_string())],
+        );
+        text
+    }
+}
+
+/// Record the outcome of an archive run and write the textfile when it is configured. Failures to
+/// write the textfile are logged so they never change the result of the run.
+pub fn record_run(config: &Configuration, summary: &RunSummary) {
+    let mut metrics = lock();
+    if let Some(textfile) = textfile_path(config) {
+        carry_over(&mut metrics, textfile.as_path());
+    }
+
+    match summary.is_success() {
+        true => {
+            metrics.last_success = Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            )
+        }
+        false => metrics.run_failures += 1,
+    }
+    for archive in summary.archives.iter() {
+        let volume = archive.volume.as_deref().unwrap_or(ALL_VOLUMES).to_string();
+        metrics
+            .archive_bytes
+            .insert(volume.clone(), archive.size_bytes);
+        metrics
+            .archive_duration
+            .insert(volume, archive.duration_seconds);
+    }
+    metrics.containers_stopped = summary.containers_stopped.len();
+    metrics.containers_paused = summary.containers_paused.len();
+
+    if let Some(textfile) = textfile_path(config) {
+        match write_textfile(textfile.as_path(), metrics.render().as_str()) {
+            Ok(()) => debug!(target: LOG_TARGET, "Wrote metrics to {}", textfile.to_string_lossy()),
+            Err(error) => {
+                error!(target: LOG_TARGET, "Unable to write metrics to {}: {}", textfile.to_string_lossy(), error)
+            }
+        }
+    }
+}
+
+/// Start serving the metrics on `/metrics` from a separate thread
+pub fn serve_metrics(address: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(address)
+        .map_err(|e| MetricsServer(format!("Unable to listen on {}: {}", address, e)))?;
+    info!(target: LOG_TARGET, "Serving metrics on http://{}{}", address, METRICS_PATH);
+    std::thread::Builder::new()
+        .name("salvage-metrics".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.map_err(Error::from).and_then(respond);
+                if let Err(error) = result {
+                    debug!(target: LOG_TARGET, "Metrics request failed: {}", error);
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Read the headers so the client is not reset before it reads the response
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    trace!(target: LOG_TARGET, "Metrics request: {}", request_line.trim_end());
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next().map(|p| p.split('?').next())) {
+        (Some("GET"), Some(Some(METRICS_PATH))) => ("200 OK", lock().render()),
+        (Some("GET"), _) => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn textfile_path(config: &Configuration) -> Option<PathBuf> {
+    config
+        .metrics
+        .textfile_dir
+        .as_ref()
+        .map(|dir| dir.join(format!("{}.{}", config.archive_prefix, TEXTFILE_EXTENSION)))
+}
+
+/// Keep the samples from the textfile written by a previous process, as each run is a new process
+/// when Salvage is run by cron and a failed run does not replace the archive samples.
+fn carry_over(metrics: &mut Metrics, textfile: &std::path::Path) {
+    let Ok(text) = std::fs::read_to_string(textfile) else {
+        return;
+    };
+    for line in text.lines().filter(|l| !l.starts_with('#')) {
+        let Some((sample, value)) = line.rsplit_once(' ') else {
+            continue;
+        };
+        let (name, volume) = match sample.split_once('{') {
+            Some((name, labels)) => {
+                let volume = labels
+                    .strip_prefix("volume=\"")
+                    .and_then(|l| l.strip_suffix("\"}"))
+                    .map(unescape);
+                (name, volume)
+            }
+            None => (sample, None),
+        };
+        match (name, volume) {
+            (LAST_SUCCESS, None) if metrics.last_success.is_none() => {
+                metrics.last_success = value.parse().ok();
+            }
+            (RUN_FAILURES, None) => {
+                let previous = value.parse().unwrap_or_default();
+                metrics.run_failures = metrics.run_failures.max(previous);
+            }
+            (ARCHIVE_BYTES, Some(volume)) => {
+                if let Ok(bytes) = value.parse() {
+                    metrics.archive_bytes.entry(volume).or_insert(bytes);
+                }
+            }
+            (ARCHIVE_DURATION, Some(volume)) => {
+                if let Ok(duration) = value.parse() {
+                    metrics.archive_duration.entry(volume).or_insert(duration);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Write the textfile under a temporary name and rename it so node_exporter never reads a partial
+/// file
+fn write_textfile(textfile: &std::path::Path, text: &str) -> Result<(), Error> {
+    let mut temporary = textfile.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    std::fs::write(temporary.as_os_str(), text)?;
+    std::fs::rename(temporary, textfile)?;
+    Ok(())
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Reverse [`escape`]
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, c.eq(&'\\').then(|| chars.next()).flatten()) {
+            (_, Some('n')) => unescaped.push('\n'),
+            (_, Some(escaped)) => unescaped.push(escaped),
+            (c, None) => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+fn lock() -> MutexGuard<'static, Metrics> {
+    METRICS.lock().unwrap_or_else(PoisonError::into_inner)
+}
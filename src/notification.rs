@@ -0,0 +1,558 @@
+use crate::configuration::{Configuration, DefaultEnv};
+use crate::error::Error;
+use crate::error::Error::{InvalidNotifyOn, Notification};
+use crate::{WrittenArchive, LOG_TARGET};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::{debug, error, info};
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+pub mod email;
+
+use email::{send_email, SmtpConfig};
+
+const TIMEOUT_SECS: u64 = 30;
+const NTFY_PRIORITY_FAILURE: &str = "high";
+const GOTIFY_PRIORITY_SUCCESS: u8 = 2;
+const GOTIFY_PRIORITY_FAILURE: u8 = 8;
+
+/// Outcome of an archive run that a notification is sent for
+#[derive(Default, Clone, Copy)]
+pub enum NotifyOn {
+    #[default]
+    Always,
+    Success,
+    Failure,
+}
+
+impl NotifyOn {
+    fn matches(&self, success: bool) -> bool {
+        match self {
+            NotifyOn::Always => true,
+            NotifyOn::Success => success,
+            NotifyOn::Failure => !success,
+        }
+    }
+}
+
+impl DefaultEnv for NotifyOn {}
+
+impl Display for NotifyOn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotifyOn::Always => write!(f, "Always"),
+            NotifyOn::Success => write!(f, "Success"),
+            NotifyOn::Failure => write!(f, "Failure"),
+        }
+    }
+}
+
+impl FromStr for NotifyOn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "a" | "always" => Ok(Self::Always),
+            "s" | "success" => Ok(Self::Success),
+            "f" | "failure" => Ok(Self::Failure),
+            _ => Err(InvalidNotifyOn),
+        }
+    }
+}
+
+/// Generic webhook that receives the run summary as JSON
+pub struct WebhookConfig {
+    pub url: String,
+    pub on: NotifyOn,
+}
+
+/// ntfy topic that receives the run summary as a text message
+pub struct NtfyConfig {
+    /// Full URL of the topic, ie `https://ntfy.sh/backups`
+    pub url: String,
+    pub token: Option<String>,
+    pub on: NotifyOn,
+}
+
+/// Gotify server that receives the run summary as a message for an application
+pub struct GotifyConfig {
+    pub url: String,
+    pub token: String,
+    pub on: NotifyOn,
+}
+
+#[derive(Default)]
+pub struct NotificationConfig {
+    pub webhook: Option<WebhookConfig>,
+    pub ntfy: Option<NtfyConfig>,
+    pub gotify: Option<GotifyConfig>,
+    pub smtp: Option<SmtpConfig>,
+}
+
+impl NotificationConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.webhook.is_some()
+            || self.ntfy.is_some()
+            || self.gotify.is_some()
+            || self.smtp.is_some()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Success,
+    Failure,
+}
+
+#[derive(Serialize)]
+pub struct ArchiveSummary {
+    pub name: String,
+    pub volume: Option<String>,
+    pub size_bytes: u64,
+    pub duration_seconds: f64,
+}
+
+impl From<&WrittenArchive> for ArchiveSummary {
+    fn from(archive: &WrittenArchive) -> Self {
+        Self {
+            name: archive.name(),
+            volume: archive.volume.clone(),
+            size_bytes: archive.size,
+            duration_seconds: archive.duration.as_secs_f64(),
+        }
+    }
+}
+
+/// Summary of an archive run that is sent to each notifier
+#[derive(Serialize)]
+pub struct RunSummary {
+    pub status: RunStatus,
+    pub archive_prefix: String,
+    pub strategy: String,
+    pub started_at: String,
+    pub finished_at: String,
+    pub duration_seconds: f64,
+    pub archives: Vec<ArchiveSummary>,
+    pub containers_stopped: Vec<String>,
+    pub containers_restarted: Vec<String>,
+    pub containers_paused: Vec<String>,
+    pub containers_unpaused: Vec<String>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    start_time: Instant,
+    #[serde(skip)]
+    started: DateTime<Local>,
+}
+
+impl RunSummary {
+    pub fn start(config: &Configuration) -> Self {
+        let started = Local::now();
+        Self {
+            status: RunStatus::Success,
+            archive_prefix: config.archive_prefix.clone(),
+            strategy: config.archive_strategy.to_string(),
+            started_at: started.to_rfc3339_opts(SecondsFormat::Secs, false),
+            finished_at: String::new(),
+            duration_seconds: 0.0,
+            archives: Vec::new(),
+            containers_stopped: Vec::new(),
+            containers_restarted: Vec::new(),
+            containers_paused: Vec::new(),
+            containers_unpaused: Vec::new(),
+            error: None,
+            start_time: Instant::now(),
+            started,
+        }
+    }
+
+    /// Record the end of the run and the error that ended it, if any
+    pub fn finish(&mut self, error: Option<&Error>) {
+        self.finished_at = Local::now().to_rfc3339_opts(SecondsFormat::Secs, false);
+        self.duration_seconds = self.start_time.elapsed().as_secs_f64();
+        self.error = error.map(|e| e.to_string());
+        self.status = match error {
+            None => RunStatus::Success,
+            Some(_) => RunStatus::Failure,
+        };
+    }
+
+    pub fn is_success(&self) -> bool {
+        matches!(self.status, RunStatus::Success)
+    }
+
+    pub fn title(&self) -> String {
+        match self.is_success() {
+            true => format!("Salvage {} archive run succeeded", self.archive_prefix),
+            false => format!("Salvage {} archive run failed", self.archive_prefix),
+        }
+    }
+
+    /// Plain text description of the run
+    pub fn text(&self) -> String {
+        let total: u64 = self.archives.iter().map(|a| a.size_bytes).sum();
+        let mut text = format!(
+            "Started {} and took {:.1} seconds.\n{} archives written ({}).\n",
+            self.started.format("%Y-%m-%d %H:%M:%S %:z"),
+            self.duration_seconds,
+            self.archives.len(),
+            format_size(total)
+        );
+        for archive in self.archives.iter() {
+            text.push_str(
+                format!(
+                    "- {} ({}) in {:.1} seconds\n",
+                    archive.name,
+                    format_size(archive.size_bytes),
+                    archive.duration_seconds
+                )
+                .as_str(),
+            );
+        }
+        if !self.containers_stopped.is_empty() {
+            text.push_str(
+                format!(
+                    "{} containers stopped, {} restarted.\n",
+                    self.containers_stopped.len(),
+                    self.containers_restarted.len()
+                )
+                .as_str(),
+            );
+        }
+        if !self.containers_paused.is_empty() {
+            text.push_str(
+                format!(
+                    "{} containers paused, {} unpaused.\n",
+                    self.containers_paused.len(),
+                    self.containers_unpaused.len()
+                )
+                .as_str(),
+            );
+        }
+        if let Some(error) = self.error.as_deref() {
+            text.push_str(format!("Error: {}\n", error).as_str());
+        }
+        text
+    }
+}
+
+/// Format a size in bytes with a binary unit
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+/// Send the run summary to every notifier configured for its outcome. Failures are logged so a
+/// notification problem never changes the result of the run.
+pub fn notify(config: &Configuration, summary: &RunSummary) {
+    let notification = &config.notification;
+    if !notification.is_enabled() {
+        return;
+    }
+    let start_time = Instant::now();
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(TIMEOUT_SECS))
+        .build();
+    let success = summary.is_success();
+
+    if let Some(webhook) = notification
+        .webhook
+        .as_ref()
+        .filter(|w| w.on.matches(success))
+    {
+        report("webhook", send_webhook(&agent, webhook, summary));
+    }
+    if let Some(ntfy) = notification.ntfy.as_ref().filter(|n| n.on.matches(success)) {
+        report("ntfy", send_ntfy(&agent, ntfy, summary));
+    }
+    if let Some(gotify) = notification
+        .gotify
+        .as_ref()
+        .filter(|g| g.on.matches(success))
+    {
+        report("Gotify", send_gotify(&agent, gotify, summary));
+    }
+    if let Some(smtp) = notification.smtp.as_ref().filter(|s| s.on.matches(success)) {
+        report("email", send_email(smtp, summary));
+    }
+    debug!(target: LOG_TARGET, "Notifications complete after {} milliseconds", start_time.elapsed().as_millis());
+}
+
+fn report(notifier: &str, result: Result<(), Error>) {
+    match result {
+        Ok(()) => info!(target: LOG_TARGET, "Sent {} notification", notifier),
+        Err(error) => {
+            error!(target: LOG_TARGET, "Unable to send {} notification: {}", notifier, error)
+        }
+    }
+}
+
+fn send_webhook(
+    agent: &ureq::Agent,
+    webhook: &WebhookConfig,
+    summary: &RunSummary,
+) -> Result<(), Error> {
+    let body = serde_json::to_string(summary)?;
+    agent
+        .post(webhook.url.as_str())
+        .set("Content-Type", "application/json")
+        .send_string(body.as_str())
+        .map_err(request_error)?;
+    Ok(())
+}
+
+fn send_ntfy(agent: &ureq::Agent, ntfy: &NtfyConfig, summary: &RunSummary) -> Result<(), Error> {
+    let mut request = agent
+        .post(ntfy.url.as_str())
+        .set("Title", summary.title().as_str());
+    request = match summary.is_success() {
+        true => request.set("Tags", "white_check_mark"),
+        false => request
+            .set("Tags", "rotating_light")
+            .set("Priority", NTFY_PRIORITY_FAILURE),
+    };
+    if let Some(token) = ntfy.token.as_deref() {
+        request = request.set("Authorization", format!("Bearer {}", token).as_str());
+    }
+    request
+        .send_string(summary.text().as_str())
+        .map_err(request_error)?;
+    Ok(())
+}
+
+fn send_gotify(
+    agent: &ureq::Agent,
+    gotify: &GotifyConfig,
+    summary: &RunSummary,
+) -> Result<(), Error> {
+    let priority = match summary.is_success() {
+        true => GOTIFY_PRIORITY_SUCCESS,
+        false => GOTIFY_PRIORITY_FAILURE,
+    };
+    let body = serde_json::json!({
+        "title": summary.title(),
+        "message": summary.text(),
+        "priority": priority,
+    });
+    agent
+        .post(format!("{}/message", gotify.url).as_str())
+        .set("X-Gotify-Key", gotify.token.as_str())
+        .set("Content-Type", "application/json")
+        .send_string(body.to_string().as_str())
+        .map_err(request_error)?;
+    Ok(())
+}
+
+fn request_error(error: ureq::Error) -> Error {
+    match error {
+        ureq::Error::Status(code, response) => Notification(format!(
+            "{} responded with status {}",
+            response.get_url(),
+            code
+        )),
+        ureq::Error::Transport(transport) => Notification(transport.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    /// A request received by [`stub`]
+    struct Received {
+        request_line: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Start an HTTP stub that answers a single request with `status` and return its URL along with
+    /// the request it received
+    fn stub(status: u16) -> (String, Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers: Vec<(String, String)> = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(':') {
+                    Some((name, value)) => headers.push((name.to_string(), value.trim().into())),
+                    None => break,
+                }
+            }
+            let length = headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+                .map_or(0, |(_, v)| v.parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(body.as_mut_slice()).unwrap();
+            write!(
+                &stream,
+                "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            sender
+                .send(Received {
+                    request_line: request_line.trim_end().to_string(),
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                })
+                .unwrap();
+        });
+        (url, receiver)
+    }
+
+    fn agent() -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(TIMEOUT_SECS))
+            .build()
+    }
+
+    fn summary(error: Option<&str>) -> RunSummary {
+        let started = Local::now();
+        RunSummary {
+            status: match error {
+                None => RunStatus::Success,
+                Some(_) => RunStatus::Failure,
+            },
+            archive_prefix: "salvage".to_string(),
+            strategy: "Multiple".to_string(),
+            started_at: started.to_rfc3339_opts(SecondsFormat::Secs, false),
+            finished_at: started.to_rfc3339_opts(SecondsFormat::Secs, false),
+            duration_seconds: 1.5,
+            archives: vec![ArchiveSummary {
+                name: "salvage_db_2024-01-01_00-00-00.tar.gz".to_string(),
+                volume: Some("db".to_string()),
+                size_bytes: 2048,
+                duration_seconds: 0.5,
+            }],
+            containers_stopped: vec!["abc".to_string()],
+            containers_restarted: vec!["abc".to_string()],
+            containers_paused: Vec::new(),
+            containers_unpaused: Vec::new(),
+            error: error.map(str::to_string),
+            start_time: Instant::now(),
+            started,
+        }
+    }
+
+    #[test]
+    fn posts_the_summary_to_a_webhook() {
+        let (url, received) = stub(200);
+        let webhook = WebhookConfig {
+            url: format!("{}/hook", url),
+            on: NotifyOn::Always,
+        };
+        send_webhook(&agent(), &webhook, &summary(None)).unwrap();
+
+        let request = received.recv().unwrap();
+        assert_eq!(request.request_line, "POST /hook HTTP/1.1");
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        let body: serde_json::Value = serde_json::from_str(request.body.as_str()).unwrap();
+        assert_eq!(body["status"], "success");
+        assert_eq!(body["archives"][0]["size_bytes"], 2048);
+        assert_eq!(body["containers_stopped"][0], "abc");
+        assert!(body["error"].is_null());
+    }
+
+    #[test]
+    fn sends_failures_to_ntfy_with_high_priority() {
+        let (url, received) = stub(200);
+        let ntfy = NtfyConfig {
+            url: format!("{}/backups", url),
+            token: Some("secret".to_string()),
+            on: NotifyOn::Failure,
+        };
+        send_ntfy(&agent(), &ntfy, &summary(Some("disk full"))).unwrap();
+
+        let request = received.recv().unwrap();
+        assert_eq!(request.request_line, "POST /backups HTTP/1.1");
+        assert_eq!(
+            request.header("title"),
+            Some("Salvage salvage archive run failed")
+        );
+        assert_eq!(request.header("priority"), Some(NTFY_PRIORITY_FAILURE));
+        assert_eq!(request.header("authorization"), Some("Bearer secret"));
+        assert!(request.body.contains("Error: disk full"));
+    }
+
+    #[test]
+    fn posts_a_message_to_gotify() {
+        let (url, received) = stub(200);
+        let gotify = GotifyConfig {
+            url,
+            token: "app-token".to_string(),
+            on: NotifyOn::Success,
+        };
+        send_gotify(&agent(), &gotify, &summary(None)).unwrap();
+
+        let request = received.recv().unwrap();
+        assert_eq!(request.request_line, "POST /message HTTP/1.1");
+        assert_eq!(request.header("x-gotify-key"), Some("app-token"));
+        let body: serde_json::Value = serde_json::from_str(request.body.as_str()).unwrap();
+        assert_eq!(body["priority"], GOTIFY_PRIORITY_SUCCESS);
+        assert_eq!(body["title"], "Salvage salvage archive run succeeded");
+    }
+
+    #[test]
+    fn reports_an_error_status() {
+        let (url, _received) = stub(500);
+        let webhook = WebhookConfig {
+            url,
+            on: NotifyOn::Always,
+        };
+        assert!(matches!(
+            send_webhook(&agent(), &webhook, &summary(None)),
+            Err(Notification(message)) if message.contains("status 500")
+        ));
+    }
+
+    #[test]
+    fn matches_the_outcome() {
+        assert!(NotifyOn::Always.matches(true) && NotifyOn::Always.matches(false));
+        assert!(NotifyOn::Success.matches(true) && !NotifyOn::Success.matches(false));
+        assert!(!NotifyOn::Failure.matches(true) && NotifyOn::Failure.matches(false));
+        assert!(matches!(NotifyOn::from_str("F"), Ok(NotifyOn::Failure)));
+        assert!(NotifyOn::from_str("never").is_err());
+    }
+
+    #[test]
+    fn describes_the_run() {
+        let text = summary(None).text();
+        assert!(text.contains("1 archives written (2.0 KiB)."));
+        assert!(text.contains("- salvage_db_2024-01-01_00-00-00.tar.gz (2.0 KiB) in 0.5 seconds"));
+        assert!(text.contains("1 containers stopped, 1 restarted."));
+        assert!(!text.contains("paused"));
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+    }
+}
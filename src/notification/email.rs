@@ -0,0 +1,620 @@
+use crate::configuration::DefaultEnv;
+use crate::error::Error;
+use crate::error::Error::{InvalidSmtpSecurity, Smtp};
+use crate::notification::{format_size, NotifyOn, RunSummary};
+use crate::LOG_TARGET;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Local;
+use log::{debug, trace, warn};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const DEFAULT_STARTTLS_PORT: u16 = 587;
+pub const DEFAULT_TLS_PORT: u16 = 465;
+pub const DEFAULT_PLAIN_PORT: u16 = 25;
+const TIMEOUT_SECS: u64 = 30;
+const BOUNDARY: &str = "salvage-report-boundary";
+const LINE_LENGTH: usize = 76;
+
+/// How the connection to the SMTP server is secured
+#[derive(Default, Clone, Copy)]
+pub enum SmtpSecurity {
+    /// Upgrade a plain connection with `STARTTLS`
+    #[default]
+    StartTls,
+    /// Connect with TLS from the start
+    Tls,
+    /// Never use TLS
+    None,
+}
+
+impl SmtpSecurity {
+    pub fn default_port(&self) -> u16 {
+        match self {
+            SmtpSecurity::StartTls => DEFAULT_STARTTLS_PORT,
+            SmtpSecurity::Tls => DEFAULT_TLS_PORT,
+            SmtpSecurity::None => DEFAULT_PLAIN_PORT,
+        }
+    }
+}
+
+impl DefaultEnv for SmtpSecurity {}
+
+impl Display for SmtpSecurity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SmtpSecurity::StartTls => write!(f, "STARTTLS"),
+            SmtpSecurity::Tls => write!(f, "TLS"),
+            SmtpSecurity::None => write!(f, "None"),
+        }
+    }
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "starttls" => Ok(Self::StartTls),
+            "tls" | "ssl" => Ok(Self::Tls),
+            "none" => Ok(Self::None),
+            _ => Err(InvalidSmtpSecurity),
+        }
+    }
+}
+
+/// SMTP server that receives a plain text and HTML report of each run
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// PEM file with the certificate authorities to trust instead of the bundled web roots
+    pub ca_file: Option<PathBuf>,
+    pub on: NotifyOn,
+}
+
+/// Send the run report as a multipart email with a plain text and an HTML part
+pub fn send_email(smtp: &SmtpConfig, summary: &RunSummary) -> Result<(), Error> {
+    let address = format!("{}:{}", smtp.host, smtp.port);
+    debug!(target: LOG_TARGET, "Connecting to SMTP server {}", address);
+    let stream = TcpStream::connect(address.as_str())
+        .map_err(|e| Smtp(format!("Unable to connect to {}: {}", address, e)))?;
+    stream.set_read_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(TIMEOUT_SECS)))?;
+
+    let mut client = match smtp.security {
+        SmtpSecurity::Tls => SmtpClient::new(Transport::Tls(Box::new(tls_stream(smtp, stream)?))),
+        _ => SmtpClient::new(Transport::Plain(stream)),
+    };
+    client.expect(220)?;
+    let mut capabilities = client.command(format!("EHLO {}", hostname()).as_str(), 250)?;
+
+    if matches!(smtp.security, SmtpSecurity::StartTls) {
+        if !capabilities
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case("STARTTLS"))
+        {
+            return Err(Smtp(format!("{} does not support STARTTLS", address)));
+        }
+        client.command("STARTTLS", 220)?;
+        client = client.upgrade(smtp)?;
+        capabilities = client.command(format!("EHLO {}", hostname()).as_str(), 250)?;
+    }
+
+    if let Some(username) = smtp.username.as_deref() {
+        if matches!(smtp.security, SmtpSecurity::None) {
+            warn!(target: LOG_TARGET, "Sending SMTP credentials to {} without TLS", address);
+        }
+        authenticate(&mut client, capabilities.as_slice(), username, smtp)?;
+    }
+
+    client.command(format!("MAIL FROM:<{}>", smtp.from).as_str(), 250)?;
+    for recipient in smtp.to.iter() {
+        client.command(format!("RCPT TO:<{}>", recipient).as_str(), 250)?;
+    }
+    client.command("DATA", 354)?;
+    client.send_data(message(smtp, summary).as_str())?;
+    client.expect(250)?;
+    // The message has been accepted so a failure to quit cleanly is not an error
+    let _ = client.command("QUIT", 221);
+    Ok(())
+}
+
+/// Authenticate with `AUTH PLAIN`, or `AUTH LOGIN` when it is the only mechanism offered
+fn authenticate(
+    client: &mut SmtpClient,
+    capabilities: &[String],
+    username: &str,
+    smtp: &SmtpConfig,
+) -> Result<(), Error> {
+    let password = smtp.password.as_deref().unwrap_or_default();
+    let mechanisms: Vec<String> = capabilities
+        .iter()
+        .filter_map(|c| c.strip_prefix("AUTH ").or_else(|| c.strip_prefix("AUTH=")))
+        .flat_map(|m| m.split_whitespace())
+        .map(|m| m.to_ascii_uppercase())
+        .collect();
+    trace!(target: LOG_TARGET, "SMTP authentication mechanisms: {:?}", mechanisms);
+
+    if mechanisms.iter().any(|m| m.eq("LOGIN")) && !mechanisms.iter().any(|m| m.eq("PLAIN")) {
+        client.command("AUTH LOGIN", 334)?;
+        client.credentials(STANDARD.encode(username).as_str(), 334)?;
+        client.credentials(STANDARD.encode(password).as_str(), 235)?;
+    } else {
+        let credentials = STANDARD.encode(format!("\0{}\0{}", username, password));
+        client.credentials(format!("AUTH PLAIN {}", credentials).as_str(), 235)?;
+    }
+    Ok(())
+}
+
+/// Build the report message with its headers
+fn message(smtp: &SmtpConfig, summary: &RunSummary) -> String {
+    let mut message = String::new();
+    let mut header = |name: &str, value: &str| {
+        message.push_str(format!("{}: {}\r\n", name, value).as_str());
+    };
+    header("From", smtp.from.as_str());
+    header("To", smtp.to.join(", ").as_str());
+    header("Subject", encode_header(summary.title().as_str()).as_str());
+    header("Date", Local::now().to_rfc2822().as_str());
+    header(
+        "Message-ID",
+        format!(
+            "<salvage.{}@{}>",
+            Local::now().timestamp_nanos_opt().unwrap_or_default(),
+            hostname()
+        )
+        .as_str(),
+    );
+    header("MIME-Version", "1.0");
+    header(
+        "Content-Type",
+        format!("multipart/alternative; boundary=\"{}\"", BOUNDARY).as_str(),
+    );
+    message.push_str("\r\n");
+
+    for (content_type, body) in [("text/plain", summary.text()), ("text/html", html(summary))] {
+        message.push_str(format!("--{}\r\n", BOUNDARY).as_str());
+        message.push_str(format!("Content-Type: {}; charset=utf-8\r\n", content_type).as_str());
+        message.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+        let encoded = STANDARD.encode(body);
+        for line in encoded.as_bytes().chunks(LINE_LENGTH) {
+            message.push_str(String::from_utf8_lossy(line).as_ref());
+            message.push_str("\r\n");
+        }
+    }
+    message.push_str(format!("--{}--\r\n", BOUNDARY).as_str());
+    message
+}
+
+/// HTML version of the run report with a table of the archives written
+fn html(summary: &RunSummary) -> String {
+    let colour = match summary.is_success() {
+        true => "#2e7d32",
+        false => "#c62828",
+    };
+    let mut html = format!(
+        "<html><body style=\"font-family: sans-serif\">\n<h2 style=\"color: {}\">{}</h2>\n",
+        colour,
+        escape(summary.title().as_str())
+    );
+    html.push_str(
+        format!(
+            "<p>Started {} and took {:.1} seconds using the {} strategy.</p>\n",
+            escape(summary.started_at.as_str()),
+            summary.duration_seconds,
+            escape(summary.strategy.as_str())
+        )
+        .as_str(),
+    );
+    if let Some(error) = summary.error.as_deref() {
+        html.push_str(
+            format!(
+                "<p style=\"color: {}\"><strong>Error:</strong> {}</p>\n",
+                colour,
+                escape(error)
+            )
+            .as_str(),
+        );
+    }
+
+    html.push_str("<table cellpadding=\"4\" style=\"border-collapse: collapse\">\n");
+    html.push_str("<tr><th align=\"left\">Archive</th><th align=\"left\">Volume</th><th align=\"right\">Size</th><th align=\"right\">Seconds</th></tr>\n");
+    for archive in summary.archives.iter() {
+        html.push_str(
+            format!(
+                "<tr><td>{}</td><td>{}</td><td align=\"right\">{}</td><td align=\"right\">{:.1}</td></tr>\n",
+                escape(archive.name.as_str()),
+                escape(archive.volume.as_deref().unwrap_or("all")),
+                format_size(archive.size_bytes),
+                archive.duration_seconds
+            )
+            .as_str(),
+        );
+    }
+    html.push_str("</table>\n");
+
+    if !summary.containers_stopped.is_empty() {
+        html.push_str(
+            format!(
+                "<p>{} containers stopped, {} restarted.</p>\n",
+                summary.containers_stopped.len(),
+                summary.containers_restarted.len()
+            )
+            .as_str(),
+        );
+    }
+    if !summary.containers_paused.is_empty() {
+        html.push_str(
+            format!(
+                "<p>{} containers paused, {} unpaused.</p>\n",
+                summary.containers_paused.len(),
+                summary.containers_unpaused.len()
+            )
+            .as_str(),
+        );
+    }
+    html.push_str("</body></html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Encode a header value as an RFC 2047 encoded word when it is not plain ASCII
+fn encode_header(value: &str) -> String {
+    match value.is_ascii() {
+        true => value.to_string(),
+        false => format!("=?UTF-8?B?{}?=", STANDARD.encode(value)),
+    }
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "localhost".to_string())
+}
+
+fn tls_stream(
+    smtp: &SmtpConfig,
+    stream: TcpStream,
+) -> Result<StreamOwned<ClientConnection, TcpStream>, Error> {
+    let mut roots = RootCertStore::empty();
+    match smtp.ca_file.as_ref() {
+        Some(ca_file) => {
+            for certificate in CertificateDer::pem_file_iter(ca_file).map_err(|e| {
+                Smtp(format!(
+                    "Unable to read {}: {}",
+                    ca_file.to_string_lossy(),
+                    e
+                ))
+            })? {
+                let certificate = certificate.map_err(|e| {
+                    Smtp(format!(
+                        "Unable to read {}: {}",
+                        ca_file.to_string_lossy(),
+                        e
+                    ))
+                })?;
+                roots.add(certificate)?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    let server_name = ServerName::try_from(smtp.host.clone())
+        .map_err(|e| Smtp(format!("Invalid SMTP host {}: {}", smtp.host, e)))?;
+    let connection = ClientConnection::new(Arc::new(config), server_name)?;
+    Ok(StreamOwned::new(connection, stream))
+}
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf),
+            Transport::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.write(buf),
+            Transport::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.flush(),
+            Transport::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+struct SmtpClient {
+    reader: BufReader<Transport>,
+}
+
+impl SmtpClient {
+    fn new(transport: Transport) -> Self {
+        Self {
+            reader: BufReader::new(transport),
+        }
+    }
+
+    /// Switch the plain connection to TLS after the server accepted `STARTTLS`
+    fn upgrade(self, smtp: &SmtpConfig) -> Result<Self, Error> {
+        match self.reader.into_inner() {
+            Transport::Plain(stream) => Ok(Self::new(Transport::Tls(Box::new(tls_stream(
+                smtp, stream,
+            )?)))),
+            transport => Ok(Self::new(transport)),
+        }
+    }
+
+    /// Send a command and return the lines of the reply when it has the expected code
+    fn command(&mut self, command: &str, code: u16) -> Result<Vec<String>, Error> {
+        trace!(target: LOG_TARGET, "SMTP > {}", command);
+        self.send(command, code)
+    }
+
+    /// Send a command containing credentials, which is not logged
+    fn credentials(&mut self, command: &str, code: u16) -> Result<Vec<String>, Error> {
+        trace!(target: LOG_TARGET, "SMTP > [credentials]");
+        self.send(command, code)
+    }
+
+    fn send(&mut self, command: &str, code: u16) -> Result<Vec<String>, Error> {
+        let transport = self.reader.get_mut();
+        transport.write_all(command.as_bytes())?;
+        transport.write_all(b"\r\n")?;
+        transport.flush()?;
+        self.expect(code)
+    }
+
+    /// Send the message followed by the end of data marker, escaping lines that start with a dot
+    fn send_data(&mut self, message: &str) -> Result<(), Error> {
+        let transport = self.reader.get_mut();
+        for line in message.trim_end_matches("\r\n").split("\r\n") {
+            if line.starts_with('.') {
+                transport.write_all(b".")?;
+            }
+            transport.write_all(line.as_bytes())?;
+            transport.write_all(b"\r\n")?;
+        }
+        transport.write_all(b".\r\n")?;
+        transport.flush()?;
+        Ok(())
+    }
+
+    /// Read a reply, which can span several lines, and check its code
+    fn expect(&mut self, code: u16) -> Result<Vec<String>, Error> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(Smtp("Connection closed by the server".to_string()));
+            }
+            let line = line.trim_end();
+            trace!(target: LOG_TARGET, "SMTP < {}", line);
+            let reply = line.get(..3).and_then(|c| c.parse::<u16>().ok());
+            if reply != Some(code) {
+                return Err(Smtp(format!(
+                    "Expected reply {} but received: {}",
+                    code, line
+                )));
+            }
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(lines);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::{ArchiveSummary, RunStatus};
+    use std::net::TcpListener;
+    use std::sync::mpsc::{channel, Receiver};
+
+    /// Start an SMTP sink that offers the authentication `mechanisms`, accepts a single session and
+    /// returns every line the client sent
+    fn sink(mechanisms: &'static str) -> (u16, Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = channel();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut reply = |text: &str| write!(writer, "{}\r\n", text).unwrap();
+            let mut lines = Vec::new();
+            let mut data = false;
+            let mut login = 0;
+            reply("220 sink ESMTP");
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let line = line.trim_end_matches("\r\n").to_string();
+                lines.push(line.clone());
+                match line.as_str() {
+                    "." if data => {
+                        data = false;
+                        reply("250 queued");
+                    }
+                    _ if data => (),
+                    _ if login > 0 => {
+                        login -= 1;
+                        reply(if login > 0 {
+                            "334 UGFzc3dvcmQ6"
+                        } else {
+                            "235 ok"
+                        });
+                    }
+                    "QUIT" => {
+                        reply("221 bye");
+                        break;
+                    }
+                    "DATA" => {
+                        data = true;
+                        reply("354 go ahead");
+                    }
+                    "AUTH LOGIN" => {
+                        login = 2;
+                        reply("334 VXNlcm5hbWU6");
+                    }
+                    l if l.starts_with("EHLO") => {
+                        reply("250-sink");
+                        reply(format!("250 AUTH {}", mechanisms).as_str());
+                    }
+                    l if l.starts_with("AUTH PLAIN") => reply("235 ok"),
+                    _ => reply("250 ok"),
+                }
+            }
+            sender.send(lines).unwrap();
+        });
+        (port, receiver)
+    }
+
+    fn config(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+            from: "salvage@example.com".to_string(),
+            to: vec!["ops@example.com".to_string(), "dev@example.com".to_string()],
+            ca_file: None,
+            on: NotifyOn::Always,
+        }
+    }
+
+    fn summary() -> RunSummary {
+        let mut summary = RunSummary {
+            status: RunStatus::Success,
+            archive_prefix: "salvage".to_string(),
+            strategy: "Multiple".to_string(),
+            started_at: String::new(),
+            finished_at: String::new(),
+            duration_seconds: 0.0,
+            archives: Vec::new(),
+            containers_stopped: Vec::new(),
+            containers_restarted: Vec::new(),
+            containers_paused: Vec::new(),
+            containers_unpaused: Vec::new(),
+            error: None,
+            start_time: std::time::Instant::now(),
+            started: Local::now(),
+        };
+        summary.archives.push(ArchiveSummary {
+            name: "salvage_<db>_2024-01-01_00-00-00.tar.gz".to_string(),
+            volume: Some("<db>".to_string()),
+            size_bytes: 1024,
+            duration_seconds: 0.5,
+        });
+        summary
+    }
+
+    #[test]
+    fn sends_the_report_with_auth_plain() {
+        let (port, received) = sink("LOGIN PLAIN");
+        send_email(&config(port), &summary()).unwrap();
+        let lines = received.recv().unwrap();
+
+        // Base64 of "\0user\0pass"
+        assert!(lines.contains(&"AUTH PLAIN AHVzZXIAcGFzcw==".to_string()));
+        assert!(lines.contains(&"MAIL FROM:<salvage@example.com>".to_string()));
+        assert!(lines.contains(&"RCPT TO:<ops@example.com>".to_string()));
+        assert!(lines.contains(&"RCPT TO:<dev@example.com>".to_string()));
+        assert!(lines.contains(&"Subject: Salvage salvage archive run succeeded".to_string()));
+        assert!(lines.contains(&format!("--{}--", BOUNDARY)));
+        assert_eq!(lines.last().map(String::as_str), Some("QUIT"));
+        assert!(lines.iter().all(|l| l.len() <= 998));
+    }
+
+    #[test]
+    fn falls_back_to_auth_login() {
+        let (port, received) = sink("LOGIN");
+        send_email(&config(port), &summary()).unwrap();
+        let lines = received.recv().unwrap();
+
+        let login = lines.iter().position(|l| l.eq("AUTH LOGIN")).unwrap();
+        assert_eq!(lines[login + 1], STANDARD.encode("user"));
+        assert_eq!(lines[login + 2], STANDARD.encode("pass"));
+    }
+
+    #[test]
+    fn escapes_lines_starting_with_a_dot() {
+        let (port, received) = sink("PLAIN");
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut client = SmtpClient::new(Transport::Plain(stream));
+        client.expect(220).unwrap();
+        client.command("DATA", 354).unwrap();
+        client.send_data(".hidden\r\nline\r\n..two\r\n").unwrap();
+        client.expect(250).unwrap();
+        client.command("QUIT", 221).unwrap();
+
+        assert_eq!(
+            received.recv().unwrap(),
+            ["DATA", "..hidden", "line", "...two", ".", "QUIT"]
+        );
+    }
+
+    #[test]
+    fn reports_an_unexpected_reply() {
+        let (port, _received) = sink("PLAIN");
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut client = SmtpClient::new(Transport::Plain(stream));
+        assert!(matches!(
+            client.expect(250),
+            Err(Smtp(message)) if message.contains("220 sink ESMTP")
+        ));
+    }
+
+    #[test]
+    fn escapes_the_html_report() {
+        let html = html(&summary());
+        assert!(html.contains("salvage_&lt;db&gt;_2024-01-01_00-00-00.tar.gz"));
+        assert!(!html.contains("<db>"));
+        assert_eq!(encode_header("plain"), "plain");
+        assert_eq!(
+            encode_header("é"),
+            format!("=?UTF-8?B?{}?=", STANDARD.encode("é"))
+        );
+    }
+}
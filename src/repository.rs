@@ -0,0 +1,782 @@
+use crate::catalog::Catalog;
+use crate::checksum::hash_file;
+use crate::configuration::Configuration;
+use crate::docker::RestartGuard;
+use crate::error::Error;
+use crate::error::Error::{ChecksumMismatch, SnapshotNotFound, UnsafeArchivePath};
+use crate::exclude::VolumeFilter;
+use crate::restore::check_ancestors;
+use crate::retention::{prune, ArchiveFile};
+use crate::{WrittenArchive, LOG_TARGET, TIMESTAMP_FORMAT};
+use clap::Subcommand;
+use fastcdc::v2020::StreamCDC;
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{CString, OsString};
+use std::fs::{File, Permissions};
+use std::io::{BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+use time::PrimitiveDateTime;
+use tokio::runtime::Runtime;
+
+const CHUNKS_DIR: &str = "chunks";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const SNAPSHOT_EXTENSION: &str = "json";
+const MIN_CHUNK_SIZE: u32 = 512 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 8 * 1024 * 1024;
+
+/// Operations on the snapshots in the repository used by the `Repository` strategy
+#[derive(Subcommand)]
+pub enum RepositoryCommand {
+    /// List the snapshots in the repository
+    List,
+    /// Restore a snapshot into its volume directory
+    Restore {
+        /// Name of the snapshot to restore
+        snapshot: String,
+        /// Directory to restore into instead of the volume directory
+        #[arg(long, value_name = "DIR")]
+        target: Option<PathBuf>,
+    },
+    /// Apply the retention policy to the snapshots and delete chunks no snapshot uses
+    Prune {
+        /// Log what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+/// Contents of a volume at the time of a single run
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Archive prefix the snapshot was stored with, which is missing from older snapshots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+    pub volume: String,
+    pub time: String,
+    pub nodes: Vec<Node>,
+}
+
+/// A file, directory or symlink in a snapshot. File contents are the concatenation of the chunks.
+#[derive(Serialize, Deserialize)]
+pub struct Node {
+    pub path: String,
+    #[serde(rename = "type")]
+    pub kind: NodeKind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+pub fn run_repository_command(
+    config: &Configuration,
+    runtime: &Runtime,
+    command: RepositoryCommand,
+) -> Result<(), Error> {
+    match command {
+        RepositoryCommand::List => list_snapshots(config),
+        RepositoryCommand::Restore { snapshot, target } => {
+            restore_snapshot(config, runtime, snapshot.as_str(), target)
+        }
+        RepositoryCommand::Prune { dry_run } => prune_repository(config, dry_run),
+    }
+}
+
+/// Record a snapshot of each directory in the repository. Files are split into content defined
+/// chunks which are compressed and stored by their SHA-256 hash, so data that is already in the
+/// repository from any volume or run is not stored again. Snapshots are added to `written` as
+/// they are stored.
+pub fn repository_archive(
+    directories: Vec<(OsString, PathBuf)>,
+    config: &Configuration,
+    timestamp: &str,
+    written: &mut Vec<WrittenArchive>,
+) -> Result<(), Error> {
+    std::fs::create_dir_all(config.repository_dir.join(CHUNKS_DIR))?;
+    std::fs::create_dir_all(config.repository_dir.join(SNAPSHOTS_DIR))?;
+
+    for (name, path) in directories {
+        let start_time = Instant::now();
+        let volume = name.to_string_lossy().to_string();
+        let mut stats = ChunkStats::default();
+        let mut nodes = Vec::new();
+        let mut paths = BTreeMap::new();
+        let mut filter = VolumeFilter::new(&config.exclude, volume.as_str(), path.as_path())?;
+        walk(path.as_path(), Path::new(""), &mut filter, &mut paths)?;
+
+        for (relative, metadata) in paths {
+            let Some(relative_str) = relative.to_str() else {
+                warn!(target: LOG_TARGET, "Skipping {} because its name is not valid UTF-8", relative.to_string_lossy());
+                continue;
+            };
+            let full_path = path.join(relative.as_path());
+            let file_type = metadata.file_type();
+            let mut node = Node {
+                path: relative_str.to_string(),
+                kind: NodeKind::File,
+                mode: metadata.mode(),
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                mtime: metadata.mtime(),
+                size: 0,
+                chunks: Vec::new(),
+                target: None,
+            };
+            if file_type.is_dir() {
+                node.kind = NodeKind::Dir;
+            } else if file_type.is_symlink() {
+                let target = std::fs::read_link(full_path.as_path())?;
+                let Some(target) = target.to_str() else {
+                    warn!(target: LOG_TARGET, "Skipping {} because its target is not valid UTF-8", full_path.to_string_lossy());
+                    continue;
+                };
+                node.kind = NodeKind::Symlink;
+                node.target = Some(target.to_string());
+            } else if file_type.is_file() {
+                node.size = metadata.size();
+                node.chunks = store_chunks(config, full_path.as_path(), &mut stats)?;
+            } else {
+                debug!(target: LOG_TARGET, "Skipping special file {}", full_path.to_string_lossy());
+                continue;
+            }
+            nodes.push(node);
+        }
+
+        let snapshot = Snapshot {
+            prefix: Some(config.archive_prefix.clone()),
+            volume: volume.clone(),
+            time: timestamp.to_string(),
+            nodes,
+        };
+        let id = snapshot_id(config, &snapshot);
+        write_snapshot(config, id.as_str(), &snapshot)?;
+        info!(target: LOG_TARGET, "Snapshot {} stored {} new chunks ({} bytes) and reused {} chunks", id, stats.new_chunks, stats.new_bytes, stats.reused_chunks);
+        debug!(target: LOG_TARGET, "Snapshot {} took {} milliseconds", id, start_time.elapsed().as_millis());
+        let path = snapshot_path(config, id.as_str());
+        written.push(WrittenArchive {
+            checksum: Some(hash_file(path.as_path())?),
+            path,
+            volume: Some(volume),
+            size: stats.new_bytes,
+            duration: start_time.elapsed(),
+            file_count: snapshot.nodes.len() as u64,
+            base: None,
+            parent: None,
+            index: None,
+        });
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct ChunkStats {
+    new_chunks: usize,
+    new_bytes: u64,
+    reused_chunks: usize,
+}
+
+/// Collect the metadata of every path below `root` that is not excluded, keyed by its path relative
+/// to the volume
+fn walk(
+    root: &Path,
+    relative: &Path,
+    filter: &mut VolumeFilter,
+    paths: &mut BTreeMap<PathBuf, std::fs::Metadata>,
+) -> Result<(), Error> {
+    for entry in filter.read_dir(root.join(relative).as_path())? {
+        let path = relative.join(entry.file_name());
+        let metadata = entry.metadata()?;
+        let is_dir = metadata.is_dir();
+        paths.insert(path.clone(), metadata);
+        if is_dir {
+            walk(root, path.as_path(), filter, paths)?;
+        }
+    }
+    Ok(())
+}
+
+/// Split the file into chunks, storing the chunks not yet in the repository, and return the hashes
+/// of every chunk in order
+fn store_chunks(
+    config: &Configuration,
+    path: &Path,
+    stats: &mut ChunkStats,
+) -> Result<Vec<String>, Error> {
+    let file = BufReader::new(File::open(path)?);
+    let mut hashes = Vec::new();
+    for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk?;
+        let hash = format!("{:x}", Sha256::digest(chunk.data.as_slice()));
+        let chunk_path = chunk_path(config, hash.as_str());
+        if chunk_path.is_file() {
+            stats.reused_chunks += 1;
+        } else {
+            let compressed = zstd::encode_all(
+                chunk.data.as_slice(),
+                config.archive_compression_level as i32,
+            )?;
+            write_atomic(
+                chunk_path.as_path(),
+                compressed.as_slice(),
+                config.archive_permission(),
+            )?;
+            stats.new_chunks += 1;
+            stats.new_bytes += compressed.len() as u64;
+        }
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+/// Chunks are spread over subdirectories named after the first two characters of their hash
+fn chunk_path(config: &Configuration, hash: &str) -> PathBuf {
+    config
+        .repository_dir
+        .join(CHUNKS_DIR)
+        .join(hash.get(..2).unwrap_or(hash))
+        .join(hash)
+}
+
+fn read_chunk(config: &Configuration, hash: &str) -> Result<Vec<u8>, Error> {
+    let data = zstd::decode_all(File::open(chunk_path(config, hash))?)?;
+    let actual = format!("{:x}", Sha256::digest(data.as_slice()));
+    match actual.eq(hash) {
+        true => Ok(data),
+        false => Err(ChecksumMismatch(hash.to_string(), actual)),
+    }
+}
+
+/// Write the file under a temporary name and rename it into place so readers never see a partial file
+fn write_atomic(path: &Path, data: &[u8], permissions: Permissions) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut temporary = path.as_os_str().to_os_string();
+    temporary.push(".tmp");
+    let mut file = File::create(temporary.as_os_str())?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::set_permissions(temporary.as_os_str(), permissions)?;
+    std::fs::rename(temporary, path)?;
+    Ok(())
+}
+
+/// ID of a new snapshot: `{prefix}_{volume}_{time}`, with a counter added when a snapshot with that
+/// ID already exists, such as from a second run within the same second or a prefix and volume that
+/// join to the same name as another pair
+fn snapshot_id(config: &Configuration, snapshot: &Snapshot) -> String {
+    let id = format!(
+        "{}_{}_{}",
+        config.archive_prefix, snapshot.volume, snapshot.time
+    );
+    (1..)
+        .map(|n| match n {
+            1 => id.clone(),
+            n => format!("{}_{}", id, n),
+        })
+        .find(|id| !snapshot_path(config, id.as_str()).exists())
+        .unwrap_or(id)
+}
+
+/// Archive prefix a snapshot was stored with. Only snapshots written before the prefix was recorded
+/// fall back to taking it from the ID, which ends with their volume and time.
+fn snapshot_prefix<'a>(id: &'a str, snapshot: &'a Snapshot) -> Option<&'a str> {
+    snapshot
+        .prefix
+        .as_deref()
+        .or_else(|| id.strip_suffix(format!("_{}_{}", snapshot.volume, snapshot.time).as_str()))
+}
+
+fn snapshot_path(config: &Configuration, id: &str) -> PathBuf {
+    config
+        .repository_dir
+        .join(SNAPSHOTS_DIR)
+        .join(format!("{}.{}", id, SNAPSHOT_EXTENSION))
+}
+
+fn write_snapshot(config: &Configuration, id: &str, snapshot: &Snapshot) -> Result<(), Error> {
+    let mut writer = BufWriter::new(Vec::new());
+    serde_json::to_writer(&mut writer, snapshot)?;
+    let data = writer.into_inner().map_err(|e| e.into_error())?;
+    write_atomic(
+        snapshot_path(config, id).as_path(),
+        data.as_slice(),
+        config.archive_permission(),
+    )
+}
+
+fn read_snapshot(path: &Path) -> Result<Snapshot, Error> {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+}
+
+/// Load every snapshot in the repository keyed by snapshot ID, which is the name of its file
+fn load_snapshots(config: &Configuration) -> Result<BTreeMap<String, Snapshot>, Error> {
+    let directory = config.repository_dir.join(SNAPSHOTS_DIR);
+    let mut snapshots = BTreeMap::new();
+    if !directory.is_dir() {
+        return Ok(snapshots);
+    }
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e.eq(SNAPSHOT_EXTENSION)) {
+            let id = path.file_stem().unwrap_or_default().to_string_lossy();
+            snapshots.insert(id.to_string(), read_snapshot(path.as_path())?);
+        }
+    }
+    Ok(snapshots)
+}
+
+fn list_snapshots(config: &Configuration) -> Result<(), Error> {
+    let snapshots = load_snapshots(config)?;
+    for (id, snapshot) in snapshots.iter() {
+        let size: u64 = snapshot.nodes.iter().map(|n| n.size).sum();
+        info!(target: LOG_TARGET, "{}: volume {} at {} with {} entries ({} bytes)", id, snapshot.volume, snapshot.time, snapshot.nodes.len(), size);
+    }
+    info!(target: LOG_TARGET, "Found {} snapshots in {}", snapshots.len(), config.repository_dir.to_string_lossy());
+    Ok(())
+}
+
+/// Restore a snapshot into its volume directory or the target directory, stopping any containers
+/// using the volume while restoring and starting them again afterward.
+fn restore_snapshot(
+    config: &Configuration,
+    runtime: &Runtime,
+    id: &str,
+    target: Option<PathBuf>,
+) -> Result<(), Error> {
+    let start_time = Instant::now();
+    let path = snapshot_path(config, id);
+    if !path.is_file() {
+        return Err(SnapshotNotFound(id.to_string()));
+    }
+    let snapshot = read_snapshot(path.as_path())?;
+    let restore_path = target.unwrap_or_else(|| config.data_dir.join(snapshot.volume.as_str()));
+    info!(target: LOG_TARGET, "Restore of snapshot {} to {} started", id, restore_path.to_string_lossy());
+
+    let mut restart_guard = RestartGuard::new(runtime, config.container_management_enabled())?;
+    if let Err(error) = restart_guard.pre_restore(restore_path.as_path()) {
+        return restart_guard.finish(Err(error));
+    }
+
+    let result = restore_nodes(config, &snapshot, restore_path.as_path());
+
+    restart_guard.finish(result)?;
+    info!(target: LOG_TARGET, "Restore process finished after {} milliseconds", start_time.elapsed().as_millis());
+    Ok(())
+}
+
+fn restore_nodes(config: &Configuration, snapshot: &Snapshot, root: &Path) -> Result<(), Error> {
+    std::fs::create_dir_all(root)?;
+    let mut restored = Vec::new();
+    for node in snapshot.nodes.iter() {
+        let relative = Path::new(node.path.as_str());
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(UnsafeArchivePath(node.path.clone()));
+        }
+        check_ancestors(root, relative)?;
+        let path = root.join(relative);
+        trace!(target: LOG_TARGET, "Restoring {}", path.to_string_lossy());
+        // Replace existing symlinks rather than following them, and files replaced by a symlink
+        if let Ok(existing) = std::fs::symlink_metadata(path.as_path()) {
+            if existing.file_type().is_symlink()
+                || (node.kind.eq(&NodeKind::Symlink) && !existing.is_dir())
+            {
+                std::fs::remove_file(path.as_path())?;
+            }
+        }
+        match node.kind {
+            NodeKind::Dir => std::fs::create_dir_all(path.as_path())?,
+            NodeKind::Symlink => {
+                std::os::unix::fs::symlink(
+                    node.target.as_deref().unwrap_or_default(),
+                    path.as_path(),
+                )?;
+            }
+            NodeKind::File => {
+                let mut file = BufWriter::new(File::create(path.as_path())?);
+                for hash in node.chunks.iter() {
+                    file.write_all(read_chunk(config, hash.as_str())?.as_slice())?;
+                }
+                file.flush()?;
+            }
+        }
+        restored.push((path, node));
+    }
+
+    // Apply metadata deepest first so restoring a directory's contents cannot change it afterward
+    for (path, node) in restored.iter().rev() {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| UnsafeArchivePath(node.path.clone()))?;
+        // SAFETY: c_path is a valid nul terminated string that outlives the call
+        if unsafe { libc::lchown(c_path.as_ptr(), node.uid, node.gid) } != 0 {
+            debug!(target: LOG_TARGET, "Unable to set owner of {}: {}", path.to_string_lossy(), std::io::Error::last_os_error());
+        }
+        if node.kind.ne(&NodeKind::Symlink) {
+            std::fs::set_permissions(path, Permissions::from_mode(node.mode))?;
+        }
+        let mtime = filetime::FileTime::from_unix_time(node.mtime, 0);
+        filetime::set_symlink_file_times(path, mtime, mtime)?;
+    }
+    debug!(target: LOG_TARGET, "Restored {} entries to {}", restored.len(), root.to_string_lossy());
+    Ok(())
+}
+
+/// Apply the retention policy to the snapshots in the repository stored with the configured archive
+/// prefix, then delete every chunk that is no longer referenced by any snapshot. When `dry_run` is
+/// set nothing is deleted.
+pub fn prune_repository(config: &Configuration, dry_run: bool) -> Result<(), Error> {
+    let start_time = Instant::now();
+    let snapshots = load_snapshots(config)?;
+    let mut deleted_snapshots = HashSet::new();
+
+    if config.retention.is_enabled() {
+        let mut catalog = Catalog::load(config)?;
+        let groups = retention_groups(config.archive_prefix.as_str(), &snapshots)?;
+        let result = prune(config, groups, dry_run, |archive| {
+            let id = archive.path.to_string_lossy().to_string();
+            let path = snapshot_path(config, id.as_str());
+            std::fs::remove_file(path.as_path())?;
+            let name = path.file_name().unwrap_or_default();
+            catalog.remove(name.to_string_lossy().as_ref());
+            deleted_snapshots.insert(id);
+            Ok(())
+        });
+        // Keep the catalog in step with the snapshots deleted before any failure
+        if !deleted_snapshots.is_empty() {
+            catalog.save(config)?;
+        }
+        result?;
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let referenced: HashSet<&str> = snapshots
+        .iter()
+        .filter(|(id, _)| !deleted_snapshots.contains(*id))
+        .flat_map(|(_, s)| s.nodes.iter())
+        .flat_map(|n| n.chunks.iter().map(String::as_str))
+        .collect();
+    let chunks_dir = config.repository_dir.join(CHUNKS_DIR);
+    let mut deleted_chunks = 0;
+    if chunks_dir.is_dir() {
+        for directory in std::fs::read_dir(chunks_dir)? {
+            let directory = directory?.path();
+            if !directory.is_dir() {
+                continue;
+            }
+            for chunk in std::fs::read_dir(directory)? {
+                let chunk = chunk?.path();
+                let name = chunk.file_name().unwrap_or_default().to_string_lossy();
+                if !referenced.contains(name.as_ref()) {
+                    trace!(target: LOG_TARGET, "Deleting unreferenced chunk {}", name);
+                    std::fs::remove_file(chunk.as_path())?;
+                    deleted_chunks += 1;
+                }
+            }
+        }
+    }
+    info!(target: LOG_TARGET, "Prune deleted {} snapshots and {} chunks after {} milliseconds", deleted_snapshots.len(), deleted_chunks, start_time.elapsed().as_millis());
+    Ok(())
+}
+
+/// Group the snapshots stored with `prefix` by volume for the retention policy, newest first.
+/// Snapshots stored with other prefixes in the same repository are left to their own policies.
+fn retention_groups(
+    prefix: &str,
+    snapshots: &BTreeMap<String, Snapshot>,
+) -> Result<BTreeMap<Option<String>, Vec<ArchiveFile>>, Error> {
+    let mut groups: BTreeMap<Option<String>, Vec<ArchiveFile>> = BTreeMap::new();
+    for (id, snapshot) in snapshots.iter() {
+        if snapshot_prefix(id, snapshot).ne(&Some(prefix)) {
+            continue;
+        }
+        let timestamp = PrimitiveDateTime::parse(snapshot.time.as_str(), TIMESTAMP_FORMAT)
+            .map_err(time::error::Error::from)?;
+        groups
+            .entry(Some(snapshot.volume.clone()))
+            .or_default()
+            .push(ArchiveFile {
+                path: PathBuf::from(id),
+                volume: Some(snapshot.volume.clone()),
+                timestamp,
+                incremental: false,
+            });
+    }
+    for archives in groups.values_mut() {
+        archives.sort_by_key(|a| std::cmp::Reverse(a.timestamp));
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{CatalogArchive, CatalogRun};
+    use crate::configuration::{validate_config, ArchiveStrategy, Settings};
+    use crate::{BACKUP_DIR_ENV, DATA_DIR_ENV, RETENTION_KEEP_LAST_ENV};
+
+    fn config(directory: &Path) -> Configuration {
+        let mut settings = Settings::default();
+        let directory = directory.to_string_lossy();
+        settings.set_override(DATA_DIR_ENV, directory.as_ref(), "--data-dir");
+        settings.set_override(BACKUP_DIR_ENV, directory.as_ref(), "--backup-dir");
+        validate_config(&settings).unwrap()
+    }
+
+    /// Data that does not repeat, so chunk boundaries come from the content
+    fn data(length: usize) -> Vec<u8> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn reassemble(config: &Configuration, hashes: &[String]) -> Vec<u8> {
+        hashes
+            .iter()
+            .flat_map(|hash| read_chunk(config, hash.as_str()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        let original = data(6 * AVG_CHUNK_SIZE as usize);
+        let path = directory.path().join("file");
+        std::fs::write(path.as_path(), original.as_slice()).unwrap();
+
+        let mut stats = ChunkStats::default();
+        let hashes = store_chunks(&config, path.as_path(), &mut stats).unwrap();
+        assert!(hashes.len() > 1);
+        assert_eq!(stats.new_chunks, hashes.len());
+        assert_eq!(stats.reused_chunks, 0);
+        assert!(reassemble(&config, hashes.as_slice()) == original);
+
+        let mut stats = ChunkStats::default();
+        let again = store_chunks(&config, path.as_path(), &mut stats).unwrap();
+        assert_eq!(again, hashes);
+        assert_eq!(stats.new_chunks, 0);
+        assert_eq!(stats.reused_chunks, hashes.len());
+    }
+
+    #[test]
+    fn reuses_the_chunks_around_a_change() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        let mut original = data(8 * AVG_CHUNK_SIZE as usize);
+        let path = directory.path().join("file");
+        std::fs::write(path.as_path(), original.as_slice()).unwrap();
+        let hashes = store_chunks(&config, path.as_path(), &mut ChunkStats::default()).unwrap();
+
+        // Inserting bytes shifts the rest of the file but only changes the chunks around them
+        original.splice(original.len() / 2..original.len() / 2, *b"inserted");
+        std::fs::write(path.as_path(), original.as_slice()).unwrap();
+        let mut stats = ChunkStats::default();
+        let changed = store_chunks(&config, path.as_path(), &mut stats).unwrap();
+        assert!(stats.new_chunks <= 2);
+        assert!(stats.reused_chunks >= hashes.len() - 2);
+        assert!(reassemble(&config, changed.as_slice()) == original);
+    }
+
+    #[test]
+    fn detects_a_corrupted_chunk() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        let path = directory.path().join("file");
+        std::fs::write(path.as_path(), data(1024)).unwrap();
+        let hashes = store_chunks(&config, path.as_path(), &mut ChunkStats::default()).unwrap();
+
+        let chunk = chunk_path(&config, hashes[0].as_str());
+        std::fs::write(chunk, zstd::encode_all(&b"corrupted"[..], 0).unwrap()).unwrap();
+        assert!(matches!(
+            read_chunk(&config, hashes[0].as_str()),
+            Err(ChecksumMismatch(..))
+        ));
+    }
+
+    fn snapshot(prefix: Option<&str>, volume: &str, time: &str) -> Snapshot {
+        Snapshot {
+            prefix: prefix.map(str::to_string),
+            volume: volume.to_string(),
+            time: time.to_string(),
+            nodes: Vec::new(),
+        }
+    }
+
+    fn ids(groups: &BTreeMap<Option<String>, Vec<ArchiveFile>>, volume: &str) -> Vec<String> {
+        groups
+            .get(&Some(volume.to_string()))
+            .map(|archives| {
+                archives
+                    .iter()
+                    .map(|a| a.path.to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn groups_only_snapshots_with_the_prefix() {
+        let snapshots = BTreeMap::from([
+            (
+                "backup_db_2024-01-01_00-00-00".to_string(),
+                snapshot(Some("backup"), "db", "2024-01-01_00-00-00"),
+            ),
+            (
+                "backup_db_2024-01-02_00-00-00".to_string(),
+                snapshot(Some("backup"), "db", "2024-01-02_00-00-00"),
+            ),
+            (
+                "other_db_2024-01-03_00-00-00".to_string(),
+                snapshot(Some("other"), "db", "2024-01-03_00-00-00"),
+            ),
+        ]);
+        let groups = retention_groups("backup", &snapshots).unwrap();
+        assert_eq!(
+            ids(&groups, "db"),
+            [
+                "backup_db_2024-01-02_00-00-00",
+                "backup_db_2024-01-01_00-00-00"
+            ]
+        );
+        let groups = retention_groups("other", &snapshots).unwrap();
+        assert_eq!(ids(&groups, "db"), ["other_db_2024-01-03_00-00-00"]);
+    }
+
+    #[test]
+    fn takes_the_prefix_of_older_snapshots_from_the_id() {
+        let snapshots = BTreeMap::from([
+            (
+                "backup_db_2024-01-01_00-00-00".to_string(),
+                snapshot(None, "db", "2024-01-01_00-00-00"),
+            ),
+            (
+                "backup_x_db_2024-01-01_00-00-00".to_string(),
+                snapshot(None, "db", "2024-01-01_00-00-00"),
+            ),
+        ]);
+        let groups = retention_groups("backup", &snapshots).unwrap();
+        assert_eq!(ids(&groups, "db"), ["backup_db_2024-01-01_00-00-00"]);
+        let groups = retention_groups("backup_x", &snapshots).unwrap();
+        assert_eq!(ids(&groups, "db"), ["backup_x_db_2024-01-01_00-00-00"]);
+    }
+
+    /// Store a snapshot of the `db` volume in `directory` and record it in the catalog
+    fn store(config: &Configuration, directory: &Path, timestamp: &str) -> String {
+        let mut written = Vec::new();
+        let volume = directory.join("db");
+        repository_archive(
+            vec![(OsString::from("db"), volume)],
+            config,
+            timestamp,
+            &mut written,
+        )
+        .unwrap();
+        let archive = written.remove(0);
+        let run = CatalogRun {
+            run_id: timestamp.to_string(),
+            started_at: timestamp.to_string(),
+            finished_at: timestamp.to_string(),
+            strategy: ArchiveStrategy::Repository.to_string(),
+            compression: String::new(),
+            compression_level: 0,
+            encrypted: false,
+            archives: vec![CatalogArchive {
+                path: archive.path.clone(),
+                volume: archive.volume,
+                size: archive.size,
+                file_count: archive.file_count,
+                checksum: archive.checksum,
+                incremental: false,
+                base: None,
+                parent: None,
+                container_ids: Vec::new(),
+            }],
+        };
+        Catalog::record(config, &run).unwrap();
+        archive
+            .path
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    fn cataloged(config: &Configuration) -> Vec<String> {
+        let catalog = Catalog::load(config).unwrap();
+        catalog
+            .runs
+            .iter()
+            .flat_map(|r| r.archives.iter().map(CatalogArchive::name))
+            .collect()
+    }
+
+    #[test]
+    fn gives_snapshots_of_the_same_second_their_own_ids() {
+        let directory = tempfile::tempdir().unwrap();
+        let config = config(directory.path());
+        std::fs::create_dir(directory.path().join("db")).unwrap();
+        std::fs::write(directory.path().join("db/a"), "a").unwrap();
+        let first = store(&config, directory.path(), "2024-01-01_00-00-00");
+        let second = store(&config, directory.path(), "2024-01-01_00-00-00");
+        assert_eq!(first, "salvage_db_2024-01-01_00-00-00.json");
+        assert_eq!(second, "salvage_db_2024-01-01_00-00-00_2.json");
+
+        let snapshots = load_snapshots(&config).unwrap();
+        assert_eq!(snapshots.len(), 2);
+        let groups = retention_groups("salvage", &snapshots).unwrap();
+        assert_eq!(ids(&groups, "db").len(), 2);
+    }
+
+    #[test]
+    fn removes_pruned_snapshots_from_the_catalog() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut settings = Settings::default();
+        let path = directory.path().to_string_lossy();
+        settings.set_override(DATA_DIR_ENV, path.as_ref(), "--data-dir");
+        settings.set_override(BACKUP_DIR_ENV, path.as_ref(), "--backup-dir");
+        settings.set_override(RETENTION_KEEP_LAST_ENV, 1, "--keep-last");
+        let config = validate_config(&settings).unwrap();
+        std::fs::create_dir(directory.path().join("db")).unwrap();
+        std::fs::write(directory.path().join("db/a"), "a").unwrap();
+        let old = store(&config, directory.path(), "2024-01-01_00-00-00");
+        let new = store(&config, directory.path(), "2024-01-02_00-00-00");
+
+        prune_repository(&config, true).unwrap();
+        assert_eq!(cataloged(&config), [old.clone(), new.clone()]);
+        prune_repository(&config, false).unwrap();
+        assert_eq!(cataloged(&config), [new]);
+        assert_eq!(load_snapshots(&config).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::catalog::Catalog;
+use crate::configuration::{ArchiveCompression, Configuration};
+use crate::docker::RestartGuard;
+use crate::encryption::open_archive;
+use crate::error::Error;
+use crate::error::Error::{InvalidArguments, UnknownArchiveType, UnsafeArchivePath};
+use crate::incremental::{
+    incremental_chain, is_deleted_list, is_incremental, unescape, DELETED_ENTRY,
+};
+use crate::retention::ArchiveFile;
+use crate::{select_decoder, LOG_TARGET};
+use clap::Args;
+use log::{debug, info, trace, warn};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+use tokio::runtime::Runtime;
+
+#[derive(Args)]
+pub struct RestoreOptions {
+    /// Archive to restore, as a path or a name in the archive directory
+    pub archive: PathBuf,
+    /// Volume to restore into, when it cannot be read from the catalog or the archive name
+    #[arg(long, value_name = "NAME")]
+    pub volume: Option<String>,
+    /// Directory to extract into instead of the volume directory
+    #[arg(long, value_name = "DIR")]
+    pub target: Option<PathBuf>,
+    /// age identity file used to decrypt the archive
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<PathBuf>,
+}
+
+/// Restore an archive into the data directory, stopping any containers using the restored
+/// volumes while extracting and starting them again afterward.
+pub fn restore(
+    config: &Configuration,
+    runtime: &Runtime,
+    options: RestoreOptions,
+) -> Result<(), Error> {
+    let start_time = Instant::now();
+    let archive_path = resolve_archive(config, options.archive.as_path());
+    info!(target: LOG_TARGET, "Restore of {} started", archive_path.to_string_lossy());
+
+    // The volume of an archive is recorded in the catalog, or only in the name of an archive
+    // written before the catalog existed
+    let catalog = Catalog::load(config)?;
+    let archive_name = archive_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let cataloged = catalog.find(archive_name.as_str()).map(|(_, a)| a);
+    let volume = options.volume.or_else(|| match cataloged {
+        Some(archive) => archive.volume.clone(),
+        None => ArchiveFile::parse(archive_path.as_path(), config.archive_prefix.as_str())
+            .and_then(|a| a.volume),
+    });
+    let restore_path = match (options.target.as_ref(), volume.as_ref()) {
+        (Some(_), None) => {
+            return Err(InvalidArguments(
+                "--target requires --volume for archives with multiple volumes".into(),
+            ))
+        }
+        (Some(target), Some(_)) => target.clone(),
+        (None, Some(volume)) => config.data_dir.join(volume),
+        (None, None) => config.data_dir.clone(),
+    };
+    debug!(target: LOG_TARGET, "Restore path: {}", restore_path.to_string_lossy());
+
+    // Stop containers that contain volumes that are being restored
+    let mut restart_guard = RestartGuard::new(runtime, config.container_management_enabled())?;
+    if let Err(error) = restart_guard.pre_restore(restore_path.as_path()) {
+        return restart_guard.finish(Err(error));
+    }
+
+    let identity = options
+        .identity
+        .as_deref()
+        .or(config.encryption.identity_file.as_deref());
+    // An incremental archive is restored by replaying its full archive and every incremental
+    // archive up to and including it. The chain is followed in the catalog, or found from the
+    // archive names for archives cataloged before the archive each builds on was recorded.
+    let directory = archive_path.parent().unwrap_or(Path::new("."));
+    let result = match cataloged {
+        Some(archive) if archive.incremental && archive.parent.is_some() => catalog
+            .incremental_chain(archive_name.as_str())
+            .map(|chain| chain.iter().map(|name| directory.join(name)).collect()),
+        Some(archive) if !archive.incremental => Ok(vec![archive_path.clone()]),
+        _ if is_incremental(archive_path.as_path()) => {
+            incremental_chain(archive_path.as_path(), config.archive_prefix.as_str())
+        }
+        _ => Ok(vec![archive_path.clone()]),
+    }
+    .and_then(|chain| {
+        chain.iter().try_for_each(|archive| {
+            extract(
+                archive.as_path(),
+                identity,
+                volume.as_deref(),
+                options.target.as_deref(),
+                config.data_dir.as_path(),
+            )
+        })
+    });
+
+    // Start containers that were stopped for restoring.
+    restart_guard.finish(result)?;
+    info!(target: LOG_TARGET, "Restore process finished after {} milliseconds", start_time.elapsed().as_millis());
+    Ok(())
+}
+
+/// Use the archive path as provided when it exists, otherwise look for it in the backup directory
+fn resolve_archive(config: &Configuration, archive: &Path) -> PathBuf {
+    match archive.exists() {
+        true => archive.to_path_buf(),
+        false => config.backup_dir.join(archive),
+    }
+}
+
+/// Get the compression of an archive from the extension following `.tar.`, ignoring any
+/// encryption extension
+pub fn archive_compression<P: AsRef<Path>>(path: P) -> Result<ArchiveCompression, Error> {
+    let file_name = path.as_ref().to_string_lossy();
+    file_name
+        .rsplit_once(".tar.")
+        .map(|(_, extension)| extension.split('.').next().unwrap_or(extension))
+        .and_then(ArchiveCompression::from_extension)
+        .ok_or_else(|| UnknownArchiveType(file_name.to_string()))
+}
+
+/// Extract every entry of the archive. The first path component of each entry is the volume
+/// name, which is replaced by the target directory or the volume directory under the data directory.
+fn extract(
+    archive_path: &Path,
+    identity: Option<&Path>,
+    volume: Option<&str>,
+    target: Option<&Path>,
+    data_dir: &Path,
+) -> Result<(), Error> {
+    let compression = archive_compression(archive_path)?;
+    let decoder = select_decoder(open_archive(archive_path, identity)?, &compression)?;
+    let mut archive = tar::Archive::new(decoder);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+    archive.set_preserve_ownerships(true);
+    archive.set_overwrite(true);
+    let incremental = is_incremental(archive_path);
+    info!(target: LOG_TARGET, "Extracting {}", archive_path.to_string_lossy());
+
+    let mut restored = 0;
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let mut components = entry_path.components();
+        let entry_volume = match components.next() {
+            Some(Component::Normal(name)) => name.to_string_lossy().to_string(),
+            _ => return Err(UnsafeArchivePath(entry_path.to_string_lossy().into())),
+        };
+        if volume.is_some_and(|v| v.ne(entry_volume.as_str())) {
+            trace!(target: LOG_TARGET, "Skipping {} from volume {}", entry_path.to_string_lossy(), entry_volume);
+            continue;
+        }
+
+        let relative = components.as_path();
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(UnsafeArchivePath(entry_path.to_string_lossy().into()));
+        }
+        let root = match target {
+            Some(target) => target.to_path_buf(),
+            None => data_dir.join(entry_volume.as_str()),
+        };
+        check_ancestors(root.as_path(), relative)?;
+        let destination = root.join(relative);
+
+        if incremental && relative.eq(Path::new(DELETED_ENTRY)) && is_deleted_list(&mut entry)? {
+            let mut deleted = Vec::new();
+            entry.read_to_end(&mut deleted)?;
+            remove_deleted(root.as_path(), deleted.as_slice())?;
+            continue;
+        }
+        // Hard links name their target relative to the working directory rather than the volume
+        if entry.header().entry_type().is_hard_link() {
+            return Err(UnsafeArchivePath(entry_path.to_string_lossy().into()));
+        }
+
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        // Replace an existing symlink rather than writing through it
+        if std::fs::symlink_metadata(destination.as_path()).is_ok_and(|m| m.is_symlink()) {
+            std::fs::remove_file(destination.as_path())?;
+        }
+        trace!(target: LOG_TARGET, "Restoring {} to {}", entry_path.to_string_lossy(), destination.to_string_lossy());
+        entry.unpack(destination.as_path())?;
+        restored += 1;
+    }
+
+    match restored {
+        0 => {
+            warn!(target: LOG_TARGET, "No entries were restored from {}", archive_path.to_string_lossy())
+        }
+        _ => {
+            debug!(target: LOG_TARGET, "Restored {} entries from {}", restored, archive_path.to_string_lossy())
+        }
+    }
+    Ok(())
+}
+
+/// Remove the paths listed in the deleted entry of an incremental archive from the volume
+fn remove_deleted(base: &Path, deleted: &[u8]) -> Result<(), Error> {
+    for line in deleted.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        let relative = unescape(line);
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(UnsafeArchivePath(relative.to_string_lossy().into()));
+        }
+        check_ancestors(base, relative.as_path())?;
+        let path = base.join(relative);
+        match std::fs::symlink_metadata(path.as_path()) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path.as_path())?,
+            Ok(_) => std::fs::remove_file(path.as_path())?,
+            Err(_) => continue,
+        }
+        trace!(target: LOG_TARGET, "Removed deleted path {}", path.to_string_lossy());
+    }
+    Ok(())
+}
+
+/// Check that no directory between `root` and the entry at `relative` below it is a symlink, so an
+/// entry cannot be written outside of `root` through a symlink already on disk or one restored by
+/// an earlier entry
+pub fn check_ancestors(root: &Path, relative: &Path) -> Result<(), Error> {
+    let mut path = root.to_path_buf();
+    if let Some(parent) = relative.parent() {
+        for component in parent.components() {
+            path.push(component);
+            if std::fs::symlink_metadata(path.as_path()).is_ok_and(|m| m.is_symlink()) {
+                return Err(UnsafeArchivePath(relative.to_string_lossy().into()));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{validate_config, Configuration, Settings};
+    use crate::incremental::incremental_archive;
+    use crate::{BACKUP_DIR_ENV, DATA_DIR_ENV};
+    use flate2::write::GzEncoder;
+    use std::ffi::OsString;
+    use std::fs::File;
+    use tar::{EntryType, Header};
+
+    const ARCHIVE: &str = "salvage_app_2024-01-01_00-00-00.tar.gz";
+
+    fn write_archive<F>(path: &Path, build: F)
+    where
+        F: FnOnce(&mut tar::Builder<GzEncoder<File>>),
+    {
+        let encoder = GzEncoder::new(File::create(path).unwrap(), flate2::Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        build(&mut builder);
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn header() -> Header {
+        let mut header = Header::new_gnu();
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    fn append_file(builder: &mut tar::Builder<GzEncoder<File>>, path: &str, data: &[u8]) {
+        let mut header = header();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn append_symlink(builder: &mut tar::Builder<GzEncoder<File>>, path: &str, target: &Path) {
+        let mut header = header();
+        header.set_entry_type(EntryType::Symlink);
+        header.set_size(0);
+        header.set_mode(0o777);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    fn directories() -> (tempfile::TempDir, PathBuf, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(data.join("app")).unwrap();
+        std::fs::create_dir(outside.as_path()).unwrap();
+        (dir, data, outside)
+    }
+
+    #[test]
+    fn refuses_child_of_restored_symlink() {
+        let (dir, data, outside) = directories();
+        let archive = dir.path().join(ARCHIVE);
+        write_archive(archive.as_path(), |builder| {
+            append_symlink(builder, "app/a", outside.as_path());
+            append_file(builder, "app/a/passwd", b"root");
+        });
+
+        let result = extract(archive.as_path(), None, None, None, data.as_path());
+        assert!(matches!(result, Err(UnsafeArchivePath(_))));
+        assert!(!outside.join("passwd").exists());
+    }
+
+    #[test]
+    fn refuses_child_of_symlink_on_disk() {
+        let (dir, data, outside) = directories();
+        std::os::unix::fs::symlink(outside.as_path(), data.join("app/a")).unwrap();
+        let archive = dir.path().join(ARCHIVE);
+        write_archive(archive.as_path(), |builder| {
+            append_file(builder, "app/a/passwd", b"root");
+        });
+
+        let result = extract(archive.as_path(), None, None, None, data.as_path());
+        assert!(matches!(result, Err(UnsafeArchivePath(_))));
+        assert!(!outside.join("passwd").exists());
+    }
+
+    #[test]
+    fn replaces_symlink_instead_of_writing_through_it() {
+        let (dir, data, outside) = directories();
+        std::os::unix::fs::symlink(outside.join("file"), data.join("app/file")).unwrap();
+        let archive = dir.path().join(ARCHIVE);
+        write_archive(archive.as_path(), |builder| {
+            append_file(builder, "app/file", b"contents");
+        });
+
+        extract(archive.as_path(), None, None, None, data.as_path()).unwrap();
+        assert!(!outside.join("file").exists());
+        let restored = data.join("app/file");
+        assert!(!std::fs::symlink_metadata(restored.as_path())
+            .unwrap()
+            .is_symlink());
+        assert_eq!(std::fs::read(restored).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn restores_symlinks_and_files_inside_the_volume() {
+        let (dir, data, _outside) = directories();
+        let archive = dir.path().join(ARCHIVE);
+        write_archive(archive.as_path(), |builder| {
+            append_file(builder, "app/dir/file", b"contents");
+            append_symlink(builder, "app/link", Path::new("dir/file"));
+        });
+
+        extract(archive.as_path(), None, None, None, data.as_path()).unwrap();
+        assert_eq!(std::fs::read(data.join("app/link")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn restores_a_volume_file_named_like_the_deleted_list() {
+        let (dir, data, _outside) = directories();
+        let volume = data.join("app");
+        std::fs::write(volume.join(DELETED_ENTRY), "keep\n").unwrap();
+        std::fs::write(volume.join("keep"), "contents").unwrap();
+        std::fs::write(volume.join("gone"), "contents").unwrap();
+        let backup = dir.path().join("backup");
+        std::fs::create_dir(backup.as_path()).unwrap();
+        let mut settings = Settings::default();
+        settings.set_override(DATA_DIR_ENV, data.to_string_lossy(), "--data-dir");
+        settings.set_override(BACKUP_DIR_ENV, backup.to_string_lossy(), "--backup-dir");
+        let config: Configuration = validate_config(&settings).unwrap();
+
+        // A full archive, then an incremental archive recording the deletion of `gone`
+        let mut written = Vec::new();
+        for timestamp in ["2024-01-01_00-00-00", "2024-01-02_00-00-00"] {
+            let directories = vec![(OsString::from("app"), volume.clone())];
+            let first = written.len();
+            incremental_archive(directories, &config, timestamp, &mut written).unwrap();
+            for archive in written[first..].iter() {
+                archive.index.as_ref().unwrap().save().unwrap();
+            }
+            std::fs::remove_file(volume.join("gone")).ok();
+            // Rewriting the file puts it in the incremental archive too
+            std::fs::write(volume.join(DELETED_ENTRY), "keep\n").unwrap();
+        }
+        assert!(is_incremental(written[1].path.as_path()));
+
+        let target = dir.path().join("restored");
+        for archive in written.iter() {
+            extract(
+                archive.path.as_path(),
+                None,
+                Some("app"),
+                Some(target.as_path()),
+                data.as_path(),
+            )
+            .unwrap();
+        }
+        assert_eq!(
+            std::fs::read(target.join(DELETED_ENTRY)).unwrap(),
+            b"keep\n"
+        );
+        assert!(target.join("keep").is_file());
+        assert!(!target.join("gone").exists());
+    }
+}
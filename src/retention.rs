@@ -0,0 +1,633 @@
+use crate::catalog::Catalog;
+use crate::checksum::sidecar_path;
+use crate::configuration::{ArchiveCompression, Configuration};
+use crate::encryption::ENCRYPTED_EXTENSION;
+use crate::error::Error;
+use crate::incremental::INCREMENTAL_EXTENSION;
+use crate::{volume_directories, LOG_TARGET, TIMESTAMP_FORMAT};
+use log::{debug, info};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use time::{Duration, OffsetDateTime, PrimitiveDateTime};
+
+/// Length of a timestamp produced with [`TIMESTAMP_FORMAT`]
+const TIMESTAMP_LENGTH: usize = 19;
+
+#[derive(Default)]
+pub struct RetentionPolicy {
+    pub keep_last: Option<u32>,
+    pub keep_daily: Option<u32>,
+    pub keep_weekly: Option<u32>,
+    pub keep_monthly: Option<u32>,
+    pub keep_yearly: Option<u32>,
+    pub max_age_days: Option<u32>,
+    pub dry_run: bool,
+}
+
+/// An archive found in the backup directory that was created by Salvage
+pub struct ArchiveFile {
+    pub path: PathBuf,
+    pub volume: Option<String>,
+    pub timestamp: PrimitiveDateTime,
+    pub incremental: bool,
+}
+
+/// Calendar period used by the grandfather-father-son keep rules
+#[derive(Copy, Clone)]
+enum Period {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// Outcome of the retention policy for a single archive along with the reason for it
+pub enum Decision {
+    Keep(String),
+    Delete(String),
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.has_keep_rules() || self.max_age_days.is_some()
+    }
+
+    fn has_keep_rules(&self) -> bool {
+        self.keep_last.is_some()
+            || self.keep_daily.is_some()
+            || self.keep_weekly.is_some()
+            || self.keep_monthly.is_some()
+            || self.keep_yearly.is_some()
+    }
+
+    /// Decide which archives of a single volume to keep. Archives must be sorted newest first.
+    /// An archive selected by a keep rule is kept whatever its age. Any other archive is deleted
+    /// when it is older than the max age or when keep rules are configured, unless a kept
+    /// incremental archive depends on it. The newest archive is always kept, so a volume that has
+    /// not been archived for longer than the max age keeps its last archive.
+    pub fn decide(&self, archives: &[ArchiveFile], now: PrimitiveDateTime) -> Vec<Decision> {
+        let mut reasons: Vec<Vec<&str>> = vec![Vec::new(); archives.len()];
+        if let Some(keep) = self.keep_last {
+            reasons
+                .iter_mut()
+                .take(keep as usize)
+                .for_each(|r| r.push("last"));
+        }
+
+        let periods = [
+            (Period::Daily, self.keep_daily),
+            (Period::Weekly, self.keep_weekly),
+            (Period::Monthly, self.keep_monthly),
+            (Period::Yearly, self.keep_yearly),
+        ];
+        for (period, keep) in periods {
+            let Some(keep) = keep else { continue };
+            let mut last_key = None;
+            let mut selected = 0;
+            for (index, archive) in archives.iter().enumerate() {
+                if selected >= keep {
+                    break;
+                }
+                let key = period.key(&archive.timestamp);
+                if last_key != Some(key) {
+                    last_key = Some(key);
+                    reasons[index].push(period.name());
+                    selected += 1;
+                }
+            }
+        }
+
+        let mut decisions: Vec<Decision> = archives
+            .iter()
+            .zip(reasons)
+            .map(|(archive, reasons)| {
+                let max_age = self
+                    .max_age_days
+                    .filter(|days| now - archive.timestamp > Duration::days((*days).into()));
+                match max_age {
+                    _ if !reasons.is_empty() => Decision::Keep(reasons.join(", ")),
+                    Some(days) => Decision::Delete(format!("older than {} days", days)),
+                    None if !self.has_keep_rules() => Decision::Keep("within max age".into()),
+                    None => Decision::Delete("not selected by any keep rule".into()),
+                }
+            })
+            .collect();
+        if let Some(decision @ Decision::Delete(_)) = decisions.first_mut() {
+            *decision = Decision::Keep("newest archive".into());
+        }
+
+        // A kept incremental archive cannot be restored without the older archives it builds on
+        for index in 0..archives.len() {
+            if !archives[index].incremental || matches!(decisions[index], Decision::Delete(_)) {
+                continue;
+            }
+            let name = archives[index].path.file_name().unwrap_or_default();
+            for base in index + 1..archives.len() {
+                if let Decision::Delete(_) = decisions[base] {
+                    decisions[base] = Decision::Keep(format!("base of {}", name.to_string_lossy()));
+                }
+                if !archives[base].incremental {
+                    break;
+                }
+            }
+        }
+        decisions
+    }
+}
+
+impl Period {
+    /// Key identifying the period an archive timestamp falls in
+    fn key(&self, timestamp: &PrimitiveDateTime) -> (i32, u16) {
+        let date = timestamp.date();
+        match self {
+            Period::Daily => (date.year(), date.ordinal()),
+            Period::Weekly => {
+                let (year, week, _) = date.to_iso_week_date();
+                (year, week.into())
+            }
+            Period::Monthly => (date.year(), u8::from(date.month()).into()),
+            Period::Yearly => (date.year(), 0),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Period::Daily => "daily",
+            Period::Weekly => "weekly",
+            Period::Monthly => "monthly",
+            Period::Yearly => "yearly",
+        }
+    }
+}
+
+impl ArchiveFile {
+    /// Parse the path of an archive named `{prefix}_{name}_{timestamp}.tar.{ext}` for the `Multiple`
+    /// strategy or `{prefix}_{timestamp}.tar.{ext}` for the `Single` strategy. Encrypted archives
+    /// have an additional `.age` extension and incremental archives have `.incr` before `.tar`.
+    pub fn parse<P: AsRef<Path>, S: AsRef<str>>(path: P, prefix: S) -> Option<Self> {
+        let file_name = path.as_ref().file_name()?.to_str()?;
+        let (stem, extension) = file_name.rsplit_once(".tar.")?;
+        let extension = extension
+            .strip_suffix(ENCRYPTED_EXTENSION)
+            .and_then(|e| e.strip_suffix('.'))
+            .unwrap_or(extension);
+        ArchiveCompression::from_extension(extension)?;
+
+        let (stem, incremental) = match stem
+            .strip_suffix(INCREMENTAL_EXTENSION)
+            .and_then(|s| s.strip_suffix('.'))
+        {
+            Some(stem) => (stem, true),
+            None => (stem, false),
+        };
+
+        let remainder = stem.strip_prefix(prefix.as_ref())?.strip_prefix('_')?;
+        let split = remainder.len().checked_sub(TIMESTAMP_LENGTH)?;
+        let (volume, timestamp) = (remainder.get(..split)?, remainder.get(split..)?);
+        let timestamp = PrimitiveDateTime::parse(timestamp, TIMESTAMP_FORMAT).ok()?;
+        let volume = match volume {
+            "" => None,
+            v => Some(v.strip_suffix('_').filter(|v| !v.is_empty())?.to_string()),
+        };
+
+        Some(Self {
+            path: path.as_ref().to_path_buf(),
+            volume,
+            timestamp,
+            incremental,
+        })
+    }
+}
+
+/// Names of the volumes that archives can be for: the volume directories in the data directory and
+/// the volumes recorded in the catalog
+pub fn known_volumes(config: &Configuration, catalog: &Catalog) -> HashSet<String> {
+    volume_directories(config)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, _)| name.to_string_lossy().to_string())
+        .chain(catalog.archives().filter_map(|(_, a)| a.volume.clone()))
+        .collect()
+}
+
+/// Group the archives created with the configured prefix by volume. Archives created by the
+/// `Single` strategy are grouped under `None`. Each group is sorted newest first.
+///
+/// A name only tells the prefix apart from the volume when the volume is known, as the archives of
+/// prefix `backup_db` for volume `data` are also named like archives of prefix `backup` for volume
+/// `db_data`. Archives for a volume not in `volumes` are left out, as are archives without a volume
+/// when the prefix ends with `_` and a volume in `volumes`.
+pub fn group_archives<I: IntoIterator<Item = PathBuf>>(
+    paths: I,
+    prefix: &str,
+    volumes: &HashSet<String>,
+) -> BTreeMap<Option<String>, Vec<ArchiveFile>> {
+    let ambiguous_single = volumes.iter().any(|v| {
+        prefix
+            .strip_suffix(v.as_str())
+            .is_some_and(|p| p.len() > 1 && p.ends_with('_'))
+    });
+    let mut groups: BTreeMap<Option<String>, Vec<ArchiveFile>> = BTreeMap::new();
+    for archive in paths
+        .into_iter()
+        .filter_map(|p| ArchiveFile::parse(p, prefix))
+    {
+        match archive.volume.as_ref() {
+            Some(volume) if !volumes.contains(volume) => {
+                debug!(target: LOG_TARGET, "Ignoring archive {} for unknown volume {}", archive.path.to_string_lossy(), volume);
+                continue;
+            }
+            None if ambiguous_single => {
+                debug!(target: LOG_TARGET, "Ignoring archive {} as its name also matches a shorter prefix", archive.path.to_string_lossy());
+                continue;
+            }
+            _ => (),
+        }
+        groups
+            .entry(archive.volume.clone())
+            .or_default()
+            .push(archive);
+    }
+
+    for archives in groups.values_mut() {
+        archives.sort_by_key(|a| std::cmp::Reverse(a.timestamp));
+    }
+    groups
+}
+
+/// Find all archives in the backup directory created with the configured prefix grouped by volume.
+/// Archives are described by the catalog, while archives written before the catalog existed are
+/// recognised by their file name.
+pub fn find_archives(
+    config: &Configuration,
+    catalog: &Catalog,
+) -> Result<BTreeMap<Option<String>, Vec<ArchiveFile>>, Error> {
+    let mut paths = Vec::new();
+    let mut cataloged = Vec::new();
+    for entry in std::fs::read_dir(config.backup_dir.as_path())? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match catalog.find(name.as_ref()) {
+            Some((run, archive)) => {
+                cataloged.extend(ArchiveFile::from_catalog(config, run, archive))
+            }
+            None => paths.push(path),
+        }
+    }
+
+    let volumes = known_volumes(config, catalog);
+    let mut groups = group_archives(paths, config.archive_prefix.as_str(), &volumes);
+    for archive in cataloged {
+        groups
+            .entry(archive.volume.clone())
+            .or_default()
+            .push(archive);
+    }
+    for archives in groups.values_mut() {
+        archives.sort_by_key(|a| std::cmp::Reverse(a.timestamp));
+    }
+    Ok(groups)
+}
+
+/// Delete archives in the backup directory that fall outside the configured retention policy.
+/// When `dry_run` is set the decisions are only logged and no archive is deleted.
+pub fn apply_retention(config: &Configuration, dry_run: bool) -> Result<(), Error> {
+    if !config.retention.is_enabled() {
+        match dry_run {
+            true => info!(target: LOG_TARGET, "No retention policy configured"),
+            false => debug!(target: LOG_TARGET, "No retention policy configured"),
+        }
+        return Ok(());
+    }
+    let mut catalog = Catalog::load(config)?;
+    let groups = find_archives(config, &catalog)?;
+    let mut removed = false;
+    let result = prune(config, groups, dry_run, |archive| {
+        std::fs::remove_file(archive.path.as_path())?;
+        let sidecar = sidecar_path(archive.path.as_path());
+        if sidecar.is_file() {
+            std::fs::remove_file(sidecar)?;
+        }
+        let name = archive.path.file_name().unwrap_or_default();
+        catalog.remove(name.to_string_lossy().as_ref());
+        removed = true;
+        Ok(())
+    });
+    // Keep the catalog in step with the archives deleted before any failure
+    if removed {
+        catalog.save(config)?;
+    }
+    result
+}
+
+/// Log which archives in the backup directory the retention policy would keep or delete once the
+/// `planned` archives of a run have been written, without deleting anything
+pub fn preview_retention(config: &Configuration, planned: Vec<ArchiveFile>) -> Result<(), Error> {
+    if !config.retention.is_enabled() {
+        info!(target: LOG_TARGET, "No retention policy configured");
+        return Ok(());
+    }
+    let catalog = Catalog::load(config)?;
+    let mut groups = find_archives(config, &catalog)?;
+    for archive in planned {
+        groups
+            .entry(archive.volume.clone())
+            .or_default()
+            .insert(0, archive);
+    }
+    prune(config, groups, true, |_| Ok(()))
+}
+
+/// Apply the retention policy to the grouped archives, calling `delete` for each archive that
+/// falls outside of it. When `dry_run` is set the decisions are only logged.
+pub fn prune<F>(
+    config: &Configuration,
+    groups: BTreeMap<Option<String>, Vec<ArchiveFile>>,
+    dry_run: bool,
+    mut delete: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&ArchiveFile) -> Result<(), Error>,
+{
+    let start_time = Instant::now();
+    let now = OffsetDateTime::now_local()?;
+    let now = PrimitiveDateTime::new(now.date(), now.time());
+
+    let mut deleted = 0;
+    for (volume, archives) in groups {
+        let volume = volume.unwrap_or_else(|| "single archive".to_string());
+        let decisions = config.retention.decide(archives.as_slice(), now);
+        for (archive, decision) in archives.iter().zip(decisions) {
+            let path = archive.path.to_string_lossy();
+            match (decision, dry_run) {
+                (Decision::Keep(reason), true) => {
+                    info!(target: LOG_TARGET, "Would keep archive {} for {} ({})", path, volume, reason)
+                }
+                (Decision::Keep(reason), false) => {
+                    debug!(target: LOG_TARGET, "Keeping archive {} for {} ({})", path, volume, reason)
+                }
+                (Decision::Delete(reason), true) => {
+                    info!(target: LOG_TARGET, "Would delete archive {} for {} ({})", path, volume, reason)
+                }
+                (Decision::Delete(reason), false) => {
+                    info!(target: LOG_TARGET, "Deleting archive {} for {} ({})", path, volume, reason);
+                    delete(archive)?;
+                    deleted += 1;
+                }
+            }
+        }
+    }
+
+    debug!(target: LOG_TARGET, "Retention deleted {} archives after {} milliseconds", deleted, start_time.elapsed().as_millis());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(groups: &BTreeMap<Option<String>, Vec<ArchiveFile>>) -> Vec<(Option<&str>, String)> {
+        groups
+            .iter()
+            .flat_map(|(volume, archives)| {
+                archives
+                    .iter()
+                    .map(move |a| (volume.as_deref(), a.path.to_string_lossy().to_string()))
+            })
+            .collect()
+    }
+
+    fn at(timestamp: &str) -> PrimitiveDateTime {
+        PrimitiveDateTime::parse(timestamp, TIMESTAMP_FORMAT).unwrap()
+    }
+
+    fn archive(timestamp: &str, incremental: bool) -> ArchiveFile {
+        ArchiveFile {
+            path: PathBuf::from(format!("backup_db_{}.tar.gz", timestamp)),
+            volume: Some("db".to_string()),
+            timestamp: at(timestamp),
+            incremental,
+        }
+    }
+
+    fn decide(policy: &RetentionPolicy, archives: &[ArchiveFile], now: &str) -> Vec<String> {
+        policy
+            .decide(archives, at(now))
+            .into_iter()
+            .map(|decision| match decision {
+                Decision::Keep(reason) => format!("keep: {}", reason),
+                Decision::Delete(reason) => format!("delete: {}", reason),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_last_archives() {
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        let archives = [
+            archive("2024-01-03_00-00-00", false),
+            archive("2024-01-02_00-00-00", false),
+            archive("2024-01-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-01-04_00-00-00"),
+            [
+                "keep: last",
+                "keep: last",
+                "delete: not selected by any keep rule"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_newest_archive_of_each_period() {
+        let policy = RetentionPolicy {
+            keep_daily: Some(2),
+            keep_weekly: Some(2),
+            keep_monthly: Some(2),
+            keep_yearly: Some(3),
+            ..Default::default()
+        };
+        let archives = [
+            // Wednesday and Tuesday of the same ISO week
+            archive("2024-02-07_12-00-00", false),
+            archive("2024-02-07_00-00-00", false),
+            archive("2024-02-06_00-00-00", false),
+            archive("2024-02-05_00-00-00", false),
+            // Previous week, month and year
+            archive("2024-01-31_00-00-00", false),
+            archive("2024-01-30_00-00-00", false),
+            archive("2023-12-31_00-00-00", false),
+            archive("2022-06-01_00-00-00", false),
+            archive("2021-06-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-02-08_00-00-00"),
+            [
+                "keep: daily, weekly, monthly, yearly",
+                "delete: not selected by any keep rule",
+                "keep: daily",
+                "delete: not selected by any keep rule",
+                "keep: weekly, monthly",
+                "delete: not selected by any keep rule",
+                "keep: yearly",
+                "keep: yearly",
+                "delete: not selected by any keep rule",
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_rules_override_max_age() {
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            max_age_days: Some(7),
+            ..Default::default()
+        };
+        let archives = [
+            archive("2024-01-09_00-00-00", false),
+            archive("2024-01-02_00-00-00", false),
+            archive("2024-01-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-01-10_00-00-00"),
+            ["keep: last", "keep: last", "delete: older than 7 days"]
+        );
+    }
+
+    #[test]
+    fn max_age_keeps_the_newest_archive() {
+        let policy = RetentionPolicy {
+            max_age_days: Some(7),
+            ..Default::default()
+        };
+        // No archive was made for a month, for example while Docker was down
+        let archives = [
+            archive("2024-01-02_00-00-00", false),
+            archive("2024-01-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-02-01_00-00-00"),
+            ["keep: newest archive", "delete: older than 7 days"]
+        );
+    }
+
+    #[test]
+    fn max_age_alone_keeps_recent_archives() {
+        let policy = RetentionPolicy {
+            max_age_days: Some(7),
+            ..Default::default()
+        };
+        let archives = [
+            archive("2024-01-09_00-00-00", false),
+            archive("2024-01-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-01-10_00-00-00"),
+            ["keep: within max age", "delete: older than 7 days"]
+        );
+    }
+
+    #[test]
+    fn keeps_the_archives_a_kept_incremental_archive_builds_on() {
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+        let archives = [
+            archive("2024-01-04_00-00-00", true),
+            archive("2024-01-03_00-00-00", true),
+            archive("2024-01-02_00-00-00", false),
+            archive("2024-01-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-01-05_00-00-00"),
+            [
+                "keep: last",
+                "keep: base of backup_db_2024-01-04_00-00-00.tar.gz",
+                "keep: base of backup_db_2024-01-04_00-00-00.tar.gz",
+                "delete: not selected by any keep rule",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_base_of_an_incremental_archive_past_max_age() {
+        let policy = RetentionPolicy {
+            max_age_days: Some(2),
+            ..Default::default()
+        };
+        let archives = [
+            archive("2024-01-04_00-00-00", false),
+            archive("2024-01-03_00-00-00", true),
+            archive("2024-01-01_00-00-00", false),
+        ];
+        assert_eq!(
+            decide(&policy, &archives, "2024-01-05_00-00-00"),
+            [
+                "keep: within max age",
+                "keep: within max age",
+                "keep: base of backup_db_2024-01-03_00-00-00.tar.gz",
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_archive_names() {
+        let archive = ArchiveFile::parse("backup_db_2024-01-02_03-04-05.tar.gz", "backup").unwrap();
+        assert_eq!(archive.volume.as_deref(), Some("db"));
+        assert_eq!(
+            archive.timestamp,
+            PrimitiveDateTime::parse("2024-01-02_03-04-05", TIMESTAMP_FORMAT).unwrap()
+        );
+        assert!(!archive.incremental);
+
+        let archive =
+            ArchiveFile::parse("backup_2024-01-02_03-04-05.tar.zst.age", "backup").unwrap();
+        assert_eq!(archive.volume, None);
+
+        let archive =
+            ArchiveFile::parse("backup_db_2024-01-02_03-04-05.incr.tar.xz", "backup").unwrap();
+        assert!(archive.incremental);
+
+        assert!(ArchiveFile::parse("backup_db_2024-01-02_03-04-05.tar.rar", "backup").is_none());
+        assert!(ArchiveFile::parse("other_db_2024-01-02_03-04-05.tar.gz", "backup").is_none());
+        assert!(ArchiveFile::parse("backupdb_2024-01-02_03-04-05.tar.gz", "backup").is_none());
+    }
+
+    #[test]
+    fn leaves_out_archives_of_unknown_volumes() {
+        let paths = [
+            "backup_db_2024-01-01_00-00-00.tar.gz",
+            "backup_db_data_2024-01-01_00-00-00.tar.gz",
+            "backup_2024-01-01_00-00-00.tar.gz",
+        ]
+        .map(PathBuf::from);
+        let volumes = HashSet::from(["db".to_string(), "data".to_string()]);
+
+        assert_eq!(
+            names(&group_archives(paths.clone(), "backup", &volumes)),
+            [
+                (None, "backup_2024-01-01_00-00-00.tar.gz".to_string()),
+                (
+                    Some("db"),
+                    "backup_db_2024-01-01_00-00-00.tar.gz".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            names(&group_archives(paths, "backup_db", &volumes)),
+            [(
+                Some("data"),
+                "backup_db_data_2024-01-01_00-00-00.tar.gz".to_string()
+            )]
+        );
+    }
+}
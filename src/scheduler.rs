@@ -0,0 +1,87 @@
+use crate::configuration::{Configuration, DefaultEnv};
+use crate::error::Error;
+use crate::error::Error::{InvalidSchedule, NoScheduledRun};
+use crate::signals::SignalListener;
+use crate::{archive, LOG_TARGET};
+use chrono::{DateTime, Local};
+use log::{error, info};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use tokio::runtime::Runtime;
+
+const DEFAULT_SCHEDULE: &str = "0 0 * * *";
+
+/// A cron expression in the standard five field format. Six and seven field expressions with
+/// seconds and years are also accepted.
+pub struct CronSchedule {
+    expression: String,
+    schedule: cron::Schedule,
+}
+
+impl CronSchedule {
+    /// The next time the schedule fires in the local timezone set by `TZ`
+    pub fn next_run(&self) -> Result<DateTime<Local>, Error> {
+        self.schedule.upcoming(Local).next().ok_or(NoScheduledRun)
+    }
+}
+
+impl DefaultEnv for CronSchedule {}
+
+impl Default for CronSchedule {
+    fn default() -> Self {
+        Self::from_str(DEFAULT_SCHEDULE).expect("default schedule is valid")
+    }
+}
+
+impl Display for CronSchedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.expression)
+    }
+}
+
+impl FromStr for CronSchedule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expression = s.split_whitespace().collect::<Vec<_>>().join(" ");
+        // The cron crate expects a leading seconds field
+        let schedule = match expression.split(' ').count() {
+            5 => cron::Schedule::from_str(format!("0 {}", expression).as_str()),
+            _ => cron::Schedule::from_str(expression.as_str()),
+        }
+        .map_err(|e| InvalidSchedule(expression.clone(), e.to_string()))?;
+
+        Ok(Self {
+            expression,
+            schedule,
+        })
+    }
+}
+
+/// Run the archive process each time the schedule fires until SIGINT or SIGTERM is received.
+/// A failed archive run is logged and does not stop the schedule.
+pub fn run_schedule(config: &Configuration, runtime: &Runtime) -> Result<(), Error> {
+    info!(target: LOG_TARGET, "Archive schedule started with {}", config.schedule);
+    let mut signals = runtime.block_on(async { SignalListener::new() })?;
+
+    loop {
+        let next_run = config.schedule.next_run()?;
+        info!(target: LOG_TARGET, "Next archive run scheduled for {}", next_run.format("%Y-%m-%d %H:%M:%S %:z"));
+        let wait = (next_run - Local::now()).to_std().unwrap_or_default();
+
+        let stopped = runtime.block_on(async {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => false,
+                _ = signals.recv() => true,
+            }
+        });
+        if stopped {
+            info!(target: LOG_TARGET, "Archive schedule stopped");
+            return Ok(());
+        }
+
+        if let Err(error) = archive(config, runtime) {
+            error!(target: LOG_TARGET, "Scheduled archive run failed: {}", error);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::error::Error;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+pub const SIGINT_EXIT_CODE: i32 = 130;
+pub const SIGTERM_EXIT_CODE: i32 = 143;
+const SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+/// Listeners currently receiving the signals and the Tokio handlers saved while no listener is
+static LISTENERS: Mutex<Listeners> = Mutex::new(Listeners {
+    count: 0,
+    saved: None,
+});
+
+struct Listeners {
+    count: usize,
+    saved: Option<[libc::sigaction; 2]>,
+}
+
+/// Receives SIGTERM and SIGINT instead of letting them terminate the process. Tokio keeps its
+/// handlers installed for the life of the process, so once the last listener is dropped the
+/// default disposition is restored and the signals terminate the process again. The handlers are
+/// reinstalled for the next listener.
+pub struct SignalListener {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl SignalListener {
+    /// Start listening for the signals. Must be called from within a Tokio runtime.
+    pub fn new() -> Result<Self, Error> {
+        let mut listeners = lock();
+        if let Some(saved) = listeners.saved.take() {
+            for (signal, action) in SIGNALS.iter().zip(saved.iter()) {
+                // SAFETY: the action was returned by sigaction for the same signal
+                unsafe { libc::sigaction(*signal, action, std::ptr::null_mut()) };
+            }
+        }
+        let listener = Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        };
+        listeners.count += 1;
+        Ok(listener)
+    }
+
+    /// Wait for SIGTERM or SIGINT and return its name with the exit code used for it by shells
+    pub async fn recv(&mut self) -> (&'static str, i32) {
+        tokio::select! {
+            _ = self.terminate.recv() => ("SIGTERM", SIGTERM_EXIT_CODE),
+            _ = self.interrupt.recv() => ("SIGINT", SIGINT_EXIT_CODE),
+        }
+    }
+}
+
+impl Drop for SignalListener {
+    fn drop(&mut self) {
+        let mut listeners = lock();
+        listeners.count -= 1;
+        if listeners.count > 0 {
+            return;
+        }
+        // SAFETY: sigaction is plain data for which all zeroes is a valid value
+        let mut saved: [libc::sigaction; 2] = unsafe { std::mem::zeroed() };
+        for (signal, action) in SIGNALS.iter().zip(saved.iter_mut()) {
+            // SAFETY: as above, and SIG_DFL with an empty mask is a valid action
+            let mut default: libc::sigaction = unsafe { std::mem::zeroed() };
+            default.sa_sigaction = libc::SIG_DFL;
+            // SAFETY: both pointers are valid for the duration of the call
+            unsafe { libc::sigaction(*signal, &default, action) };
+        }
+        listeners.saved = Some(saved);
+    }
+}
+
+fn lock() -> MutexGuard<'static, Listeners> {
+    LISTENERS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(signal: libc::c_int) -> libc::sighandler_t {
+        // SAFETY: a null action only reads the current action into `current`
+        let mut current: libc::sigaction = unsafe { std::mem::zeroed() };
+        unsafe { libc::sigaction(signal, std::ptr::null(), &mut current) };
+        current.sa_sigaction
+    }
+
+    #[test]
+    fn restores_default_disposition_after_last_listener() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let _guard = runtime.enter();
+
+        let first = SignalListener::new().unwrap();
+        let second = SignalListener::new().unwrap();
+        assert_ne!(handler(libc::SIGTERM), libc::SIG_DFL);
+        drop(first);
+        assert_ne!(handler(libc::SIGTERM), libc::SIG_DFL);
+        drop(second);
+        assert_eq!(handler(libc::SIGTERM), libc::SIG_DFL);
+        assert_eq!(handler(libc::SIGINT), libc::SIG_DFL);
+
+        let listener = SignalListener::new().unwrap();
+        assert_ne!(handler(libc::SIGTERM), libc::SIG_DFL);
+        assert_ne!(handler(libc::SIGINT), libc::SIG_DFL);
+        drop(listener);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::catalog::Catalog;
+use crate::checksum::sidecar_path;
+use crate::configuration::Configuration;
+use crate::error::Error;
+use crate::retention::{group_archives, known_volumes, prune};
+use crate::LOG_TARGET;
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+pub mod s3;
+pub mod sftp;
+
+use s3::{S3Config, S3Storage};
+use sftp::{SftpConfig, SftpStorage};
+
+#[derive(Default)]
+pub struct StorageConfig {
+    pub s3: Option<S3Config>,
+    pub sftp: Option<SftpConfig>,
+    pub delete_local: bool,
+}
+
+/// Remote location that archives are copied to after they are created
+pub trait StorageBackend {
+    /// Name of the backend used in log messages
+    fn name(&self) -> String;
+
+    /// Upload a local file using its file name as the remote name
+    fn upload(&self, path: &Path) -> Result<(), Error>;
+
+    /// List the names of all files in the remote location
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    /// Delete a file from the remote location by name
+    fn delete(&self, name: &str) -> Result<(), Error>;
+}
+
+impl StorageConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.s3.is_some() || self.sftp.is_some()
+    }
+
+    /// Create all configured storage backends
+    pub fn backends(&self) -> Result<Vec<Box<dyn StorageBackend>>, Error> {
+        let mut backends: Vec<Box<dyn StorageBackend>> = Vec::new();
+        if let Some(s3) = self.s3.as_ref() {
+            backends.push(Box::new(S3Storage::new(s3.clone())));
+        }
+        if let Some(sftp) = self.sftp.as_ref() {
+            backends.push(Box::new(SftpStorage::connect(sftp.clone())?));
+        }
+        Ok(backends)
+    }
+}
+
+/// Upload archives and their checksum sidecars to every configured storage backend. The local
+/// copies are deleted afterward when configured and every upload succeeded.
+pub fn upload_archives(config: &Configuration, archives: &[PathBuf]) -> Result<(), Error> {
+    if !config.storage.is_enabled() {
+        return Ok(());
+    }
+    let start_time = Instant::now();
+
+    for backend in config.storage.backends()? {
+        for archive in archives {
+            let upload_start = Instant::now();
+            backend.upload(archive.as_path())?;
+            let sidecar = sidecar_path(archive.as_path());
+            if sidecar.is_file() {
+                backend.upload(sidecar.as_path())?;
+            }
+            info!(target: LOG_TARGET, "Uploaded {} to {} in {} milliseconds", archive.to_string_lossy(), backend.name(), upload_start.elapsed().as_millis());
+        }
+    }
+
+    if config.storage.delete_local {
+        for archive in archives {
+            debug!(target: LOG_TARGET, "Deleting local archive {}", archive.to_string_lossy());
+            std::fs::remove_file(archive.as_path())?;
+            let sidecar = sidecar_path(archive.as_path());
+            if sidecar.is_file() {
+                std::fs::remove_file(sidecar)?;
+            }
+        }
+    }
+
+    debug!(target: LOG_TARGET, "Upload to storage backends complete after {} milliseconds", start_time.elapsed().as_millis());
+    Ok(())
+}
+
+/// Apply the retention policy to the archives in every configured storage backend
+pub fn apply_remote_retention(config: &Configuration, dry_run: bool) -> Result<(), Error> {
+    if !config.retention.is_enabled() || !config.storage.is_enabled() {
+        return Ok(());
+    }
+
+    let volumes = known_volumes(config, &Catalog::load(config)?);
+    for backend in config.storage.backends()? {
+        info!(target: LOG_TARGET, "Applying retention to {}", backend.name());
+        let names = backend.list()?;
+        let groups = group_archives(
+            names.iter().map(PathBuf::from),
+            config.archive_prefix.as_str(),
+            &volumes,
+        );
+        prune(config, groups, dry_run, |archive| {
+            backend.delete(archive.path.to_string_lossy().as_ref())?;
+            let sidecar = sidecar_path(archive.path.as_path());
+            let sidecar = sidecar.to_string_lossy();
+            if names.iter().any(|n| n.eq(sidecar.as_ref())) {
+                backend.delete(sidecar.as_ref())?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
@@ -0,0 +1,231 @@
+use crate::error::Error;
+use crate::error::Error::Storage;
+use crate::storage::StorageBackend;
+use crate::LOG_TARGET;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use log::{debug, warn};
+use ssh2::{HashType, Session, Sftp};
+use std::fs::File;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PORT: u16 = 22;
+const TIMEOUT_MS: u32 = 60_000;
+const REMOTE_DIR_MODE: i32 = 0o750;
+const PARTIAL_EXTENSION: &str = "partial";
+
+#[derive(Clone)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub private_key: PathBuf,
+    pub passphrase: Option<String>,
+    pub host_key: Option<String>,
+    pub remote_dir: PathBuf,
+}
+
+/// Storage backend for any server reachable over SSH with the SFTP subsystem enabled
+pub struct SftpStorage {
+    config: SftpConfig,
+    sftp: Sftp,
+    // The session must outlive the SFTP channel
+    _session: Session,
+}
+
+impl SftpStorage {
+    /// Connect and authenticate to the server, rejecting it when the host key does not match the
+    /// pinned fingerprint.
+    pub fn connect(config: SftpConfig) -> Result<Self, Error> {
+        let address = format!("{}:{}", config.host, config.port);
+        debug!(target: LOG_TARGET, "Connecting to SFTP server {}", address);
+        let stream = TcpStream::connect(address.as_str())
+            .map_err(|e| Storage(format!("Unable to connect to {}: {}", address, e)))?;
+        let mut session = Session::new()?;
+        session.set_timeout(TIMEOUT_MS);
+        session.set_tcp_stream(stream);
+        session.handshake()?;
+
+        let fingerprint = session
+            .host_key_hash(HashType::Sha256)
+            .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+            .ok_or_else(|| Storage(format!("No host key provided by {}", address)))?;
+        match config.host_key.as_deref() {
+            Some(expected) if expected.ne(fingerprint.as_str()) => {
+                return Err(Storage(format!(
+                    "Host key {} of {} does not match pinned host key {}",
+                    fingerprint, address, expected
+                )));
+            }
+            Some(_) => {
+                debug!(target: LOG_TARGET, "Host key of {} matched {}", address, fingerprint)
+            }
+            None => {
+                warn!(target: LOG_TARGET, "Host key of {} is not pinned. Accepting {}", address, fingerprint)
+            }
+        }
+
+        session.userauth_pubkey_file(
+            config.username.as_str(),
+            None,
+            config.private_key.as_path(),
+            config.passphrase.as_deref(),
+        )?;
+        if !session.authenticated() {
+            return Err(Storage(format!(
+                "Authentication to {} as {} failed",
+                address, config.username
+            )));
+        }
+
+        let sftp = session.sftp()?;
+        if sftp.stat(config.remote_dir.as_path()).is_err() {
+            debug!(target: LOG_TARGET, "Creating remote directory {}", config.remote_dir.to_string_lossy());
+            sftp.mkdir(config.remote_dir.as_path(), REMOTE_DIR_MODE)?;
+        }
+
+        Ok(Self {
+            config,
+            sftp,
+            _session: session,
+        })
+    }
+
+    /// Copy the file to `partial_path` and check the server has every byte of it
+    fn upload_partial(&self, path: &Path, partial_path: &Path) -> Result<(), Error> {
+        let mut local = File::open(path)?;
+        let size = local.metadata()?.len();
+        let mut remote = self.sftp.create(partial_path)?;
+        std::io::copy(&mut local, &mut remote)?;
+        // Not every server supports fsync, and the size check below catches short writes
+        remote.fsync().ok();
+        drop(remote);
+
+        let remote_size = self.sftp.stat(partial_path)?.size.unwrap_or_default();
+        match remote_size.eq(&size) {
+            true => Ok(()),
+            false => Err(Storage(format!(
+                "{} on {} has {} bytes after uploading {} bytes",
+                partial_path.to_string_lossy(),
+                self.name(),
+                remote_size,
+                size
+            ))),
+        }
+    }
+
+    /// Rename the partial upload into place. Servers that only implement SFTP version 3 refuse to
+    /// rename over a file that exists, so an earlier upload with the same name is removed first.
+    fn rename(&self, partial_path: &Path, remote_path: &Path) -> Result<(), Error> {
+        if self.sftp.rename(partial_path, remote_path, None).is_ok() {
+            return Ok(());
+        }
+        if self.sftp.stat(remote_path).is_ok() {
+            debug!(target: LOG_TARGET, "Replacing remote file {}", remote_path.to_string_lossy());
+            self.sftp.unlink(remote_path)?;
+        }
+        self.sftp.rename(partial_path, remote_path, None)?;
+        Ok(())
+    }
+}
+
+impl StorageBackend for SftpStorage {
+    fn name(&self) -> String {
+        format!(
+            "sftp://{}@{}:{}/{}",
+            self.config.username,
+            self.config.host,
+            self.config.port,
+            self.config
+                .remote_dir
+                .to_string_lossy()
+                .trim_start_matches('/')
+        )
+    }
+
+    /// Upload to a hidden partial file and rename it into place once the server has all of it, so
+    /// an interrupted upload never looks like a complete archive
+    fn upload(&self, path: &Path) -> Result<(), Error> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let remote_path = self.config.remote_dir.join(name.as_ref());
+        let partial_path = self
+            .config
+            .remote_dir
+            .join(format!(".{}.{}", name, PARTIAL_EXTENSION));
+        let result = self.upload_partial(path, partial_path.as_path());
+        let result =
+            result.and_then(|_| self.rename(partial_path.as_path(), remote_path.as_path()));
+        if result.is_err() {
+            let _ = self.sftp.unlink(partial_path.as_path());
+        }
+        result
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .sftp
+            .readdir(self.config.remote_dir.as_path())?
+            .into_iter()
+            .filter(|(_, stat)| stat.is_file())
+            .filter_map(|(path, _)| {
+                path.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            // Partial uploads are hidden
+            .filter(|name| !name.starts_with('.'))
+            .collect())
+    }
+
+    fn delete(&self, name: &str) -> Result<(), Error> {
+        self.sftp
+            .unlink(self.config.remote_dir.join(name).as_path())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Server in `SALVAGE_TEST_SFTP_HOST`, such as a local OpenSSH server container, with the port,
+    /// user and private key in the other `SALVAGE_TEST_SFTP_*` variables
+    fn config(host_key: Option<String>) -> SftpConfig {
+        let variable = |name: &str| std::env::var(format!("SALVAGE_TEST_SFTP_{}", name)).ok();
+        SftpConfig {
+            host: variable("HOST").unwrap(),
+            port: variable("PORT").map_or(DEFAULT_PORT, |p| p.parse().unwrap()),
+            username: variable("USERNAME").unwrap(),
+            private_key: PathBuf::from(variable("PRIVATE_KEY").unwrap()),
+            passphrase: None,
+            host_key,
+            remote_dir: PathBuf::from("salvage-test"),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs an SFTP server"]
+    fn round_trips_through_a_server() {
+        let storage = SftpStorage::connect(config(None)).unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("salvage_2024-01-01_00-00-00.tar.gz");
+        std::fs::write(path.as_path(), "archive").unwrap();
+
+        storage.upload(path.as_path()).unwrap();
+        storage.upload(path.as_path()).unwrap();
+        let name = "salvage_2024-01-01_00-00-00.tar.gz";
+        assert_eq!(storage.list().unwrap(), vec![name.to_string()]);
+        storage.delete(name).unwrap();
+        assert!(!storage.list().unwrap().iter().any(|n| n.eq(name)));
+    }
+
+    #[test]
+    #[ignore = "needs an SFTP server"]
+    fn rejects_a_host_key_that_does_not_match() {
+        let pinned = "SHA256:AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA".to_string();
+        assert!(matches!(
+            SftpStorage::connect(config(Some(pinned))),
+            Err(Storage(message)) if message.contains("does not match")
+        ));
+    }
+}
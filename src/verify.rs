@@ -0,0 +1,105 @@
+use crate::catalog::Catalog;
+use crate::checksum::{hash_file, read_sidecar};
+use crate::configuration::Configuration;
+use crate::encryption::open_archive;
+use crate::error::Error;
+use crate::error::Error::{ChecksumMismatch, VerificationFailed};
+use crate::restore::archive_compression;
+use crate::retention::find_archives;
+use crate::{select_decoder, LOG_TARGET};
+use clap::Args;
+use log::{debug, error, info, warn};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+#[derive(Args)]
+pub struct VerifyOptions {
+    /// Archives to verify, as paths or names in the archive directory. Every archive is verified
+    /// when none are provided.
+    pub archives: Vec<String>,
+    /// age identity file used to decrypt encrypted archives
+    #[arg(long, value_name = "FILE")]
+    pub identity: Option<PathBuf>,
+}
+
+/// Verify the provided archives, or every archive in the backup directory when none are provided.
+/// Each archive is checked against the checksum recorded in the catalog, or its checksum sidecar,
+/// and fully decompressed to prove it is readable.
+pub fn verify(config: &Configuration, options: VerifyOptions) -> Result<(), Error> {
+    let start_time = Instant::now();
+    let identity = options
+        .identity
+        .as_deref()
+        .or(config.encryption.identity_file.as_deref());
+    let catalog = Catalog::load(config)?;
+    let archives: Vec<PathBuf> = match options.archives.is_empty() {
+        true => find_archives(config, &catalog)?
+            .into_values()
+            .flatten()
+            .map(|a| a.path)
+            .collect(),
+        false => options
+            .archives
+            .iter()
+            .map(|a| match Path::new(a).exists() {
+                true => PathBuf::from(a),
+                false => config.backup_dir.join(a),
+            })
+            .collect(),
+    };
+
+    let mut failed = 0;
+    for archive in archives.iter() {
+        let name = archive.file_name().unwrap_or_default().to_string_lossy();
+        let checksum = catalog
+            .find(name.as_ref())
+            .and_then(|(_, a)| a.checksum.clone());
+        match verify_archive(archive.as_path(), checksum, identity) {
+            Ok(()) => info!(target: LOG_TARGET, "Verified archive {}", archive.to_string_lossy()),
+            Err(error) => {
+                error!(target: LOG_TARGET, "Verification of {} failed: {}", archive.to_string_lossy(), error);
+                failed += 1;
+            }
+        }
+    }
+
+    info!(target: LOG_TARGET, "Verified {} archives with {} failures after {} milliseconds", archives.len(), failed, start_time.elapsed().as_millis());
+    match failed {
+        0 => Ok(()),
+        _ => Err(VerificationFailed(failed)),
+    }
+}
+
+fn verify_archive(
+    archive: &Path,
+    checksum: Option<String>,
+    identity: Option<&Path>,
+) -> Result<(), Error> {
+    let checksum = match checksum {
+        Some(checksum) => Some(checksum),
+        None => read_sidecar(archive)?,
+    };
+    match checksum {
+        None => {
+            warn!(target: LOG_TARGET, "No checksum found for {}", archive.to_string_lossy())
+        }
+        Some(expected) => {
+            let actual = hash_file(archive)?;
+            if expected.ne(&actual) {
+                return Err(ChecksumMismatch(expected, actual));
+            }
+            debug!(target: LOG_TARGET, "Checksum matched for {}", archive.to_string_lossy());
+        }
+    }
+
+    let compression = archive_compression(archive)?;
+    let decoder = select_decoder(open_archive(archive, identity)?, &compression)?;
+    let mut tar = tar::Archive::new(decoder);
+    let mut entries = 0;
+    for entry in tar.entries()? {
+        std::io::copy(&mut entry?, &mut std::io::sink())?;
+        entries += 1;
+    }
+    debug!(target: LOG_TARGET, "Read {} entries from {}", entries, archive.to_string_lossy());
+    Ok(())
+}